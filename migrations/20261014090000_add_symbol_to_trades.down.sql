@@ -0,0 +1,3 @@
+DROP INDEX trades_symbol_timestamp_idx;
+
+ALTER TABLE trades DROP COLUMN symbol;
\ No newline at end of file
@@ -0,0 +1,4 @@
+ALTER TABLE trades ADD COLUMN symbol TEXT NOT NULL DEFAULT '';
+ALTER TABLE trades ALTER COLUMN symbol DROP DEFAULT;
+
+CREATE INDEX trades_symbol_timestamp_idx ON trades (symbol, timestamp);
\ No newline at end of file
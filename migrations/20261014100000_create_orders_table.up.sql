@@ -0,0 +1,8 @@
+CREATE TABLE orders (
+    id UUID PRIMARY KEY,
+    symbol TEXT NOT NULL,
+    side TEXT NOT NULL CHECK (side IN ('Buy', 'Sell')),
+    price DECIMAL NOT NULL,
+    quantity DECIMAL NOT NULL,
+    timestamp TIMESTAMPTZ NOT NULL
+);
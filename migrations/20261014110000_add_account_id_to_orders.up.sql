@@ -0,0 +1,2 @@
+ALTER TABLE orders ADD COLUMN account_id UUID NOT NULL DEFAULT '00000000-0000-0000-0000-000000000000';
+ALTER TABLE orders ALTER COLUMN account_id DROP DEFAULT;
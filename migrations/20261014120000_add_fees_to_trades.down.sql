@@ -0,0 +1,2 @@
+ALTER TABLE trades DROP COLUMN maker_fee;
+ALTER TABLE trades DROP COLUMN taker_fee;
\ No newline at end of file
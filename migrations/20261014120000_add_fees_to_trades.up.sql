@@ -0,0 +1,2 @@
+ALTER TABLE trades ADD COLUMN maker_fee DECIMAL NOT NULL DEFAULT 0;
+ALTER TABLE trades ADD COLUMN taker_fee DECIMAL NOT NULL DEFAULT 0;
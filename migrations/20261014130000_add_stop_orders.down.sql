@@ -0,0 +1,2 @@
+ALTER TABLE orders DROP COLUMN stop_price;
+ALTER TABLE orders DROP COLUMN order_type;
\ No newline at end of file
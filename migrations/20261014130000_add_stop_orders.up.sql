@@ -0,0 +1,2 @@
+ALTER TABLE orders ADD COLUMN order_type TEXT NOT NULL DEFAULT 'Limit' CHECK (order_type IN ('Limit', 'StopLimit'));
+ALTER TABLE orders ADD COLUMN stop_price DECIMAL;
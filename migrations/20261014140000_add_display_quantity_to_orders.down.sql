@@ -0,0 +1 @@
+ALTER TABLE orders DROP COLUMN display_quantity;
\ No newline at end of file
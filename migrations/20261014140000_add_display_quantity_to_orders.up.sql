@@ -0,0 +1 @@
+ALTER TABLE orders ADD COLUMN display_quantity DECIMAL;
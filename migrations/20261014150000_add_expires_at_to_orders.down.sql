@@ -0,0 +1 @@
+ALTER TABLE orders DROP COLUMN expires_at;
\ No newline at end of file
@@ -0,0 +1 @@
+ALTER TABLE orders ADD COLUMN expires_at TIMESTAMPTZ;
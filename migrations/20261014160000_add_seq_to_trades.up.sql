@@ -0,0 +1 @@
+ALTER TABLE trades ADD COLUMN seq BIGINT;
@@ -0,0 +1 @@
+DROP TABLE book_events;
\ No newline at end of file
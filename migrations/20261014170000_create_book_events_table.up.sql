@@ -0,0 +1,8 @@
+CREATE TABLE book_events (
+    seq BIGINT PRIMARY KEY,
+    symbol TEXT NOT NULL,
+    timestamp TIMESTAMPTZ NOT NULL,
+    event TEXT NOT NULL
+);
+
+CREATE INDEX book_events_symbol_timestamp ON book_events (symbol, timestamp);
@@ -0,0 +1 @@
+ALTER TABLE trades DROP COLUMN taker_side;
\ No newline at end of file
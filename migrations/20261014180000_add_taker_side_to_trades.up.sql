@@ -0,0 +1 @@
+ALTER TABLE trades ADD COLUMN taker_side TEXT;
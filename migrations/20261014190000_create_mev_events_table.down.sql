@@ -0,0 +1 @@
+DROP TABLE mev_events;
\ No newline at end of file
@@ -0,0 +1,12 @@
+CREATE TABLE mev_events (
+    id UUID PRIMARY KEY,
+    symbol TEXT NOT NULL,
+    order_id UUID NOT NULL,
+    side TEXT NOT NULL,
+    order_price DECIMAL,
+    best_opposite_price DECIMAL NOT NULL,
+    spread DECIMAL,
+    timestamp TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX mev_events_timestamp ON mev_events (timestamp);
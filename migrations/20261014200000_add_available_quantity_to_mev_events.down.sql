@@ -0,0 +1 @@
+ALTER TABLE mev_events DROP COLUMN available_quantity;
\ No newline at end of file
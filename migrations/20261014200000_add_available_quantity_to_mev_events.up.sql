@@ -0,0 +1 @@
+ALTER TABLE mev_events ADD COLUMN available_quantity DECIMAL;
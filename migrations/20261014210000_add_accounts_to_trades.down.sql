@@ -0,0 +1,2 @@
+ALTER TABLE trades DROP COLUMN taker_account_id;
+ALTER TABLE trades DROP COLUMN maker_account_id;
\ No newline at end of file
@@ -0,0 +1,2 @@
+ALTER TABLE trades ADD COLUMN maker_account_id UUID;
+ALTER TABLE trades ADD COLUMN taker_account_id UUID;
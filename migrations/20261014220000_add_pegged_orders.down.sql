@@ -0,0 +1,5 @@
+ALTER TABLE orders DROP COLUMN peg_offset;
+ALTER TABLE orders DROP COLUMN peg_reference;
+DELETE FROM orders WHERE order_type = 'Pegged';
+ALTER TABLE orders DROP CONSTRAINT orders_order_type_check;
+ALTER TABLE orders ADD CONSTRAINT orders_order_type_check CHECK (order_type IN ('Limit', 'StopLimit'));
\ No newline at end of file
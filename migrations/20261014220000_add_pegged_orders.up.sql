@@ -0,0 +1,4 @@
+ALTER TABLE orders DROP CONSTRAINT orders_order_type_check;
+ALTER TABLE orders ADD CONSTRAINT orders_order_type_check CHECK (order_type IN ('Limit', 'StopLimit', 'Pegged'));
+ALTER TABLE orders ADD COLUMN peg_reference TEXT;
+ALTER TABLE orders ADD COLUMN peg_offset DECIMAL;
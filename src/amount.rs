@@ -0,0 +1,88 @@
+use crate::money;
+use rust_decimal::{Decimal, RoundingStrategy};
+use std::fmt;
+use std::iter::Sum;
+use std::ops::{Add, AddAssign, Div, Mul, Rem, Sub, SubAssign};
+
+/// Numeric type the engine keeps prices, quantities and fees in.
+///
+/// `Decimal` is the default everywhere. Integer types suit deployments that
+/// scale prices and quantities to whole ticks and lots up front; note that
+/// products such as fees and notionals then carry the combined scale of
+/// price and quantity, and averages round toward zero.
+pub trait Amount:
+    Copy
+    + Ord
+    + Default
+    + fmt::Debug
+    + fmt::Display
+    + From<u16>
+    + Sum
+    + Add<Output = Self>
+    + Sub<Output = Self>
+    + Mul<Output = Self>
+    + Div<Output = Self>
+    + Rem<Output = Self>
+    + AddAssign
+    + SubAssign
+{
+    const ZERO: Self;
+
+    /// Largest quantity that still counts as zero, see `is_effectively_zero`.
+    const EPSILON: Self;
+
+    /// Rounds toward zero to the precision `reference` is written in.
+    /// Integers have no fractional part, so this is the identity for them.
+    fn round_down_like(self, reference: Self) -> Self;
+
+    /// Rounds to `scale` decimal places as `money::round_to_scale` does.
+    /// Integers are already whole, so this is the identity for them.
+    fn round_to_scale(self, scale: u32) -> Self;
+}
+
+/// Whether `quantity` is within `eps` of zero. Matching leaves remainders
+/// such as `0.0000000001` when quantities are written more finely than
+/// anyone trades; treating them as zero clears them out instead of leaving
+/// dust resting forever.
+pub fn is_effectively_zero<N: Amount>(quantity: N, eps: N) -> bool {
+    quantity <= eps
+}
+
+impl Amount for Decimal {
+    const ZERO: Self = Decimal::ZERO;
+    const EPSILON: Self = money::QUANTITY_EPSILON;
+
+    fn round_down_like(self, reference: Self) -> Self {
+        self.round_dp_with_strategy(reference.scale(), RoundingStrategy::ToZero)
+    }
+
+    fn round_to_scale(self, scale: u32) -> Self {
+        money::round_to_scale(self, scale)
+    }
+}
+
+impl Amount for i64 {
+    const ZERO: Self = 0;
+    const EPSILON: Self = 0;
+
+    fn round_down_like(self, _reference: Self) -> Self {
+        self
+    }
+
+    fn round_to_scale(self, _scale: u32) -> Self {
+        self
+    }
+}
+
+impl Amount for u64 {
+    const ZERO: Self = 0;
+    const EPSILON: Self = 0;
+
+    fn round_down_like(self, _reference: Self) -> Self {
+        self
+    }
+
+    fn round_to_scale(self, _scale: u32) -> Self {
+        self
+    }
+}
@@ -0,0 +1,370 @@
+use crate::matching_engine::{
+    FeeSchedule, LevelTiebreaker, MatchingPolicy, OrderBook, PricingMode, SelfTradePrevention,
+    SubLotRemainder,
+};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Reads `key` from the environment, falling back to `default` when it is
+/// unset. Panics on a value that does not parse, since running with a
+/// silently ignored setting is worse than not starting.
+pub fn env_or<T>(key: &str, default: T) -> T
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match std::env::var(key) {
+        Ok(value) => value
+            .parse()
+            .unwrap_or_else(|e| panic!("Invalid value for {}: {}", key, e)),
+        Err(_) => default,
+    }
+}
+
+/// Price and quantity increments orders on one symbol must respect, and
+/// the most decimal places each may be written with.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct SymbolSpec {
+    pub tick_size: Decimal,
+    pub lot_size: Decimal,
+    pub price_scale: u32,
+    pub quantity_scale: u32,
+    /// Whether prices may be zero or below, as on power markets or
+    /// calendar spreads. Other symbols only take positive prices.
+    pub allow_negative_price: bool,
+    /// What becomes of a taker's remainder smaller than `lot_size`.
+    pub sub_lot_remainder: SubLotRemainder,
+}
+
+impl SymbolSpec {
+    pub fn validate(&self, price: Option<Decimal>, quantity: Decimal) -> Result<(), String> {
+        if let Some(price) = price
+            && !within_scale(price, self.price_scale)
+        {
+            return Err(format!(
+                "price must have at most {} decimal places",
+                self.price_scale
+            ));
+        }
+        if !within_scale(quantity, self.quantity_scale) {
+            return Err(format!(
+                "quantity must have at most {} decimal places",
+                self.quantity_scale
+            ));
+        }
+        if let Some(price) = price
+            && !snaps_to(price, self.tick_size)
+        {
+            return Err(format!(
+                "price must be a multiple of the tick size {}",
+                self.tick_size
+            ));
+        }
+        if !snaps_to(quantity, self.lot_size) {
+            return Err(format!(
+                "quantity must be a multiple of the lot size {}",
+                self.lot_size
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for SymbolSpec {
+    type Err = String;
+
+    /// Parses `<tick_size>/<lot_size>`, e.g. `0.01/0.0001`, optionally
+    /// followed by `/<price_scale>/<quantity_scale>`. Without them any
+    /// scale `Decimal` can hold is accepted. Trailing flags, in any order:
+    /// `/negative` allows prices of zero and below, `/cancel-sub-lot`
+    /// cancels taker remainders smaller than a lot instead of resting them.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut increments = s;
+        let mut allow_negative_price = false;
+        let mut sub_lot_remainder = SubLotRemainder::default();
+        loop {
+            if let Some(rest) = increments.strip_suffix("/negative") {
+                allow_negative_price = true;
+                increments = rest;
+            } else if let Some(rest) = increments.strip_suffix("/cancel-sub-lot") {
+                sub_lot_remainder = SubLotRemainder::Cancel;
+                increments = rest;
+            } else {
+                break;
+            }
+        }
+        let parts: Vec<&str> = increments.split('/').collect();
+        let (tick_size, lot_size, scales) = match parts[..] {
+            [tick_size, lot_size] => (tick_size, lot_size, None),
+            [tick_size, lot_size, price_scale, quantity_scale] => {
+                (tick_size, lot_size, Some((price_scale, quantity_scale)))
+            }
+            _ => {
+                return Err(format!(
+                    "expected <tick_size>/<lot_size>[/<price_scale>/<quantity_scale>][/negative][/cancel-sub-lot], got {}",
+                    s
+                ));
+            }
+        };
+        let parse = |value: &str| match value.trim().parse::<Decimal>() {
+            Ok(increment) if increment > Decimal::ZERO => Ok(increment),
+            _ => Err(format!("invalid increment: {}", value)),
+        };
+        let parse_scale = |value: &str| match value.trim().parse::<u32>() {
+            Ok(scale) if scale <= Decimal::MAX_SCALE => Ok(scale),
+            _ => Err(format!("invalid scale: {}", value)),
+        };
+        let (price_scale, quantity_scale) = match scales {
+            Some((price_scale, quantity_scale)) => {
+                (parse_scale(price_scale)?, parse_scale(quantity_scale)?)
+            }
+            None => (Decimal::MAX_SCALE, Decimal::MAX_SCALE),
+        };
+        Ok(SymbolSpec {
+            tick_size: parse(tick_size)?,
+            lot_size: parse(lot_size)?,
+            price_scale,
+            quantity_scale,
+            allow_negative_price,
+            sub_lot_remainder,
+        })
+    }
+}
+
+/// Every setting that decides how orders match, in one place. New books
+/// are built from it and `GET /engine/info` reports it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct EngineConfig {
+    pub matching_policy: MatchingPolicy,
+    pub pricing_mode: PricingMode,
+    pub self_trade_prevention: SelfTradePrevention,
+    pub level_tiebreaker: LevelTiebreaker,
+    pub fee_schedule: FeeSchedule,
+    pub max_price_levels_per_side: usize,
+    pub max_fill_per_maker: Option<Decimal>,
+    /// Resting orders worth less than this are cancelled rather than
+    /// traded against.
+    pub min_trade_notional: Decimal,
+    /// Longest any order may rest before it is swept, if limited.
+    pub max_order_age: Option<Duration>,
+    /// Tick and lot sizes, by symbol. Symbols without one are not checked.
+    pub symbol_specs: HashMap<String, SymbolSpec>,
+}
+
+impl Default for EngineConfig {
+    /// The settings of `OrderBook::new`, with no symbol specs.
+    fn default() -> Self {
+        let order_book = OrderBook::new();
+        EngineConfig {
+            matching_policy: order_book.matching_policy,
+            pricing_mode: order_book.pricing_mode,
+            self_trade_prevention: order_book.self_trade_prevention,
+            level_tiebreaker: order_book.level_tiebreaker,
+            fee_schedule: order_book.fee_schedule,
+            max_price_levels_per_side: order_book.max_price_levels_per_side,
+            max_fill_per_maker: order_book.max_fill_per_maker,
+            min_trade_notional: order_book.min_trade_notional,
+            max_order_age: order_book.max_order_age(),
+            symbol_specs: HashMap::new(),
+        }
+    }
+}
+
+impl EngineConfig {
+    /// A book for `symbol` with nothing on it and these settings.
+    pub fn new_book(&self, symbol: &str) -> OrderBook {
+        let mut order_book = OrderBook::new();
+        self.apply(symbol, &mut order_book);
+        order_book
+    }
+
+    /// Gives `order_book`, the book of `symbol`, these settings and the
+    /// symbol's lot handling, leaving its orders alone.
+    pub fn apply(&self, symbol: &str, order_book: &mut OrderBook) {
+        let spec = self.symbol_specs.get(symbol);
+        order_book.lot_size = spec.map(|spec| spec.lot_size);
+        order_book.sub_lot_remainder =
+            spec.map_or_else(SubLotRemainder::default, |spec| spec.sub_lot_remainder);
+        order_book.matching_policy = self.matching_policy;
+        order_book.pricing_mode = self.pricing_mode;
+        order_book.self_trade_prevention = self.self_trade_prevention;
+        order_book.level_tiebreaker = self.level_tiebreaker;
+        order_book.fee_schedule = self.fee_schedule;
+        order_book.max_price_levels_per_side = self.max_price_levels_per_side;
+        order_book.max_fill_per_maker = self.max_fill_per_maker;
+        order_book.min_trade_notional = self.min_trade_notional;
+        order_book.set_max_order_age(self.max_order_age);
+    }
+
+    /// Whether orders on `symbol` may be priced at zero or below.
+    pub fn allows_negative_price(&self, symbol: &str) -> bool {
+        self.symbol_specs
+            .get(symbol)
+            .is_some_and(|spec| spec.allow_negative_price)
+    }
+}
+
+/// Whether `value` is written with at most `max_scale` decimal places.
+pub fn within_scale(value: Decimal, max_scale: u32) -> bool {
+    value.scale() <= max_scale
+}
+
+/// Whether `value` is a whole number of `increment`s.
+pub fn snaps_to(value: Decimal, increment: Decimal) -> bool {
+    (value % increment).is_zero()
+}
+
+/// Reads per-symbol specs from `key`, formatted as comma-separated
+/// `<symbol>=<tick_size>/<lot_size>` entries. Symbols without an entry are
+/// not checked. Panics on a malformed entry, like `env_or`.
+pub fn symbol_specs(key: &str) -> HashMap<String, SymbolSpec> {
+    per_symbol(key)
+}
+
+/// Reads comma-separated `<symbol>=<value>` entries from `key`, empty when
+/// it is unset. Panics on a malformed entry, like `env_or`.
+pub fn per_symbol<T>(key: &str) -> HashMap<String, T>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    let Ok(value) = std::env::var(key) else {
+        return HashMap::new();
+    };
+
+    value
+        .split(',')
+        .filter(|entry| !entry.trim().is_empty())
+        .map(|entry| {
+            let parsed = entry
+                .split_once('=')
+                .ok_or_else(|| format!("expected <symbol>=<value>, got {}", entry))
+                .and_then(|(symbol, value)| {
+                    let value = value.trim().parse().map_err(|e: T::Err| e.to_string())?;
+                    Ok((symbol.trim().to_string(), value))
+                });
+            parsed.unwrap_or_else(|e| panic!("Invalid value for {}: {}", key, e))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    const SPEC: SymbolSpec = SymbolSpec {
+        tick_size: dec!(0.01),
+        lot_size: dec!(0.001),
+        price_scale: Decimal::MAX_SCALE,
+        quantity_scale: Decimal::MAX_SCALE,
+        allow_negative_price: false,
+        sub_lot_remainder: SubLotRemainder::Rest,
+    };
+
+    const SCALED: SymbolSpec = SymbolSpec {
+        price_scale: 2,
+        quantity_scale: 3,
+        ..SPEC
+    };
+
+    #[test]
+    fn test_valid_tick_and_lot_pass() {
+        assert!(SPEC.validate(Some(dec!(100.25)), dec!(1.5)).is_ok());
+        assert!(SPEC.validate(None, dec!(0.001)).is_ok());
+    }
+
+    #[test]
+    fn test_off_tick_price_is_rejected() {
+        assert_eq!(
+            SPEC.validate(Some(dec!(100.123456789)), dec!(1.0))
+                .unwrap_err(),
+            "price must be a multiple of the tick size 0.01"
+        );
+    }
+
+    #[test]
+    fn test_off_lot_quantity_is_rejected() {
+        assert_eq!(
+            SPEC.validate(Some(dec!(100.00)), dec!(1.0005)).unwrap_err(),
+            "quantity must be a multiple of the lot size 0.001"
+        );
+    }
+
+    #[test]
+    fn test_in_scale_price_is_accepted() {
+        assert!(SCALED.validate(Some(dec!(100.25)), dec!(1.125)).is_ok());
+        assert!(SCALED.validate(Some(dec!(100)), dec!(1)).is_ok());
+    }
+
+    #[test]
+    fn test_over_scale_price_is_rejected() {
+        // Snaps to the tick, but is written more finely than the symbol allows.
+        assert_eq!(
+            SCALED
+                .validate(Some(dec!(100.250000)), dec!(1.0))
+                .unwrap_err(),
+            "price must have at most 2 decimal places"
+        );
+        assert_eq!(
+            SCALED.validate(None, dec!(1.0000)).unwrap_err(),
+            "quantity must have at most 3 decimal places"
+        );
+    }
+
+    #[test]
+    fn test_parse_symbol_spec() {
+        assert_eq!("0.01/0.001".parse::<SymbolSpec>(), Ok(SPEC));
+        assert_eq!("0.01/0.001/2/3".parse::<SymbolSpec>(), Ok(SCALED));
+        assert!("0.01".parse::<SymbolSpec>().is_err());
+        assert!("0/0.001".parse::<SymbolSpec>().is_err());
+        assert!("0.01/0.001/2".parse::<SymbolSpec>().is_err());
+        assert!("0.01/0.001/2/29".parse::<SymbolSpec>().is_err());
+
+        let negative = SymbolSpec {
+            allow_negative_price: true,
+            ..SCALED
+        };
+        assert_eq!("0.01/0.001/2/3/negative".parse(), Ok(negative));
+        assert!(
+            "0.01/0.001/negative"
+                .parse::<SymbolSpec>()
+                .unwrap()
+                .allow_negative_price
+        );
+        assert!("0.01/negative".parse::<SymbolSpec>().is_err());
+
+        let cancelling = SymbolSpec {
+            allow_negative_price: true,
+            sub_lot_remainder: SubLotRemainder::Cancel,
+            ..SPEC
+        };
+        assert_eq!("0.01/0.001/cancel-sub-lot/negative".parse(), Ok(cancelling));
+        assert_eq!("0.01/0.001/negative/cancel-sub-lot".parse(), Ok(cancelling));
+    }
+
+    #[test]
+    fn test_books_take_their_symbol_lot_handling() {
+        let engine = EngineConfig {
+            symbol_specs: HashMap::from([(
+                "BTC-USD".to_string(),
+                SymbolSpec {
+                    sub_lot_remainder: SubLotRemainder::Cancel,
+                    ..SPEC
+                },
+            )]),
+            ..EngineConfig::default()
+        };
+
+        let btc = engine.new_book("BTC-USD");
+        assert_eq!(btc.lot_size, Some(dec!(0.001)));
+        assert_eq!(btc.sub_lot_remainder, SubLotRemainder::Cancel);
+        let eth = engine.new_book("ETH-USD");
+        assert_eq!(eth.lot_size, None);
+        assert_eq!(eth.sub_lot_remainder, SubLotRemainder::Rest);
+    }
+}
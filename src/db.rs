@@ -0,0 +1,573 @@
+use crate::matching_engine::{ArbitrageEvent, OrderChange, Trade};
+use crate::reconstruct::{BookEvent, RecordedEvent};
+use crate::{Order, OrderType, PegReference, Side, TimeInForce};
+use chrono::{DateTime, Utc};
+use futures_util::stream::BoxStream;
+use rust_decimal::Decimal;
+use serde::Serialize;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+/// A trade as stored in the `trades` table.
+#[derive(Debug, Clone, Serialize)]
+pub struct TradeRecord {
+    pub id: Uuid,
+    pub symbol: String,
+    pub maker_order_id: Uuid,
+    pub taker_order_id: Uuid,
+    /// Missing for trades stored before taker sides were recorded.
+    pub taker_side: Option<String>,
+    /// Missing for trades stored before accounts were recorded.
+    pub maker_account_id: Option<Uuid>,
+    pub taker_account_id: Option<Uuid>,
+    pub price: Decimal,
+    pub quantity: Decimal,
+    pub maker_fee: Decimal,
+    pub taker_fee: Decimal,
+    pub timestamp: DateTime<Utc>,
+    /// Missing for trades stored before sequence numbers existed.
+    pub seq: Option<i64>,
+}
+
+pub async fn insert_trade(pool: &PgPool, trade: &Trade) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "INSERT INTO trades (id, symbol, maker_order_id, taker_order_id, taker_side, maker_account_id, taker_account_id, price, quantity, maker_fee, taker_fee, timestamp, seq) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)",
+        trade.id,
+        trade.symbol,
+        trade.maker_order_id,
+        trade.taker_order_id,
+        trade.taker_side.as_str(),
+        trade.maker_account_id,
+        trade.taker_account_id,
+        trade.price,
+        trade.quantity,
+        trade.maker_fee,
+        trade.taker_fee,
+        trade.timestamp,
+        trade.seq as i64
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Which stored trades `recent_trades` returns. Every filter left `None`
+/// matches all trades.
+#[derive(Debug, Clone, Default)]
+pub struct TradeFilter {
+    pub symbol: Option<String>,
+    /// The taker's side. Trades stored without one never match.
+    pub side: Option<Side>,
+    /// Inclusive bounds on the trade price.
+    pub min_price: Option<Decimal>,
+    pub max_price: Option<Decimal>,
+}
+
+/// Most recent trades first, ties broken by id so pages never overlap,
+/// skipping the first `offset` that match `filter`.
+pub async fn recent_trades(
+    pool: &PgPool,
+    filter: &TradeFilter,
+    offset: i64,
+    limit: i64,
+) -> Result<Vec<TradeRecord>, sqlx::Error> {
+    sqlx::query_as!(
+        TradeRecord,
+        "SELECT id, symbol, maker_order_id, taker_order_id, taker_side, maker_account_id, taker_account_id, price, quantity, maker_fee, taker_fee, timestamp, seq FROM trades WHERE ($1::text IS NULL OR symbol = $1) AND ($2::text IS NULL OR taker_side = $2) AND ($3::numeric IS NULL OR price >= $3) AND ($4::numeric IS NULL OR price <= $4) ORDER BY timestamp DESC, id OFFSET $5 LIMIT $6",
+        filter.symbol.as_deref(),
+        filter.side.map(|side| side.as_str()),
+        filter.min_price,
+        filter.max_price,
+        offset,
+        limit
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Trades of `symbol` from `from` to `to` inclusive, oldest first.
+pub async fn trades_between(
+    pool: &PgPool,
+    symbol: &str,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<Vec<TradeRecord>, sqlx::Error> {
+    sqlx::query_as!(
+        TradeRecord,
+        "SELECT id, symbol, maker_order_id, taker_order_id, taker_side, maker_account_id, taker_account_id, price, quantity, maker_fee, taker_fee, timestamp, seq FROM trades WHERE symbol = $1 AND timestamp >= $2 AND timestamp <= $3 ORDER BY timestamp ASC",
+        symbol,
+        from,
+        to
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Every stored trade, of `symbol` if given, oldest first, one row at a
+/// time so an export never holds the whole table.
+pub fn stream_trades<'a>(
+    pool: &'a PgPool,
+    symbol: Option<&'a str>,
+) -> BoxStream<'a, Result<TradeRecord, sqlx::Error>> {
+    sqlx::query_as!(
+        TradeRecord,
+        "SELECT id, symbol, maker_order_id, taker_order_id, taker_side, maker_account_id, taker_account_id, price, quantity, maker_fee, taker_fee, timestamp, seq FROM trades WHERE ($1::text IS NULL OR symbol = $1) ORDER BY timestamp ASC, id",
+        symbol
+    )
+    .fetch(pool)
+}
+
+/// Runs a trivial query, to check the database answers.
+pub async fn ping(pool: &PgPool) -> Result<(), sqlx::Error> {
+    sqlx::query("SELECT 1").execute(pool).await?;
+    Ok(())
+}
+
+/// Highest trade sequence number stored so far, 0 if there is none.
+pub async fn last_trade_seq(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let last = sqlx::query_scalar!("SELECT MAX(seq) FROM trades")
+        .fetch_one(pool)
+        .await?;
+    Ok(last.unwrap_or(0) as u64)
+}
+
+/// Inserts a resting order, or updates its price and remaining quantity if
+/// it was already stored.
+pub async fn upsert_order(pool: &PgPool, order: &Order) -> Result<(), sqlx::Error> {
+    let peg = order.order_type.peg();
+    sqlx::query!(
+        "INSERT INTO orders (id, symbol, account_id, side, order_type, price, stop_price, peg_reference, peg_offset, quantity, display_quantity, expires_at, timestamp) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13) ON CONFLICT (id) DO UPDATE SET order_type = EXCLUDED.order_type, price = EXCLUDED.price, quantity = EXCLUDED.quantity, timestamp = EXCLUDED.timestamp",
+        order.id,
+        order.symbol,
+        order.account_id,
+        order.side.as_str(),
+        order.order_type.as_str(),
+        order.price,
+        order.order_type.stop_price(),
+        peg.map(|(reference, _)| reference.as_str()),
+        peg.map(|(_, offset)| offset),
+        order.quantity,
+        order.display_quantity,
+        order.time_in_force.expires_at(),
+        order.timestamp
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn delete_order(pool: &PgPool, id: Uuid) -> Result<(), sqlx::Error> {
+    sqlx::query!("DELETE FROM orders WHERE id = $1", id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Writes every change, logging failures instead of stopping so one bad
+/// row doesn't hold back the rest.
+pub async fn persist_order_changes(pool: &PgPool, changes: &[OrderChange]) {
+    for change in changes {
+        let result = match change {
+            OrderChange::Upsert(order) => upsert_order(pool, order).await,
+            OrderChange::Delete(id) => delete_order(pool, *id).await,
+        };
+
+        if let Err(e) = result {
+            eprintln!("Failed to persist order change {:?}: {}", change, e);
+        }
+    }
+}
+
+/// Writes every order in `orders`, returning how many were saved. Failures
+/// are logged and skipped like in `persist_order_changes`.
+pub async fn flush_orders(pool: &PgPool, orders: &[Order]) -> usize {
+    let mut flushed = 0;
+    for order in orders {
+        match upsert_order(pool, order).await {
+            Ok(()) => flushed += 1,
+            Err(e) => eprintln!("Failed to flush order {}: {}", order.id, e),
+        }
+    }
+    flushed
+}
+
+/// An arbitrage opportunity as stored in the `mev_events` table.
+#[derive(Debug, Clone, Serialize)]
+pub struct MevEventRecord {
+    pub id: Uuid,
+    pub symbol: String,
+    pub order_id: Uuid,
+    pub side: String,
+    pub order_price: Option<Decimal>,
+    pub best_opposite_price: Decimal,
+    pub spread: Option<Decimal>,
+    /// `None` for events stored before sizing was recorded.
+    pub available_quantity: Option<Decimal>,
+    pub timestamp: DateTime<Utc>,
+}
+
+pub async fn insert_mev_event(pool: &PgPool, event: &ArbitrageEvent) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "INSERT INTO mev_events (id, symbol, order_id, side, order_price, best_opposite_price, spread, available_quantity, timestamp) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+        Uuid::new_v4(),
+        event.symbol,
+        event.order_id,
+        event.side.as_str(),
+        event.order_price,
+        event.best_opposite_price,
+        event.spread,
+        event.available_quantity,
+        event.timestamp
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Writes every event, logging failures like `persist_order_changes`.
+pub async fn persist_mev_events(pool: &PgPool, events: &[ArbitrageEvent]) {
+    for event in events {
+        if let Err(e) = insert_mev_event(pool, event).await {
+            eprintln!(
+                "Failed to persist MEV event for order {}: {}",
+                event.order_id, e
+            );
+        }
+    }
+}
+
+/// Most recent arbitrage opportunities first.
+pub async fn recent_mev_events(
+    pool: &PgPool,
+    limit: i64,
+) -> Result<Vec<MevEventRecord>, sqlx::Error> {
+    sqlx::query_as!(
+        MevEventRecord,
+        "SELECT id, symbol, order_id, side, order_price, best_opposite_price, spread, available_quantity, timestamp FROM mev_events ORDER BY timestamp DESC LIMIT $1",
+        limit
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn insert_book_event(pool: &PgPool, recorded: &RecordedEvent) -> Result<(), sqlx::Error> {
+    let event = serde_json::to_string(&recorded.event).expect("Book events always serialize.");
+    sqlx::query!(
+        "INSERT INTO book_events (seq, symbol, timestamp, event) VALUES ($1, $2, $3, $4)",
+        recorded.seq as i64,
+        recorded.symbol,
+        recorded.timestamp,
+        event
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Writes every event, logging failures like `persist_order_changes`.
+pub async fn persist_book_events(pool: &PgPool, events: &[RecordedEvent]) {
+    for recorded in events {
+        if let Err(e) = insert_book_event(pool, recorded).await {
+            eprintln!("Failed to persist book event {}: {}", recorded.seq, e);
+        }
+    }
+}
+
+/// Events of `symbol` stamped at or before `until`, in sequence order.
+pub async fn load_book_events(
+    pool: &PgPool,
+    symbol: &str,
+    until: DateTime<Utc>,
+) -> Result<Vec<RecordedEvent>, sqlx::Error> {
+    let rows = sqlx::query!(
+        "SELECT seq, symbol, timestamp, event FROM book_events WHERE symbol = $1 AND timestamp <= $2 ORDER BY seq",
+        symbol,
+        until
+    )
+    .fetch_all(pool)
+    .await?;
+
+    rows.into_iter()
+        .map(|row| {
+            let event: BookEvent =
+                serde_json::from_str(&row.event).map_err(|e| sqlx::Error::Decode(e.into()))?;
+            Ok(RecordedEvent {
+                seq: row.seq as u64,
+                symbol: row.symbol,
+                timestamp: row.timestamp,
+                event,
+            })
+        })
+        .collect()
+}
+
+/// Highest book event sequence number stored so far, 0 if there is none.
+pub async fn last_book_event_seq(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let last = sqlx::query_scalar!("SELECT MAX(seq) FROM book_events")
+        .fetch_one(pool)
+        .await?;
+    Ok(last.unwrap_or(0) as u64)
+}
+
+/// Loads every persisted resting order, oldest first, including parked stop
+/// orders. Only GTC and GTD orders ever rest, told apart by whether they
+/// have an expiry. Post-only is checked on entry, so it is not stored.
+pub async fn load_resting_orders(pool: &PgPool) -> Result<Vec<Order>, sqlx::Error> {
+    let rows = sqlx::query!(
+        "SELECT id, symbol, account_id, side, order_type, price, stop_price, peg_reference, peg_offset, quantity, display_quantity, expires_at, timestamp FROM orders ORDER BY timestamp"
+    )
+    .fetch_all(pool)
+    .await?;
+
+    rows.into_iter()
+        .map(|row| {
+            let side = row
+                .side
+                .parse::<Side>()
+                .map_err(|e| sqlx::Error::Decode(e.into()))?;
+            let peg = row.peg_reference.zip(row.peg_offset);
+            let order_type = match (row.order_type.as_str(), row.stop_price, peg) {
+                ("Limit", _, _) => OrderType::Limit,
+                ("StopLimit", Some(stop_price), _) => OrderType::StopLimit { stop_price },
+                ("Pegged", _, Some((reference, offset))) => OrderType::Pegged {
+                    reference: reference
+                        .parse::<PegReference>()
+                        .map_err(|e| sqlx::Error::Decode(e.into()))?,
+                    offset,
+                },
+                (other, _, _) => {
+                    let message = format!("invalid resting order type: {}", other);
+                    return Err(sqlx::Error::Decode(message.into()));
+                }
+            };
+            Ok(Order {
+                id: row.id,
+                symbol: row.symbol,
+                account_id: row.account_id,
+                order_type,
+                side,
+                price: Some(row.price),
+                quantity: row.quantity,
+                time_in_force: match row.expires_at {
+                    Some(expires_at) => TimeInForce::Gtd { expires_at },
+                    None => TimeInForce::Gtc,
+                },
+                post_only: false,
+                reduce_only: false,
+                display_quantity: row.display_quantity,
+                quote_quantity: None,
+                max_slippage_bps: None,
+                timestamp: row.timestamp,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matching_engine::OrderBook;
+    use chrono::Duration;
+    use futures_util::TryStreamExt;
+    use rust_decimal_macros::dec;
+
+    fn create_test_order(side: Side, price: Decimal, quantity: Decimal) -> Order {
+        Order::test_limit(side, price, quantity, Utc::now())
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs a Postgres server at DATABASE_URL"]
+    async fn test_ping_answers(pool: PgPool) {
+        ping(&pool).await.unwrap();
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs a Postgres server at DATABASE_URL"]
+    async fn test_flush_orders_writes_whole_book(pool: PgPool) {
+        let mut order_book = OrderBook::new();
+        order_book
+            .add_order(create_test_order(Side::Buy, dec!(99.0), dec!(1.0)))
+            .unwrap();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(101.0), dec!(2.0)))
+            .unwrap();
+        let orders: Vec<Order> = order_book.orders().cloned().collect();
+
+        assert_eq!(flush_orders(&pool, &orders).await, 2);
+
+        let restored = load_resting_orders(&pool).await.unwrap();
+        assert_eq!(restored.len(), 2);
+        let restored_book = OrderBook::load_from_orders(restored);
+        assert_eq!(restored_book.best_bid(), Some(dec!(99.0)));
+        assert_eq!(restored_book.best_ask(), Some(dec!(101.0)));
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs a Postgres server at DATABASE_URL"]
+    async fn test_trades_between_is_inclusive_and_ascending(pool: PgPool) {
+        let start = Utc::now();
+        let trade = |symbol: &str, seconds, price| {
+            Trade::test_at(symbol, price, dec!(1.0), start + Duration::seconds(seconds))
+        };
+        for trade in [
+            trade("BTC-USD", 20, dec!(102.0)),
+            trade("BTC-USD", 0, dec!(100.0)),
+            trade("ETH-USD", 5, dec!(10.0)),
+            trade("BTC-USD", 30, dec!(103.0)),
+            trade("BTC-USD", 10, dec!(101.0)),
+        ] {
+            insert_trade(&pool, &trade).await.unwrap();
+        }
+
+        let trades = trades_between(&pool, "BTC-USD", start, start + Duration::seconds(20))
+            .await
+            .unwrap();
+
+        let prices: Vec<Decimal> = trades.iter().map(|trade| trade.price).collect();
+        assert_eq!(prices, vec![dec!(100.0), dec!(101.0), dec!(102.0)]);
+        assert!(
+            trades
+                .iter()
+                .all(|trade| trade.maker_account_id.is_some() && trade.taker_account_id.is_some())
+        );
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs a Postgres server at DATABASE_URL"]
+    async fn test_stream_trades_filters_by_symbol_oldest_first(pool: PgPool) {
+        let start = Utc::now();
+        for (symbol, seconds, price) in [
+            ("BTC-USD", 10, dec!(101.0)),
+            ("ETH-USD", 5, dec!(10.0)),
+            ("BTC-USD", 0, dec!(100.0)),
+        ] {
+            let trade = Trade {
+                taker_side: Side::Sell,
+                ..Trade::test_at(symbol, price, dec!(1.0), start + Duration::seconds(seconds))
+            };
+            insert_trade(&pool, &trade).await.unwrap();
+        }
+
+        let btc: Vec<TradeRecord> = stream_trades(&pool, Some("BTC-USD"))
+            .try_collect()
+            .await
+            .unwrap();
+        let prices: Vec<Decimal> = btc.iter().map(|trade| trade.price).collect();
+        assert_eq!(prices, vec![dec!(100.0), dec!(101.0)]);
+        let all: Vec<TradeRecord> = stream_trades(&pool, None).try_collect().await.unwrap();
+        assert_eq!(all.len(), 3);
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs a Postgres server at DATABASE_URL"]
+    async fn test_recent_trades_pages_and_filters(pool: PgPool) {
+        let start = Utc::now();
+        for (seconds, taker_side, price) in [
+            (0, Side::Buy, dec!(100.0)),
+            (1, Side::Sell, dec!(101.0)),
+            (2, Side::Buy, dec!(102.0)),
+            (3, Side::Sell, dec!(103.0)),
+            (4, Side::Buy, dec!(104.0)),
+        ] {
+            let trade = Trade {
+                taker_side,
+                ..Trade::test_at(
+                    "BTC-USD",
+                    price,
+                    dec!(1.0),
+                    start + Duration::seconds(seconds),
+                )
+            };
+            insert_trade(&pool, &trade).await.unwrap();
+        }
+        let prices = |trades: Vec<TradeRecord>| -> Vec<Decimal> {
+            trades.iter().map(|trade| trade.price).collect()
+        };
+        let all = TradeFilter::default();
+
+        let first = recent_trades(&pool, &all, 0, 2).await.unwrap();
+        assert_eq!(prices(first), vec![dec!(104.0), dec!(103.0)]);
+        let last = recent_trades(&pool, &all, 4, 2).await.unwrap();
+        assert_eq!(prices(last), vec![dec!(100.0)]);
+        assert!(recent_trades(&pool, &all, 5, 2).await.unwrap().is_empty());
+
+        let in_range = TradeFilter {
+            min_price: Some(dec!(101.0)),
+            max_price: Some(dec!(103.0)),
+            ..TradeFilter::default()
+        };
+        let trades = recent_trades(&pool, &in_range, 0, 10).await.unwrap();
+        assert_eq!(prices(trades), vec![dec!(103.0), dec!(102.0), dec!(101.0)]);
+
+        let buys_in_range = TradeFilter {
+            side: Some(Side::Buy),
+            ..in_range
+        };
+        let trades = recent_trades(&pool, &buys_in_range, 0, 10).await.unwrap();
+        assert_eq!(prices(trades), vec![dec!(102.0)]);
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs a Postgres server at DATABASE_URL"]
+    async fn test_mev_events_roundtrip_newest_first(pool: PgPool) {
+        let mut order_book = OrderBook::new();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(100.0), dec!(1.0)))
+            .unwrap();
+        let mut early = create_test_order(Side::Buy, dec!(101.0), dec!(1.0));
+        early.timestamp -= Duration::seconds(1);
+        let mut market = create_test_order(Side::Buy, dec!(1.0), dec!(1.0));
+        market.order_type = OrderType::Market;
+        market.price = None;
+        let events: Vec<ArbitrageEvent> = [&early, &market]
+            .into_iter()
+            .map(|order| order_book.detect_arbitrage(order).unwrap())
+            .collect();
+
+        persist_mev_events(&pool, &events).await;
+        let stored = recent_mev_events(&pool, 10).await.unwrap();
+
+        assert_eq!(stored.len(), 2);
+        assert_eq!(stored[0].order_id, market.id);
+        assert_eq!(stored[0].order_price, None);
+        assert_eq!(stored[0].spread, None);
+        assert_eq!(stored[1].order_id, early.id);
+        assert_eq!(stored[1].side, "Buy");
+        assert_eq!(stored[1].best_opposite_price, dec!(100.0));
+        assert_eq!(stored[1].spread, Some(dec!(1.0)));
+        assert_eq!(stored[1].available_quantity, Some(dec!(1.0)));
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs a Postgres server at DATABASE_URL"]
+    async fn test_book_events_roundtrip_in_sequence_order(pool: PgPool) {
+        let start = Utc::now();
+        let order = create_test_order(Side::Buy, dec!(99.0), dec!(1.0));
+        let order_id = order.id;
+        let events = [
+            RecordedEvent {
+                seq: 2,
+                symbol: "BTC-USD".to_string(),
+                timestamp: start + Duration::seconds(1),
+                event: BookEvent::Cancelled { order_id },
+            },
+            RecordedEvent {
+                seq: 1,
+                symbol: "BTC-USD".to_string(),
+                timestamp: start,
+                event: BookEvent::Submitted { order },
+            },
+        ];
+        persist_book_events(&pool, &events).await;
+
+        let loaded = load_book_events(&pool, "BTC-USD", start).await.unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert!(matches!(&loaded[0].event, BookEvent::Submitted { order } if order.id == order_id));
+
+        let loaded = load_book_events(&pool, "BTC-USD", start + Duration::seconds(1))
+            .await
+            .unwrap();
+        assert_eq!(loaded.iter().map(|e| e.seq).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(last_book_event_seq(&pool).await.unwrap(), 2);
+    }
+}
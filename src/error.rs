@@ -0,0 +1,216 @@
+use crate::risk::RiskError;
+use axum::{
+    Json,
+    http::{StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use std::fmt;
+use std::sync::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::Duration;
+
+/// Why a request failed. Every variant maps to one status code, and the
+/// body is always `{ "code": ..., "message": ... }`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ApiError {
+    /// The request is malformed or breaks the symbol's trading rules.
+    Validation(String),
+    /// A pre-trade risk check turned the order away.
+    RiskRejected(RiskError),
+    /// The request is well formed but clashes with the book as it stands.
+    Conflict(String),
+    /// The account already has as many open orders as it may.
+    TooManyOpenOrders(String),
+    /// The account sent orders faster than it may. Answered with a
+    /// `Retry-After` header.
+    RateLimited {
+        retry_after: Duration,
+    },
+    /// The caller did not present the credentials the endpoint needs.
+    Forbidden(String),
+    NotFound(String),
+    /// Trading is halted and new orders are turned away until it resumes.
+    Halted(String),
+    /// A dependency the server needs, such as the database, is down.
+    Unavailable(String),
+    Internal(String),
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorBody {
+    code: &'static str,
+    message: String,
+}
+
+impl ApiError {
+    /// A price far through the book conflicts with the current market
+    /// rather than being malformed, so it is a 409 unlike other risk
+    /// rejections.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::Validation(_) => StatusCode::BAD_REQUEST,
+            ApiError::RiskRejected(RiskError::NotionalTooLarge { .. }) => StatusCode::BAD_REQUEST,
+            ApiError::RiskRejected(RiskError::OutsidePriceBand { .. }) => StatusCode::CONFLICT,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::TooManyOpenOrders(_) | ApiError::RateLimited { .. } => {
+                StatusCode::TOO_MANY_REQUESTS
+            }
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Halted(_) | ApiError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Stable machine-readable name for the variant.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::Validation(_) => "validation",
+            ApiError::RiskRejected(_) => "risk_rejected",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::TooManyOpenOrders(_) => "too_many_open_orders",
+            ApiError::RateLimited { .. } => "rate_limited",
+            ApiError::Forbidden(_) => "forbidden",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::Halted(_) => "trading_halted",
+            ApiError::Unavailable(_) => "unavailable",
+            ApiError::Internal(_) => "internal",
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::RiskRejected(e) => write!(f, "{}", e),
+            ApiError::RateLimited { retry_after } => write!(
+                f,
+                "too many orders, retry in {} ms",
+                retry_after.as_millis()
+            ),
+            ApiError::Validation(message)
+            | ApiError::Conflict(message)
+            | ApiError::TooManyOpenOrders(message)
+            | ApiError::Forbidden(message)
+            | ApiError::NotFound(message)
+            | ApiError::Halted(message)
+            | ApiError::Unavailable(message)
+            | ApiError::Internal(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl From<RiskError> for ApiError {
+    fn from(e: RiskError) -> Self {
+        ApiError::RiskRejected(e)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = ErrorBody {
+            code: self.code(),
+            message: self.to_string(),
+        };
+        let mut response = (self.status(), Json(body)).into_response();
+        if let ApiError::RateLimited { retry_after } = self {
+            // Whole seconds, rounded up so a client that waits is let in.
+            let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, seconds.max(1).into());
+        }
+        response
+    }
+}
+
+/// Locks `mutex`, turning a lock poisoned by a panicked handler into an
+/// internal error instead of panicking every later request too.
+pub fn lock<T>(mutex: &Mutex<T>) -> Result<MutexGuard<'_, T>, ApiError> {
+    mutex.lock().map_err(|_| poisoned())
+}
+
+/// `lock` for the shared side of a read-write lock.
+pub fn read<T>(lock: &RwLock<T>) -> Result<RwLockReadGuard<'_, T>, ApiError> {
+    lock.read().map_err(|_| poisoned())
+}
+
+/// `lock` for the exclusive side of a read-write lock.
+pub fn write<T>(lock: &RwLock<T>) -> Result<RwLockWriteGuard<'_, T>, ApiError> {
+    lock.write().map_err(|_| poisoned())
+}
+
+fn poisoned() -> ApiError {
+    eprintln!("State lock poisoned by an earlier panic.");
+    ApiError::Internal("internal state unavailable".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    async fn body_json(error: ApiError) -> (StatusCode, serde_json::Value) {
+        let response = error.into_response();
+        let status = response.status();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&bytes).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_error_body_has_code_and_message() {
+        let (status, body) = body_json(ApiError::NotFound("order not found".to_string())).await;
+
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(
+            body,
+            serde_json::json!({ "code": "not_found", "message": "order not found" })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_risk_rejection_keeps_its_status() {
+        let (status, body) = body_json(ApiError::RiskRejected(RiskError::OutsidePriceBand {
+            price: dec!(120),
+            reference: dec!(100),
+            band_pct: dec!(10),
+        }))
+        .await;
+
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["code"], "risk_rejected");
+        assert_eq!(
+            body["message"],
+            "order price 120 is more than 10% through the best opposite price 100"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited_sets_retry_after() {
+        let response = ApiError::RateLimited {
+            retry_after: Duration::from_millis(1200),
+        }
+        .into_response();
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "2");
+    }
+
+    #[test]
+    fn test_poisoned_lock_is_internal() {
+        let mutex = Mutex::new(0);
+        let _ = std::panic::catch_unwind(|| {
+            let _guard = mutex.lock().unwrap();
+            panic!("poison the lock");
+        });
+
+        assert_eq!(
+            lock(&mutex).unwrap_err().status(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+}
@@ -0,0 +1,243 @@
+use crate::matching_engine::{BookDelta, OrderBook, OrderChange};
+use crate::reconstruct::{BookEvent, EventOutcome};
+use crate::ws::TopOfBookUpdate;
+use crate::{AppState, Order, db, error};
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::time::Duration;
+
+pub const DEFAULT_EXPIRY_SWEEP_SECS: u64 = 1;
+
+/// What one sweep took off the books, gathered under the books lock so the
+/// broadcasts and database writes can happen after it is released.
+#[derive(Debug, Default)]
+pub struct Sweep {
+    pub expired: Vec<Order>,
+    pub changes: Vec<OrderChange>,
+    pub deltas: Vec<BookDelta>,
+    /// Tops of book the expiries moved.
+    pub tops: Vec<TopOfBookUpdate>,
+}
+
+/// Expires good-till-date orders on every book as of `now`, through a
+/// `BookEvent::Expired` so pegged orders follow the prices left behind.
+/// Each event is handed to `record` before it is applied, so replaying
+/// the log repeats the sweep.
+pub fn sweep(
+    order_books: &mut HashMap<String, OrderBook>,
+    now: DateTime<Utc>,
+    mut record: impl FnMut(&str, &BookEvent),
+) -> Sweep {
+    let mut sweep = Sweep::default();
+    for (symbol, order_book) in order_books.iter_mut() {
+        if !order_book.expiry_due(now) {
+            continue;
+        }
+        record(symbol, &BookEvent::Expired);
+        let EventOutcome::Expired(expired) = order_book.apply_event(BookEvent::Expired, now) else {
+            unreachable!("an expiry event always yields an expiry outcome");
+        };
+        if expired.is_empty() {
+            continue;
+        }
+        sweep.expired.extend(expired);
+        sweep.deltas.extend(order_book.take_deltas(symbol));
+        sweep.changes.extend(order_book.take_changes());
+        let top = order_book.top_of_book_changed();
+        if top.changed {
+            sweep.tops.push(TopOfBookUpdate {
+                symbol: symbol.clone(),
+                top: top.current,
+            });
+        }
+    }
+    sweep
+}
+
+/// Sweeps the books every `interval` until the process exits, so orders
+/// expire even on a book nobody is trading.
+pub(crate) async fn run(state: AppState, interval: Duration) {
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        ticks.tick().await;
+        let now = Utc::now();
+        let swept = match error::write(&state.order_books) {
+            Ok(mut order_books) => sweep(&mut order_books, now, |symbol, event| {
+                crate::record_event(&state, symbol, now, event.clone())
+            }),
+            Err(_) => continue,
+        };
+        crate::flush_events(&state).await;
+        if swept.expired.is_empty() {
+            continue;
+        }
+
+        for delta in swept.deltas {
+            let _ = state.book_tx.send(delta);
+        }
+        for top in swept.tops {
+            let _ = state.top_tx.send(top);
+        }
+        db::persist_order_changes(&state.db_pool, &swept.changes).await;
+        println!(
+            "Expired {} orders, persisted {} changes.",
+            swept.expired.len(),
+            swept.changes.len()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reconstruct::{RecordedEvent, reconstruct_book_at};
+    use crate::{OrderType, PegReference, Side, TimeInForce};
+    use rust_decimal_macros::dec;
+
+    fn gtd_bid(symbol: &str, expires_at: DateTime<Utc>) -> Order {
+        Order {
+            symbol: symbol.to_string(),
+            time_in_force: TimeInForce::Gtd { expires_at },
+            ..Order::test_limit(Side::Buy, dec!(100), dec!(1), Utc::now())
+        }
+    }
+
+    #[test]
+    fn test_sweep_collects_expired_orders_across_books() {
+        let now = Utc::now();
+        let mut order_books: HashMap<String, OrderBook> = HashMap::new();
+        for (symbol, expires_at) in [
+            ("BTC-USD", now - chrono::Duration::seconds(1)),
+            ("ETH-USD", now - chrono::Duration::seconds(1)),
+            ("ETH-USD", now + chrono::Duration::hours(1)),
+        ] {
+            order_books
+                .entry(symbol.to_string())
+                .or_default()
+                .add_order(gtd_bid(symbol, expires_at))
+                .unwrap();
+        }
+        for order_book in order_books.values_mut() {
+            order_book.take_changes();
+            order_book.take_deltas("");
+        }
+
+        let mut recorded = Vec::new();
+        let swept = sweep(&mut order_books, now, |symbol, event| {
+            recorded.push((symbol.to_string(), event.clone()))
+        });
+
+        assert_eq!(swept.expired.len(), 2);
+        recorded.sort_by(|a, b| a.0.cmp(&b.0));
+        assert!(matches!(
+            recorded.as_slice(),
+            [(btc, BookEvent::Expired), (eth, BookEvent::Expired)]
+                if btc == "BTC-USD" && eth == "ETH-USD"
+        ));
+        assert_eq!(swept.changes.len(), 2);
+        assert!(
+            swept
+                .changes
+                .iter()
+                .all(|change| matches!(change, OrderChange::Delete(_)))
+        );
+        assert_eq!(swept.deltas.len(), 2);
+        assert!(order_books["BTC-USD"].bids.is_empty());
+        assert_eq!(order_books["ETH-USD"].orders().count(), 1);
+
+        assert!(sweep(&mut order_books, now, |_, _| {}).expired.is_empty());
+    }
+
+    #[test]
+    fn test_sweep_reprices_pegs_after_the_best_bid_expires() {
+        let now = Utc::now();
+        let mut order_book = OrderBook::new();
+        order_book
+            .add_order(gtd_bid("BTC-USD", now - chrono::Duration::seconds(1)))
+            .unwrap();
+        order_book
+            .add_order(Order::test_limit(Side::Buy, dec!(99), dec!(1), now))
+            .unwrap();
+        let pegged = Order {
+            order_type: OrderType::Pegged {
+                reference: PegReference::BestBid,
+                offset: dec!(-0.5),
+            },
+            ..Order::test_limit(Side::Buy, dec!(99.5), dec!(1), now)
+        };
+        let pegged_id = pegged.id;
+        order_book.add_order(pegged).unwrap();
+        order_book.take_changes();
+        let mut order_books = HashMap::from([("BTC-USD".to_string(), order_book)]);
+
+        let swept = sweep(&mut order_books, now, |_, _| {});
+
+        assert_eq!(swept.expired.len(), 1);
+        let order_book = &order_books["BTC-USD"];
+        assert!(!order_book.bids.contains_key(&dec!(99.5)));
+        assert_eq!(order_book.bids[&dec!(98.5)][0].id, pegged_id);
+        assert!(
+            swept.changes.iter().any(
+                |change| matches!(change, OrderChange::Upsert(order) if order.id == pegged_id)
+            )
+        );
+    }
+
+    #[test]
+    fn test_replaying_a_sweep_reprices_pegs_at_the_same_moment() {
+        let submitted_at = Utc::now() - chrono::Duration::seconds(10);
+        let swept_at = submitted_at + chrono::Duration::seconds(5);
+        let pegged = Order {
+            order_type: OrderType::Pegged {
+                reference: PegReference::BestBid,
+                offset: dec!(-0.5),
+            },
+            ..Order::test_limit(Side::Buy, dec!(99.5), dec!(1), submitted_at)
+        };
+        let pegged_id = pegged.id;
+        let mut events: Vec<RecordedEvent> = [
+            gtd_bid("BTC-USD", submitted_at + chrono::Duration::seconds(1)),
+            Order::test_limit(Side::Buy, dec!(99), dec!(1), submitted_at),
+            pegged,
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(seq, order)| RecordedEvent {
+            seq: seq as u64,
+            symbol: "BTC-USD".to_string(),
+            timestamp: submitted_at,
+            event: BookEvent::Submitted { order },
+        })
+        .collect();
+
+        let mut order_book = OrderBook::new();
+        for recorded in &events {
+            order_book.apply_event(recorded.event.clone(), recorded.timestamp);
+        }
+        let mut order_books = HashMap::from([("BTC-USD".to_string(), order_book)]);
+        let mut logged = Vec::new();
+        sweep(&mut order_books, swept_at, |symbol, event| {
+            logged.push((symbol.to_string(), event.clone()))
+        });
+        for (symbol, event) in logged {
+            events.push(RecordedEvent {
+                seq: events.len() as u64,
+                symbol,
+                timestamp: swept_at,
+                event,
+            });
+        }
+
+        let replayed = reconstruct_book_at(
+            OrderBook::new(),
+            &events,
+            swept_at + chrono::Duration::seconds(5),
+        );
+        let live = &order_books["BTC-USD"].bids[&dec!(98.5)][0];
+        let rebuilt = &replayed.bids[&dec!(98.5)][0];
+        assert_eq!(live.id, pegged_id);
+        assert_eq!(rebuilt.id, pegged_id);
+        assert_eq!(rebuilt.timestamp, swept_at);
+        assert_eq!(rebuilt.timestamp, live.timestamp);
+    }
+}
@@ -0,0 +1,150 @@
+use crate::AppState;
+use crate::db::{self, TradeRecord};
+use axum::{
+    body::Body,
+    extract::{Query, State},
+    http::header,
+    response::IntoResponse,
+};
+use futures_util::StreamExt;
+use serde::Deserialize;
+use std::borrow::Cow;
+use tokio::sync::mpsc;
+
+/// How many formatted rows wait for a slow client before the database
+/// reads pause.
+const EXPORT_CHANNEL_CAPACITY: usize = 256;
+
+pub const CSV_HEADER: &str = "seq,id,symbol,timestamp,taker_side,price,quantity,maker_fee,taker_fee,maker_order_id,taker_order_id,maker_account_id,taker_account_id\n";
+
+/// Query of `GET /trades/export.csv`. Without a symbol every trade is
+/// exported.
+#[derive(Debug, Deserialize)]
+pub struct ExportQuery {
+    pub symbol: Option<String>,
+}
+
+/// One trade as a CSV line, in `CSV_HEADER` order. Columns stored trades
+/// may lack are left empty. `Decimal` prints every digit and never uses
+/// scientific notation, so amounts go out exactly as stored.
+pub fn trade_row(trade: &TradeRecord) -> String {
+    let optional = |value: Option<String>| value.unwrap_or_default();
+    let fields = [
+        optional(trade.seq.map(|seq| seq.to_string())),
+        trade.id.to_string(),
+        trade.symbol.clone(),
+        trade.timestamp.to_rfc3339(),
+        optional(trade.taker_side.clone()),
+        trade.price.to_string(),
+        trade.quantity.to_string(),
+        trade.maker_fee.to_string(),
+        trade.taker_fee.to_string(),
+        trade.maker_order_id.to_string(),
+        trade.taker_order_id.to_string(),
+        optional(trade.maker_account_id.map(|id| id.to_string())),
+        optional(trade.taker_account_id.map(|id| id.to_string())),
+    ];
+    let mut row = fields
+        .iter()
+        .map(|field| csv_field(field))
+        .collect::<Vec<_>>()
+        .join(",");
+    row.push('\n');
+    row
+}
+
+/// Quotes `field` if it holds a separator, quote or line break, doubling
+/// any quotes inside.
+fn csv_field(field: &str) -> Cow<'_, str> {
+    if field.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
+
+/// Streams the stored trades as CSV, a row at a time as the database
+/// yields them. A database error part way ends the body early, so the
+/// client sees a failed transfer rather than a short file.
+pub(crate) async fn export_trades_csv(
+    State(state): State<AppState>,
+    Query(query): Query<ExportQuery>,
+) -> impl IntoResponse {
+    let (row_tx, row_rx) = mpsc::channel::<Result<String, std::io::Error>>(EXPORT_CHANNEL_CAPACITY);
+    tokio::spawn(async move {
+        if row_tx.send(Ok(CSV_HEADER.to_string())).await.is_err() {
+            return;
+        }
+        let mut trades = db::stream_trades(&state.db_pool, query.symbol.as_deref());
+        while let Some(trade) = trades.next().await {
+            let row = trade.map(|trade| trade_row(&trade)).map_err(|e| {
+                eprintln!("Failed to export trades from DB: {}", e);
+                std::io::Error::other(e)
+            });
+            let failed = row.is_err();
+            if row_tx.send(row).await.is_err() || failed {
+                return;
+            }
+        }
+    });
+
+    let rows = futures_util::stream::unfold(row_rx, |mut row_rx| async move {
+        row_rx.recv().await.map(|row| (row, row_rx))
+    });
+    (
+        [
+            (header::CONTENT_TYPE, "text/csv"),
+            (
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"trades.csv\"",
+            ),
+        ],
+        Body::from_stream(rows),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+    use rust_decimal_macros::dec;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_trade_rows_quote_fields_and_keep_decimals_plain() {
+        let trade = TradeRecord {
+            id: Uuid::from_u128(1),
+            symbol: "BTC-USD".to_string(),
+            maker_order_id: Uuid::from_u128(2),
+            taker_order_id: Uuid::from_u128(3),
+            taker_side: Some("Buy".to_string()),
+            maker_account_id: Some(Uuid::from_u128(4)),
+            taker_account_id: Some(Uuid::from_u128(5)),
+            price: dec!(100.50),
+            quantity: dec!(0.00000001),
+            maker_fee: dec!(0.0000000005025),
+            taker_fee: dec!(0.00000001005),
+            timestamp: Utc.with_ymd_and_hms(2026, 10, 14, 12, 0, 0).unwrap(),
+            seq: Some(7),
+        };
+        assert_eq!(
+            trade_row(&trade),
+            "7,00000000-0000-0000-0000-000000000001,BTC-USD,2026-10-14T12:00:00+00:00,Buy,\
+             100.50,0.00000001,0.0000000005025,0.00000001005,\
+             00000000-0000-0000-0000-000000000002,00000000-0000-0000-0000-000000000003,\
+             00000000-0000-0000-0000-000000000004,00000000-0000-0000-0000-000000000005\n"
+        );
+
+        let legacy = TradeRecord {
+            symbol: "ODD,\"SYM\"".to_string(),
+            taker_side: None,
+            maker_account_id: None,
+            taker_account_id: None,
+            seq: None,
+            ..trade
+        };
+        let row = trade_row(&legacy);
+        assert!(row.starts_with(",00000000-0000-0000-0000-000000000001,\"ODD,\"\"SYM\"\"\","));
+        assert!(row.ends_with("00000000-0000-0000-0000-000000000003,,\n"));
+    }
+}
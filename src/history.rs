@@ -0,0 +1,81 @@
+use crate::matching_engine::Trade;
+use std::collections::VecDeque;
+
+pub const DEFAULT_TRADE_HISTORY_CAPACITY: usize = 1000;
+
+/// The most recent trades kept in memory, so they can be served without a
+/// database round trip. Holds at most `capacity` trades, dropping the
+/// oldest first.
+#[derive(Debug)]
+pub struct TradeHistory {
+    capacity: usize,
+    trades: VecDeque<Trade>,
+}
+
+impl TradeHistory {
+    pub fn new(capacity: usize) -> Self {
+        TradeHistory {
+            capacity,
+            trades: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub fn record_trade(&mut self, trade: Trade) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.trades.len() == self.capacity {
+            self.trades.pop_front();
+        }
+        self.trades.push_back(trade);
+    }
+
+    /// Up to `limit` trades, newest first.
+    pub fn recent_trades(&self, limit: usize) -> Vec<Trade> {
+        self.trades.iter().rev().take(limit).cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use rust_decimal::Decimal;
+
+    fn trade_with_quantity(quantity: i64) -> Trade {
+        Trade::test_at(
+            "BTC-USD",
+            Decimal::ONE_HUNDRED,
+            Decimal::from(quantity),
+            Utc::now(),
+        )
+    }
+
+    fn quantities(trades: &[Trade]) -> Vec<Decimal> {
+        trades.iter().map(|t| t.quantity).collect()
+    }
+
+    #[test]
+    fn test_overflow_evicts_oldest_and_returns_newest_first() {
+        let mut history = TradeHistory::new(3);
+        for quantity in 1..=5 {
+            history.record_trade(trade_with_quantity(quantity));
+        }
+
+        assert_eq!(
+            quantities(&history.recent_trades(10)),
+            vec![Decimal::from(5), Decimal::from(4), Decimal::from(3)]
+        );
+        assert_eq!(
+            quantities(&history.recent_trades(2)),
+            vec![Decimal::from(5), Decimal::from(4)]
+        );
+    }
+
+    #[test]
+    fn test_zero_capacity_keeps_nothing() {
+        let mut history = TradeHistory::new(0);
+        history.record_trade(trade_with_quantity(1));
+        assert!(history.recent_trades(10).is_empty());
+    }
+}
@@ -0,0 +1,179 @@
+use crate::matching_engine::OrderResult;
+use chrono::{DateTime, Duration, Utc};
+use std::collections::{HashMap, VecDeque};
+use uuid::Uuid;
+
+pub const DEFAULT_IDEMPOTENCY_CAPACITY: usize = 10_000;
+pub const DEFAULT_IDEMPOTENCY_WINDOW_SECS: i64 = 300;
+
+/// A client order id is only unique within the account that chose it.
+pub type IdempotencyKey = (Uuid, String);
+
+/// Results of recently submitted orders by client order id, so a retried
+/// submission gets the original result back instead of matching twice.
+/// Entries expire after `window` and the oldest go first once `capacity`
+/// is reached.
+#[derive(Debug)]
+pub struct IdempotencyCache {
+    capacity: usize,
+    window: Duration,
+    /// `None` while the order is reserved but has no result yet.
+    results: HashMap<IdempotencyKey, Option<OrderResult>>,
+    /// Keys in the order they were recorded, oldest first.
+    seen: VecDeque<(DateTime<Utc>, IdempotencyKey)>,
+}
+
+impl IdempotencyCache {
+    pub fn new(capacity: usize, window: Duration) -> Self {
+        IdempotencyCache {
+            capacity,
+            window,
+            results: HashMap::new(),
+            seen: VecDeque::new(),
+        }
+    }
+
+    /// The result recorded for `key`, if it is still inside the window.
+    pub fn get(&mut self, key: &IdempotencyKey, now: DateTime<Utc>) -> Option<OrderResult> {
+        self.expire(now);
+        self.results.get(key).cloned().flatten()
+    }
+
+    /// Whether `key` is reserved by an order still being submitted.
+    pub fn in_flight(&self, key: &IdempotencyKey) -> bool {
+        matches!(self.results.get(key), Some(None))
+    }
+
+    /// Claims `key` for an order about to be submitted, so a retry arriving
+    /// before its result is recorded finds it taken. False if it already
+    /// was, reserved or recorded.
+    pub fn reserve(&mut self, key: IdempotencyKey, now: DateTime<Utc>) -> bool {
+        self.expire(now);
+        if self.results.contains_key(&key) {
+            return false;
+        }
+        self.insert(key, None, now);
+        true
+    }
+
+    /// Gives up a reservation whose order was never submitted.
+    pub fn release(&mut self, key: &IdempotencyKey) {
+        if self.in_flight(key) {
+            self.results.remove(key);
+            self.seen.retain(|(_, seen)| seen != key);
+        }
+    }
+
+    /// Records the result for `key`, completing its reservation if it has
+    /// one. A result already recorded is kept.
+    pub fn record(&mut self, key: IdempotencyKey, result: OrderResult, now: DateTime<Utc>) {
+        self.expire(now);
+        match self.results.get_mut(&key) {
+            Some(Some(_)) => {}
+            Some(reserved) => *reserved = Some(result),
+            None => self.insert(key, Some(result), now),
+        }
+    }
+
+    fn insert(&mut self, key: IdempotencyKey, result: Option<OrderResult>, now: DateTime<Utc>) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.seen.len() == self.capacity
+            && let Some((_, oldest)) = self.seen.pop_front()
+        {
+            self.results.remove(&oldest);
+        }
+        self.seen.push_back((now, key.clone()));
+        self.results.insert(key, result);
+    }
+
+    fn expire(&mut self, now: DateTime<Utc>) {
+        let cutoff = now - self.window;
+        while let Some((recorded_at, _)) = self.seen.front()
+            && *recorded_at <= cutoff
+        {
+            let (_, key) = self.seen.pop_front().unwrap();
+            self.results.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matching_engine::OrderStatus;
+    use rust_decimal::Decimal;
+
+    fn result() -> OrderResult {
+        OrderResult {
+            order_id: Uuid::new_v4(),
+            status: OrderStatus::Resting,
+            filled_quantity: Decimal::ZERO,
+            remaining_quantity: Decimal::ONE,
+            average_price: None,
+            trades: Vec::new(),
+            triggered: Vec::new(),
+        }
+    }
+
+    fn key(client_order_id: &str) -> IdempotencyKey {
+        (Uuid::nil(), client_order_id.to_string())
+    }
+
+    #[test]
+    fn test_entries_expire_after_window() {
+        let now = Utc::now();
+        let mut cache = IdempotencyCache::new(10, Duration::seconds(60));
+        let recorded = result();
+        cache.record(key("a"), recorded.clone(), now);
+
+        let cached = cache.get(&key("a"), now + Duration::seconds(59)).unwrap();
+        assert_eq!(cached.order_id, recorded.order_id);
+        assert!(cache.get(&key("a"), now + Duration::seconds(60)).is_none());
+    }
+
+    #[test]
+    fn test_oldest_entry_evicted_at_capacity() {
+        let now = Utc::now();
+        let mut cache = IdempotencyCache::new(2, Duration::seconds(60));
+        for id in ["a", "b", "c"] {
+            cache.record(key(id), result(), now);
+        }
+
+        assert!(cache.get(&key("a"), now).is_none());
+        assert!(cache.get(&key("b"), now).is_some());
+        assert!(cache.get(&key("c"), now).is_some());
+    }
+
+    #[test]
+    fn test_reserved_key_is_taken_until_released() {
+        let now = Utc::now();
+        let mut cache = IdempotencyCache::new(10, Duration::seconds(60));
+        assert!(cache.reserve(key("a"), now));
+        assert!(cache.in_flight(&key("a")));
+        assert!(!cache.reserve(key("a"), now));
+        assert!(cache.get(&key("a"), now).is_none());
+
+        cache.release(&key("a"));
+        assert!(cache.reserve(key("a"), now));
+        let recorded = result();
+        cache.record(key("a"), recorded.clone(), now);
+        assert!(!cache.in_flight(&key("a")));
+        assert!(!cache.reserve(key("a"), now));
+        cache.release(&key("a"));
+        assert_eq!(
+            cache.get(&key("a"), now).unwrap().order_id,
+            recorded.order_id
+        );
+    }
+
+    #[test]
+    fn test_same_id_on_another_account_is_distinct() {
+        let now = Utc::now();
+        let mut cache = IdempotencyCache::new(10, Duration::seconds(60));
+        cache.record(key("a"), result(), now);
+
+        assert!(cache.get(&(Uuid::new_v4(), "a".to_string()), now).is_none());
+    }
+}
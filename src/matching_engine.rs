@@ -1,346 +1,5282 @@
-
-use crate::{Order, Side};
-use chrono::{DateTime, Utc};
-use rust_decimal::Decimal;
-use serde::Serialize;
-use std::collections::BTreeMap;
-use uuid::Uuid;
-
-#[derive(Debug, Clone, Serialize)]
-pub struct Trade {
-    pub maker_order_id: Uuid,
-    pub taker_order_id: Uuid,
-    pub price: Decimal,
-    pub quantity: Decimal,
-    pub timestamp: DateTime<Utc>,
-}
-
-pub struct OrderBook {
-    pub bids: BTreeMap<Decimal, Vec<Order>>,
-    pub asks: BTreeMap<Decimal, Vec<Order>>,
-}
-
-impl OrderBook {
-    pub fn new() -> Self {
-        OrderBook {
-            bids: BTreeMap::new(),
-            asks: BTreeMap::new(),
-        }
-    }
-
-    pub fn add_order(&mut self, order: Order) {
-        if let Some(price) = order.price {
-            match order.side {
-                Side::Buy => {
-                    self.bids.entry(price).or_default().push(order);
-                }
-                Side::Sell => {
-                    self.asks.entry(price).or_default().push(order);
-                }
-            }
-        }
-    }
-
-    pub fn detect_arbitrage(&self, new_order: &Order) -> Option<String> {
-        let new_price = if let Some(p) = new_order.price { p } else { return None; };
-
-        match new_order.side {
-            Side::Buy => {
-                if let Some((best_ask_price, _)) = self.asks.iter().next() {
-                    if new_price > *best_ask_price {
-                        return Some(format!(
-                            "Arbitrage: Incoming BUY order at {} is higher than best ASK of {}. Opportunity to buy at {} and sell at {}.",
-                            new_price, best_ask_price, best_ask_price, new_price
-                        ));
-                    }
-                }
-            }
-            Side::Sell => {
-                if let Some((best_bid_price, _)) = self.bids.iter().rev().next() {
-                    if new_price < *best_bid_price {
-                        return Some(format!(
-                            "Arbitrage: Incoming SELL order at {} is lower than best BID of {}. Opportunity to buy at {} and sell at {}.",
-                            new_price, best_bid_price, new_price, best_bid_price
-                        ));
-                    }
-                }
-            }
-        }
-        None
-    }
-    pub fn match_order(&mut self, mut taker_order: Order) -> Vec<Trade> {
-        let mut trades = Vec::new();
-
-        let taker_price = match taker_order.price {
-            Some(price) => price,
-            None => {
-                println!("Market orders not yet implemented.");
-                return trades;
-            }
-        };
-
-        match taker_order.side {
-            Side::Buy => {
-                let mut filled_ask_levels = Vec::new();
-
-                for (&ask_price, orders_at_level) in self.asks.iter_mut() {
-                    if taker_order.quantity == Decimal::ZERO {
-                        break;
-                    }
-                    if ask_price > taker_price {
-                        break;
-                    }
-
-                    let mut filled_maker_indices = Vec::new();
-                    for (i, maker_order) in orders_at_level.iter_mut().enumerate() {
-                        if taker_order.quantity == Decimal::ZERO {
-                            break;
-                        }
-
-                        let trade_quantity = taker_order.quantity.min(maker_order.quantity);
-
-                        trades.push(Trade {
-                            maker_order_id: maker_order.id,
-                            taker_order_id: taker_order.id,
-                            price: maker_order.price.unwrap(),
-                            quantity: trade_quantity,
-                            timestamp: Utc::now(),
-                        });
-
-                        maker_order.quantity -= trade_quantity;
-                        taker_order.quantity -= trade_quantity;
-
-                        if maker_order.quantity == Decimal::ZERO {
-                            filled_maker_indices.push(i);
-                        }
-                    }
-
-                    for i in filled_maker_indices.into_iter().rev() {
-                        orders_at_level.remove(i);
-                    }
-
-                    if orders_at_level.is_empty() {
-                        filled_ask_levels.push(ask_price);
-                    }
-                }
-
-                for price in filled_ask_levels {
-                    self.asks.remove(&price);
-                }
-            }
-            Side::Sell => {
-                let mut filled_bid_levels = Vec::new();
-
-                for (&bid_price, orders_at_level) in self.bids.iter_mut().rev() {
-                    if taker_order.quantity == Decimal::ZERO {
-                        break;
-                    }
-                    if bid_price < taker_price {
-                        break;
-                    }
-
-                    let mut filled_maker_indices = Vec::new();
-                    for (i, maker_order) in orders_at_level.iter_mut().enumerate() {
-                        if taker_order.quantity == Decimal::ZERO {
-                            break;
-                        }
-
-                        let trade_quantity = taker_order.quantity.min(maker_order.quantity);
-
-                        trades.push(Trade {
-                            maker_order_id: maker_order.id,
-                            taker_order_id: taker_order.id,
-                            price: maker_order.price.unwrap(),
-                            quantity: trade_quantity,
-                            timestamp: Utc::now(),
-                        });
-
-                        maker_order.quantity -= trade_quantity;
-                        taker_order.quantity -= trade_quantity;
-
-                        if maker_order.quantity == Decimal::ZERO {
-                            filled_maker_indices.push(i);
-                        }
-                    }
-
-                    for i in filled_maker_indices.into_iter().rev() {
-                        orders_at_level.remove(i);
-                    }
-
-                    if orders_at_level.is_empty() {
-                        filled_bid_levels.push(bid_price);
-                    }
-                }
-
-                for price in filled_bid_levels {
-                    self.bids.remove(&price);
-                }
-            }
-        }
-
-        if taker_order.quantity > Decimal::ZERO {
-            self.add_order(taker_order);
-        }
-
-        trades
-    }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::{Order, OrderType};
-    use rust_decimal_macros::dec;
-
-    fn create_test_order(side: Side, price: Decimal, quantity: Decimal) -> Order {
-        Order {
-            id: Uuid::new_v4(),
-            order_type: OrderType::Limit,
-            side,
-            price: Some(price),
-            quantity,
-            timestamp: Utc::now(),
-        }
-    }
-
-    #[test]
-    fn test_arbitrage_detection_sell_side() {
-        let mut order_book = OrderBook::new();
-        order_book.add_order(create_test_order(Side::Buy, dec!(101.0), dec!(10.0)));
-
-        let new_sell_order = create_test_order(Side::Sell, dec!(100.0), dec!(5.0));
-
-        let mev = order_book.detect_arbitrage(&new_sell_order);
-        assert!(mev.is_some());
-        println!("Detected MEV: {}", mev.unwrap());
-    }
-
-    #[test]
-    fn test_arbitrage_detection_buy_side() {
-        let mut order_book = OrderBook::new();
-        order_book.add_order(create_test_order(Side::Sell, dec!(100.0), dec!(10.0)));
-        
-        let new_buy_order = create_test_order(Side::Buy, dec!(101.0), dec!(5.0));
-
-        let mev = order_book.detect_arbitrage(&new_buy_order);
-        assert!(mev.is_some());
-        println!("Detected MEV: {}", mev.unwrap());
-    }
-
-    #[test]
-    fn test_no_arbitrage() {
-        let mut order_book = OrderBook::new();
-        order_book.add_order(create_test_order(Side::Buy, dec!(100.0), dec!(10.0)));
-        let new_sell_order = create_test_order(Side::Sell, dec!(101.0), dec!(5.0));
-        assert!(order_book.detect_arbitrage(&new_sell_order).is_none());
-    }
-
-    #[test]
-    fn test_add_order() {
-        let mut order_book = OrderBook::new();
-        let buy_order = create_test_order(Side::Buy, dec!(100.0), dec!(10.0));
-        let sell_order = create_test_order(Side::Sell, dec!(101.0), dec!(5.0));
-
-        order_book.add_order(buy_order);
-        order_book.add_order(sell_order);
-
-        assert_eq!(order_book.bids.len(), 1);
-        assert_eq!(order_book.asks.len(), 1);
-        assert_eq!(
-            order_book.bids.get(&dec!(100.0)).unwrap()[0].quantity,
-            dec!(10.0)
-        );
-        assert_eq!(
-            order_book.asks.get(&dec!(101.0)).unwrap()[0].quantity,
-            dec!(5.0)
-        );
-    }
-
-    #[test]
-    fn test_simple_match_full_fill() {
-        let mut order_book = OrderBook::new();
-        let sell_maker = create_test_order(Side::Sell, dec!(100.0), dec!(10.0));
-        order_book.add_order(sell_maker);
-
-        let buy_taker = create_test_order(Side::Buy, dec!(100.0), dec!(10.0));
-        let trades = order_book.match_order(buy_taker);
-
-        assert_eq!(trades.len(), 1);
-        assert_eq!(trades[0].quantity, dec!(10.0));
-        assert_eq!(trades[0].price, dec!(100.0));
-        assert!(order_book.asks.is_empty());
-        assert!(order_book.bids.is_empty());
-    }
-
-    #[test]
-    fn test_simple_match_partial_fill_of_maker() {
-        let mut order_book = OrderBook::new();
-        let sell_maker = create_test_order(Side::Sell, dec!(100.0), dec!(10.0));
-        order_book.add_order(sell_maker);
-
-        let buy_taker = create_test_order(Side::Buy, dec!(100.0), dec!(5.0));
-        let trades = order_book.match_order(buy_taker);
-
-        assert_eq!(trades.len(), 1);
-        assert_eq!(trades[0].quantity, dec!(5.0));
-        assert_eq!(
-            order_book.asks.get(&dec!(100.0)).unwrap()[0].quantity,
-            dec!(5.0)
-        );
-        assert!(order_book.bids.is_empty());
-    }
-
-    #[test]
-    fn test_partial_fill_of_taker() {
-        let mut order_book = OrderBook::new();
-        let sell_maker = create_test_order(Side::Sell, dec!(100.0), dec!(10.0));
-        order_book.add_order(sell_maker);
-
-        let buy_taker = create_test_order(Side::Buy, dec!(100.0), dec!(15.0));
-        let trades = order_book.match_order(buy_taker);
-
-        assert_eq!(trades.len(), 1);
-        assert_eq!(trades[0].quantity, dec!(10.0));
-        assert!(order_book.asks.is_empty()); 
-        assert_eq!(
-            order_book.bids.get(&dec!(100.0)).unwrap()[0].quantity,
-            dec!(5.0)
-        ); 
-    }
-
-    #[test]
-    fn test_multi_level_match() {
-        let mut order_book = OrderBook::new();
-        order_book.add_order(create_test_order(Side::Sell, dec!(100.0), dec!(5.0)));
-        order_book.add_order(create_test_order(Side::Sell, dec!(101.0), dec!(5.0)));
-
-        let buy_taker = create_test_order(Side::Buy, dec!(101.0), dec!(8.0));
-        let trades = order_book.match_order(buy_taker);
-
-        assert_eq!(trades.len(), 2);
-        assert_eq!(trades[0].price, dec!(100.0));
-        assert_eq!(trades[0].quantity, dec!(5.0));
-        assert_eq!(trades[1].price, dec!(101.0));
-        assert_eq!(trades[1].quantity, dec!(3.0));
-
-        assert!(order_book.bids.is_empty());
-        assert_eq!(order_book.asks.len(), 1);
-        assert_eq!(
-            order_book.asks.get(&dec!(101.0)).unwrap()[0].quantity,
-            dec!(2.0)
-        );
-    }
-
-    #[test]
-    fn test_no_match() {
-        let mut order_book = OrderBook::new();
-        order_book.add_order(create_test_order(Side::Sell, dec!(101.0), dec!(10.0)));
-
-        let buy_taker = create_test_order(Side::Buy, dec!(100.0), dec!(10.0));
-        let trades = order_book.match_order(buy_taker);
-
-        assert!(trades.is_empty());
-        assert_eq!(order_book.bids.len(), 1); 
-        assert_eq!(order_book.asks.len(), 1);
-    }
-}
\ No newline at end of file
+use crate::amount::{Amount, is_effectively_zero};
+use crate::money::{FEE_SCALE, PRICE_SCALE};
+use crate::stats::MarketStats;
+use crate::{Order, OrderType, PegReference, Side, TimeInForce};
+use chrono::{DateTime, Utc};
+use crc::{CRC_32_ISO_HDLC, Crc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Trade<N = Decimal> {
+    /// Drawn from the book's `trade_ids` once matching is done, in the
+    /// order the trades executed.
+    pub id: Uuid,
+    pub symbol: String,
+    pub maker_order_id: Uuid,
+    pub taker_order_id: Uuid,
+    pub maker_account_id: Uuid,
+    /// The aggressor, whose order took liquidity on `taker_side`.
+    pub taker_account_id: Uuid,
+    pub taker_side: Side,
+    pub price: N,
+    pub quantity: N,
+    /// How much better than its limit the taker traded. Zero for market
+    /// orders, which have no limit to improve on.
+    pub price_improvement: N,
+    pub maker_fee: N,
+    pub taker_fee: N,
+    pub timestamp: DateTime<Utc>,
+    /// Position in the exchange-wide trade sequence, assigned by the server
+    /// as the trade executes so clients can spot missed trades. 0 until
+    /// then, so always 0 for trades from a bare book.
+    pub seq: u64,
+}
+
+const BASIS_POINTS_PER_UNIT: u16 = 10000;
+
+/// How many levels per side the checksums in depth snapshots and book
+/// deltas cover.
+pub const CHECKSUM_LEVELS: usize = 10;
+
+const CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+/// Fee rates charged on each trade's notional, in basis points.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct FeeSchedule<N = Decimal> {
+    pub maker_bps: N,
+    pub taker_bps: N,
+}
+
+impl<N: Amount> FeeSchedule<N> {
+    pub fn maker_fee(&self, price: N, quantity: N) -> N {
+        (price * quantity * self.maker_bps / N::from(BASIS_POINTS_PER_UNIT))
+            .round_to_scale(FEE_SCALE)
+    }
+
+    pub fn taker_fee(&self, price: N, quantity: N) -> N {
+        (price * quantity * self.taker_bps / N::from(BASIS_POINTS_PER_UNIT))
+            .round_to_scale(FEE_SCALE)
+    }
+}
+
+impl<N: Amount> Trade<N> {
+    /// Volume-weighted average price over `trades`, rounded to
+    /// `PRICE_SCALE`, or `None` when nothing traded.
+    pub fn vwap(trades: &[Trade<N>]) -> Option<N> {
+        let total_quantity: N = trades.iter().map(|t| t.quantity).sum();
+        if total_quantity == N::ZERO {
+            return None;
+        }
+        let notional: N = trades.iter().map(|t| t.price * t.quantity).sum();
+        Some((notional / total_quantity).round_to_scale(PRICE_SCALE))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum OrderStatus {
+    /// The whole order traded.
+    Filled,
+    /// Some of the order traded; the remainder rested or was discarded
+    /// depending on its time in force.
+    PartiallyFilled,
+    /// Nothing traded and the order now rests on the book.
+    Resting,
+    /// Nothing traded and nothing rests, e.g. a killed FOK order.
+    Rejected,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct OrderResult<N = Decimal> {
+    pub order_id: Uuid,
+    pub status: OrderStatus,
+    pub filled_quantity: N,
+    pub remaining_quantity: N,
+    pub average_price: Option<N>,
+    pub trades: Vec<Trade<N>>,
+    /// Results for stop orders this order's trades set off.
+    pub triggered: Vec<OrderResult<N>>,
+}
+
+impl<N: Amount> OrderResult<N> {
+    /// Result for order `order_id` of `quantity` that made `trades` and,
+    /// if `rested`, left a remainder on the book. A remainder of dust is
+    /// dropped rather than rested, so it counts as filled.
+    pub fn new(order_id: Uuid, quantity: N, trades: Vec<Trade<N>>, rested: bool) -> Self {
+        let filled_quantity: N = trades.iter().map(|t| t.quantity).sum();
+        let mut remaining_quantity = quantity - filled_quantity;
+        if filled_quantity > N::ZERO && is_effectively_zero(remaining_quantity, N::EPSILON) {
+            remaining_quantity = N::ZERO;
+        }
+        let status = if remaining_quantity == N::ZERO {
+            OrderStatus::Filled
+        } else if filled_quantity > N::ZERO {
+            OrderStatus::PartiallyFilled
+        } else if rested {
+            OrderStatus::Resting
+        } else {
+            OrderStatus::Rejected
+        };
+
+        OrderResult {
+            order_id,
+            status,
+            filled_quantity,
+            remaining_quantity,
+            average_price: Trade::vwap(&trades),
+            trades,
+            triggered: Vec::new(),
+        }
+    }
+
+    /// Trades of this order followed by those of every stop it triggered,
+    /// directly or through a cascade.
+    pub fn all_trades(&self) -> Vec<&Trade<N>> {
+        let mut trades: Vec<&Trade<N>> = self.trades.iter().collect();
+        for triggered in &self.triggered {
+            trades.extend(triggered.all_trades());
+        }
+        trades
+    }
+
+    /// Result for an order that never traded or rested.
+    pub fn rejected(order: &Order<N>) -> Self {
+        OrderResult::new(order.id, order.quantity, Vec::new(), false)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PriceLevel<N = Decimal> {
+    pub price: N,
+    pub total_quantity: N,
+    pub order_count: usize,
+}
+
+impl<N: Amount> PriceLevel<N> {
+    fn aggregate(price: N, orders: &VecDeque<Order<N>>) -> Self {
+        PriceLevel {
+            price,
+            total_quantity: orders.iter().map(visible_quantity).sum(),
+            order_count: orders.len(),
+        }
+    }
+}
+
+/// The best level on each side, as the top-of-book feed shows it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TopOfBook<N = Decimal> {
+    pub bid: Option<PriceLevel<N>>,
+    pub ask: Option<PriceLevel<N>>,
+}
+
+/// The top of book as last reported next to the top of book now.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TopOfBookChange<N = Decimal> {
+    pub previous: TopOfBook<N>,
+    pub current: TopOfBook<N>,
+    pub changed: bool,
+}
+
+/// One resting order as raw depth shows it. Icebergs only show their
+/// visible slice, as in the aggregated depth.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RawOrder<N = Decimal> {
+    pub order_id: Uuid,
+    pub quantity: N,
+}
+
+/// Every order resting at one price, in the order they will match.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RawLevel<N = Decimal> {
+    pub price: N,
+    pub orders: Vec<RawOrder<N>>,
+}
+
+impl<N: Amount> RawLevel<N> {
+    fn list(price: N, orders: &VecDeque<Order<N>>) -> Self {
+        RawLevel {
+            price,
+            orders: orders
+                .iter()
+                .map(|order| RawOrder {
+                    order_id: order.id,
+                    quantity: visible_quantity(order),
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct DepthSnapshot<N = Decimal> {
+    pub bids: Vec<PriceLevel<N>>,
+    pub asks: Vec<PriceLevel<N>>,
+    /// `OrderBook::checksum` over the top `CHECKSUM_LEVELS`, whatever
+    /// number of levels the snapshot itself holds.
+    pub checksum: u32,
+}
+
+/// One side of a level 2 view: the best levels as `[price, quantity]`,
+/// and the visible quantity of every level past them summed into `rest`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Level2Side<N = Decimal> {
+    pub levels: Vec<[N; 2]>,
+    pub rest: N,
+}
+
+/// What a market order would get if it swept the book right now.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct FillQuote<N = Decimal> {
+    pub filled_quantity: N,
+    /// Rounded to `PRICE_SCALE`; `None` when nothing would fill.
+    pub average_price: Option<N>,
+    /// Sum of price times quantity over the levels swept.
+    pub total_cost: N,
+    /// Whether the book holds the whole quantity asked for.
+    pub fully_fillable: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Level2<N = Decimal> {
+    pub bids: Level2Side<N>,
+    pub asks: Level2Side<N>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OrderBookError<N = Decimal> {
+    MissingPrice,
+    /// Resting the order would open a price level beyond the per-side cap,
+    /// and it is no better than the worst level already there.
+    BookFull {
+        price: N,
+        worst: Option<N>,
+    },
+    /// The order would trade against the opposite side and must go through
+    /// `match_order` instead of resting directly.
+    WouldCross {
+        price: N,
+        opposite: N,
+    },
+}
+
+impl<N: fmt::Display> fmt::Display for OrderBookError<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OrderBookError::MissingPrice => write!(f, "order has no price and cannot rest"),
+            OrderBookError::BookFull {
+                price,
+                worst: Some(worst),
+            } => write!(
+                f,
+                "book is full and price {} is no better than the worst level {}",
+                price, worst
+            ),
+            OrderBookError::BookFull { price, worst: None } => {
+                write!(f, "book takes no price levels, cannot rest at {}", price)
+            }
+            OrderBookError::WouldCross { price, opposite } => write!(
+                f,
+                "order at {} would cross the opposite side at {}",
+                price, opposite
+            ),
+        }
+    }
+}
+
+impl<N: fmt::Debug + fmt::Display> std::error::Error for OrderBookError<N> {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReduceError {
+    UnknownOrder(Uuid),
+    /// Reductions must be for a positive amount.
+    NonPositiveAmount,
+}
+
+impl fmt::Display for ReduceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReduceError::UnknownOrder(id) => write!(f, "order {} is not resting", id),
+            ReduceError::NonPositiveAmount => write!(f, "reduction must be positive"),
+        }
+    }
+}
+
+impl std::error::Error for ReduceError {}
+
+/// The new aggregate quantity resting at one price level. A total of zero
+/// means the level is gone.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BookDelta<N = Decimal> {
+    pub symbol: String,
+    pub side: Side,
+    pub price: N,
+    pub new_total_quantity: N,
+    /// `OrderBook::checksum` over the top `CHECKSUM_LEVELS` once every
+    /// delta from the same update has been applied.
+    pub checksum: u32,
+}
+
+/// What to do when an incoming order would trade against a resting order
+/// from the same account.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum SelfTradePrevention {
+    /// Let the orders trade with each other.
+    None,
+    /// Cancel the resting order and keep matching the incoming one.
+    #[default]
+    CancelResting,
+}
+
+/// How a taker's fill is shared among the makers at one price level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum MatchingPolicy {
+    /// Strict price-time priority: the oldest maker fills first.
+    #[default]
+    Fifo,
+    /// A taker that cannot clear the level fills every maker there in
+    /// proportion to its shown quantity. See `allocate_pro_rata` for the
+    /// rounding rule.
+    ProRata,
+}
+
+impl FromStr for MatchingPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Fifo" => Ok(MatchingPolicy::Fifo),
+            "ProRata" => Ok(MatchingPolicy::ProRata),
+            other => Err(format!("unknown matching policy: {}", other)),
+        }
+    }
+}
+
+/// What price a fill executes at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum PricingMode {
+    /// At the maker's price, so the taker pays the spread.
+    #[default]
+    MakerPrice,
+    /// Halfway between the maker's price and the taker's limit, rounded to
+    /// `PRICE_SCALE`, sharing the price improvement between them. Market
+    /// orders have no limit and still fill at the maker's price.
+    Midpoint,
+}
+
+impl FromStr for PricingMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "MakerPrice" => Ok(PricingMode::MakerPrice),
+            "Midpoint" => Ok(PricingMode::Midpoint),
+            other => Err(format!("unknown pricing mode: {}", other)),
+        }
+    }
+}
+
+impl PricingMode {
+    pub fn trade_price<N: Amount>(self, maker_price: N, price_limit: Option<N>) -> N {
+        match (self, price_limit) {
+            (PricingMode::Midpoint, Some(limit)) => {
+                ((maker_price + limit) / N::from(2)).round_to_scale(PRICE_SCALE)
+            }
+            _ => maker_price,
+        }
+    }
+}
+
+/// How orders at one price with identical timestamps queue. Earlier
+/// timestamps always go first; ties only happen with a coarse clock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum LevelTiebreaker {
+    /// In the order they reached the book.
+    #[default]
+    InsertionOrder,
+    /// Larger remaining quantity first, then insertion order.
+    LargerFirst,
+}
+
+impl FromStr for LevelTiebreaker {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "InsertionOrder" => Ok(LevelTiebreaker::InsertionOrder),
+            "LargerFirst" => Ok(LevelTiebreaker::LargerFirst),
+            other => Err(format!("unknown level tiebreaker: {}", other)),
+        }
+    }
+}
+
+impl LevelTiebreaker {
+    /// Whether `resting` stays ahead of `incoming` when their timestamps tie.
+    fn keeps_ahead<N: Amount>(self, resting: &Order<N>, incoming: &Order<N>) -> bool {
+        match self {
+            LevelTiebreaker::InsertionOrder => true,
+            LevelTiebreaker::LargerFirst => resting.quantity >= incoming.quantity,
+        }
+    }
+}
+
+/// What happens to the part of a taker left over after matching when it is
+/// smaller than one lot, as fills against odd-sized makers can leave.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum SubLotRemainder {
+    /// Rests anyway, as an exception to the lot size.
+    #[default]
+    Rest,
+    /// Is cancelled, so every resting order stays a whole number of lots.
+    Cancel,
+}
+
+/// Where a book gets the ids of the trades it makes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum TradeIds {
+    /// Random ids, unique across books and restarts.
+    #[default]
+    Random,
+    /// 1, 2, 3 and so on as UUIDs, holding the last one given out, so the
+    /// same orders always make trades with the same ids. For tests and
+    /// replays: two books counting from the same point give out the same
+    /// ids.
+    Sequential { last: u64 },
+}
+
+impl TradeIds {
+    /// Sequential ids starting from 1.
+    pub fn sequential() -> Self {
+        TradeIds::Sequential { last: 0 }
+    }
+
+    fn next_id(&mut self) -> Uuid {
+        match self {
+            TradeIds::Random => Uuid::new_v4(),
+            TradeIds::Sequential { last } => {
+                *last += 1;
+                Uuid::from_u128(u128::from(*last))
+            }
+        }
+    }
+}
+
+/// An incoming order seen crossing the book before it matched, leaving a
+/// window to trade ahead of it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ArbitrageEvent<N = Decimal> {
+    pub symbol: String,
+    pub order_id: Uuid,
+    pub side: Side,
+    /// `None` for market orders.
+    pub order_price: Option<N>,
+    /// The best ask a buy crosses, or the best bid a sell crosses.
+    pub best_opposite_price: N,
+    /// How far the order's price is through `best_opposite_price`.
+    /// `None` for market orders, which have no price.
+    pub spread: Option<N>,
+    /// How much of the order the crossed levels could fill, walking every
+    /// level within its price and leaving out what self-trade prevention
+    /// would cancel.
+    pub available_quantity: N,
+    /// When the order arrived.
+    pub timestamp: DateTime<Utc>,
+}
+
+impl<N: fmt::Display> fmt::Display for ArbitrageEvent<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let best = &self.best_opposite_price;
+        match (self.side, &self.order_price) {
+            (Side::Buy, None) => write!(
+                f,
+                "Arbitrage: Incoming market BUY order will lift the best ASK of {}. Opportunity to buy at {} ahead of it.",
+                best, best
+            ),
+            (Side::Sell, None) => write!(
+                f,
+                "Arbitrage: Incoming market SELL order will hit the best BID of {}. Opportunity to sell at {} ahead of it.",
+                best, best
+            ),
+            (Side::Buy, Some(price)) => write!(
+                f,
+                "Arbitrage: Incoming BUY order at {} is higher than best ASK of {}. Opportunity to buy at {} and sell at {}.",
+                price, best, best, price
+            ),
+            (Side::Sell, Some(price)) => write!(
+                f,
+                "Arbitrage: Incoming SELL order at {} is lower than best BID of {}. Opportunity to buy at {} and sell at {}.",
+                price, best, price, best
+            ),
+        }
+    }
+}
+
+/// A change to a resting order that still needs to be written to storage.
+#[derive(Debug, Clone)]
+pub enum OrderChange<N = Decimal> {
+    /// The order rests on the book with this state.
+    Upsert(Order<N>),
+    /// The order no longer rests on the book.
+    Delete(Uuid),
+}
+
+/// Serializes with every internal field, for `GET /admin/book/dump`;
+/// clients should use the depth endpoints instead.
+#[derive(Debug, Clone, Serialize)]
+pub struct OrderBook<N = Decimal> {
+    pub bids: BTreeMap<N, VecDeque<Order<N>>>,
+    pub asks: BTreeMap<N, VecDeque<Order<N>>>,
+    /// Stop orders waiting off-book for the market to trade through their
+    /// stop price, in arrival order.
+    pub stops: Vec<Order<N>>,
+    pub self_trade_prevention: SelfTradePrevention,
+    pub matching_policy: MatchingPolicy,
+    pub pricing_mode: PricingMode,
+    pub level_tiebreaker: LevelTiebreaker,
+    /// Most distinct price levels each side may hold. Once full, a new
+    /// level is only opened by evicting the worst one.
+    pub max_price_levels_per_side: usize,
+    /// Most one taker may fill against any single maker. A taker that hits
+    /// the cap moves on to the next maker, which keeps its place in the
+    /// queue. `None`, the default, is no cap. Pro-rata levels ignore it.
+    pub max_fill_per_maker: Option<N>,
+    /// Resting orders whose remaining `price * quantity` falls below this
+    /// are cancelled when a taker reaches them instead of trading as dust.
+    pub min_trade_notional: N,
+    /// Smallest quantity the symbol trades in, if it has one. Only used to
+    /// decide what `sub_lot_remainder` applies to.
+    pub lot_size: Option<N>,
+    pub sub_lot_remainder: SubLotRemainder,
+    pub trade_ids: TradeIds,
+    pub fee_schedule: FeeSchedule<N>,
+    /// Orders held longer than this expire as if good till that date, so
+    /// quotes left behind by a vanished client do not linger. `None`, the
+    /// default, lets orders rest indefinitely. Set through
+    /// `set_max_order_age`.
+    max_order_age: Option<Duration>,
+    /// Left out of the dump; `GET /stats/:symbol` reports it.
+    #[serde(skip)]
+    pub stats: MarketStats<N>,
+    /// How many orders, resting or parked, each account has on the book.
+    /// Accounts with none are left out.
+    open_orders: HashMap<Uuid, usize>,
+    /// No order expires, by date or by age, before this, so sweeps until
+    /// then can be skipped. May be earlier than the true next expiry.
+    next_expiry: Option<DateTime<Utc>>,
+    /// Ids of resting orders added, modified, or removed since the last
+    /// call to `take_changes`.
+    dirty_orders: HashSet<Uuid>,
+    /// Price levels whose aggregate quantity changed since the last call to
+    /// `take_deltas`.
+    dirty_levels: BTreeSet<(Side, N)>,
+    /// Ids of the pegged orders resting, so `reprice_pegged` can skip books
+    /// without any. May still hold orders that have since left the book.
+    #[serde(skip)]
+    pegged: HashSet<Uuid>,
+    /// What `top_of_book_changed` last saw.
+    #[serde(skip)]
+    reported_top: TopOfBook<N>,
+}
+
+/// Every resting and parked order of a book together with its settings, as
+/// one blob a warm standby can load. Unlike the database copy it is taken
+/// atomically. Rolling stats are left out and start over on restore.
+///
+/// Amounts are always written as exact strings, even with the
+/// `decimal-as-number` feature, so a restored book is the book it was
+/// taken of down to the scale of every number.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(
+    into = "ExactSnapshot<N>",
+    from = "ExactSnapshot<N>",
+    bound(
+        serialize = "N: Amount",
+        deserialize = "N: Amount + FromStr, N::Err: fmt::Display"
+    )
+)]
+pub struct OrderBookSnapshot<N = Decimal> {
+    /// Bids, asks, then stops, each level in queue order.
+    pub orders: Vec<Order<N>>,
+    pub self_trade_prevention: SelfTradePrevention,
+    pub matching_policy: MatchingPolicy,
+    pub pricing_mode: PricingMode,
+    pub level_tiebreaker: LevelTiebreaker,
+    pub max_price_levels_per_side: usize,
+    pub min_trade_notional: N,
+    pub max_fill_per_maker: Option<N>,
+    pub fee_schedule: FeeSchedule<N>,
+    pub max_order_age: Option<Duration>,
+    pub lot_size: Option<N>,
+    pub sub_lot_remainder: SubLotRemainder,
+    pub trade_ids: TradeIds,
+}
+
+/// An amount written as its `Display` text and read back with `FromStr`,
+/// whatever serde does with `N` itself.
+#[derive(Debug, Clone, Copy, Default)]
+struct Exact<N>(N);
+
+impl<N: fmt::Display> Serialize for Exact<N> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&self.0)
+    }
+}
+
+impl<'de, N> Deserialize<'de> for Exact<N>
+where
+    N: FromStr,
+    N::Err: fmt::Display,
+{
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        text.parse().map(Exact).map_err(serde::de::Error::custom)
+    }
+}
+
+/// `OrderBookSnapshot` as it goes over the wire, every amount `Exact`.
+#[derive(Serialize, Deserialize)]
+#[serde(bound(
+    serialize = "N: fmt::Display",
+    deserialize = "N: FromStr + Default, N::Err: fmt::Display"
+))]
+struct ExactSnapshot<N> {
+    orders: Vec<Order<Exact<N>>>,
+    self_trade_prevention: SelfTradePrevention,
+    matching_policy: MatchingPolicy,
+    #[serde(default)]
+    pricing_mode: PricingMode,
+    #[serde(default)]
+    level_tiebreaker: LevelTiebreaker,
+    max_price_levels_per_side: usize,
+    #[serde(default)]
+    min_trade_notional: Exact<N>,
+    #[serde(default)]
+    max_fill_per_maker: Option<Exact<N>>,
+    fee_schedule: FeeSchedule<Exact<N>>,
+    #[serde(default)]
+    max_order_age: Option<Duration>,
+    #[serde(default)]
+    lot_size: Option<Exact<N>>,
+    #[serde(default)]
+    sub_lot_remainder: SubLotRemainder,
+    #[serde(default)]
+    trade_ids: TradeIds,
+}
+
+impl<N> From<OrderBookSnapshot<N>> for ExactSnapshot<N> {
+    fn from(snapshot: OrderBookSnapshot<N>) -> Self {
+        ExactSnapshot {
+            orders: snapshot
+                .orders
+                .into_iter()
+                .map(|order| map_amounts(order, Exact))
+                .collect(),
+            self_trade_prevention: snapshot.self_trade_prevention,
+            matching_policy: snapshot.matching_policy,
+            pricing_mode: snapshot.pricing_mode,
+            level_tiebreaker: snapshot.level_tiebreaker,
+            max_price_levels_per_side: snapshot.max_price_levels_per_side,
+            min_trade_notional: Exact(snapshot.min_trade_notional),
+            max_fill_per_maker: snapshot.max_fill_per_maker.map(Exact),
+            fee_schedule: FeeSchedule {
+                maker_bps: Exact(snapshot.fee_schedule.maker_bps),
+                taker_bps: Exact(snapshot.fee_schedule.taker_bps),
+            },
+            max_order_age: snapshot.max_order_age,
+            lot_size: snapshot.lot_size.map(Exact),
+            sub_lot_remainder: snapshot.sub_lot_remainder,
+            trade_ids: snapshot.trade_ids,
+        }
+    }
+}
+
+impl<N> From<ExactSnapshot<N>> for OrderBookSnapshot<N> {
+    fn from(snapshot: ExactSnapshot<N>) -> Self {
+        let exact = |Exact(amount)| amount;
+        OrderBookSnapshot {
+            orders: snapshot
+                .orders
+                .into_iter()
+                .map(|order| map_amounts(order, exact))
+                .collect(),
+            self_trade_prevention: snapshot.self_trade_prevention,
+            matching_policy: snapshot.matching_policy,
+            pricing_mode: snapshot.pricing_mode,
+            level_tiebreaker: snapshot.level_tiebreaker,
+            max_price_levels_per_side: snapshot.max_price_levels_per_side,
+            min_trade_notional: exact(snapshot.min_trade_notional),
+            max_fill_per_maker: snapshot.max_fill_per_maker.map(exact),
+            fee_schedule: FeeSchedule {
+                maker_bps: exact(snapshot.fee_schedule.maker_bps),
+                taker_bps: exact(snapshot.fee_schedule.taker_bps),
+            },
+            max_order_age: snapshot.max_order_age,
+            lot_size: snapshot.lot_size.map(exact),
+            sub_lot_remainder: snapshot.sub_lot_remainder,
+            trade_ids: snapshot.trade_ids,
+        }
+    }
+}
+
+/// `order` with every amount passed through `f`.
+fn map_amounts<N, M>(order: Order<N>, f: impl Fn(N) -> M) -> Order<M> {
+    let order_type = match order.order_type {
+        OrderType::Limit => OrderType::Limit,
+        OrderType::Market => OrderType::Market,
+        OrderType::StopLimit { stop_price } => OrderType::StopLimit {
+            stop_price: f(stop_price),
+        },
+        OrderType::Pegged { reference, offset } => OrderType::Pegged {
+            reference,
+            offset: f(offset),
+        },
+    };
+    Order {
+        id: order.id,
+        symbol: order.symbol,
+        account_id: order.account_id,
+        order_type,
+        side: order.side,
+        price: order.price.map(&f),
+        quantity: f(order.quantity),
+        time_in_force: order.time_in_force,
+        post_only: order.post_only,
+        reduce_only: order.reduce_only,
+        display_quantity: order.display_quantity.map(&f),
+        quote_quantity: order.quote_quantity.map(&f),
+        max_slippage_bps: order.max_slippage_bps.map(&f),
+        timestamp: order.timestamp,
+    }
+}
+
+/// The book as the server runs it, with `Decimal` amounts.
+pub type DefaultOrderBook = OrderBook<Decimal>;
+
+impl<N: Amount> Default for OrderBook<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<N: Amount> OrderBook<N> {
+    pub fn new() -> Self {
+        Self::with_policy(MatchingPolicy::default())
+    }
+
+    pub fn with_policy(matching_policy: MatchingPolicy) -> Self {
+        OrderBook {
+            matching_policy,
+            pricing_mode: PricingMode::default(),
+            level_tiebreaker: LevelTiebreaker::default(),
+            max_price_levels_per_side: usize::MAX,
+            min_trade_notional: N::ZERO,
+            max_fill_per_maker: None,
+            lot_size: None,
+            sub_lot_remainder: SubLotRemainder::default(),
+            trade_ids: TradeIds::default(),
+            bids: BTreeMap::new(),
+            asks: BTreeMap::new(),
+            stops: Vec::new(),
+            self_trade_prevention: SelfTradePrevention::default(),
+            fee_schedule: FeeSchedule::default(),
+            max_order_age: None,
+            stats: MarketStats::default(),
+            open_orders: HashMap::new(),
+            next_expiry: None,
+            dirty_orders: HashSet::new(),
+            dirty_levels: BTreeSet::new(),
+            pegged: HashSet::new(),
+            reported_top: TopOfBook {
+                bid: None,
+                ask: None,
+            },
+        }
+    }
+
+    /// Rebuilds a book from previously persisted resting orders. The
+    /// restored orders are not reported again by `take_changes`.
+    pub fn load_from_orders(orders: Vec<Order<N>>) -> Self {
+        let mut order_book = OrderBook::new();
+        for order in orders {
+            let id = order.id;
+            if let Err(e) = order_book.add_order(order) {
+                println!("Skipping persisted order {}: {}", id, e);
+            }
+        }
+        order_book.dirty_orders.clear();
+        order_book.dirty_levels.clear();
+        order_book
+    }
+
+    /// Every order the book holds: bids, asks, then parked stops.
+    pub fn orders(&self) -> impl Iterator<Item = &Order<N>> {
+        self.bids
+            .values()
+            .chain(self.asks.values())
+            .flatten()
+            .chain(&self.stops)
+    }
+
+    pub fn snapshot(&self) -> OrderBookSnapshot<N> {
+        OrderBookSnapshot {
+            orders: self.orders().cloned().collect(),
+            self_trade_prevention: self.self_trade_prevention,
+            matching_policy: self.matching_policy,
+            pricing_mode: self.pricing_mode,
+            level_tiebreaker: self.level_tiebreaker,
+            max_price_levels_per_side: self.max_price_levels_per_side,
+            min_trade_notional: self.min_trade_notional,
+            max_fill_per_maker: self.max_fill_per_maker,
+            fee_schedule: self.fee_schedule,
+            max_order_age: self.max_order_age,
+            lot_size: self.lot_size,
+            sub_lot_remainder: self.sub_lot_remainder,
+            trade_ids: self.trade_ids,
+        }
+    }
+
+    /// Rebuilds the book a `snapshot` was taken of. Like `load_from_orders`,
+    /// nothing is reported as changed afterwards.
+    pub fn restore(snapshot: OrderBookSnapshot<N>) -> Self {
+        let mut order_book = OrderBook::load_from_orders(snapshot.orders);
+        order_book.self_trade_prevention = snapshot.self_trade_prevention;
+        order_book.matching_policy = snapshot.matching_policy;
+        order_book.pricing_mode = snapshot.pricing_mode;
+        order_book.level_tiebreaker = snapshot.level_tiebreaker;
+        order_book.max_price_levels_per_side = snapshot.max_price_levels_per_side;
+        order_book.min_trade_notional = snapshot.min_trade_notional;
+        order_book.max_fill_per_maker = snapshot.max_fill_per_maker;
+        order_book.fee_schedule = snapshot.fee_schedule;
+        order_book.lot_size = snapshot.lot_size;
+        order_book.sub_lot_remainder = snapshot.sub_lot_remainder;
+        order_book.trade_ids = snapshot.trade_ids;
+        order_book.set_max_order_age(snapshot.max_order_age);
+        order_book
+    }
+
+    pub fn max_order_age(&self) -> Option<Duration> {
+        self.max_order_age
+    }
+
+    /// Lets orders rest at most `max_order_age`, or indefinitely with
+    /// `None`. Orders already on the book are held to it from now on.
+    pub fn set_max_order_age(&mut self, max_order_age: Option<Duration>) {
+        self.max_order_age = max_order_age;
+        self.next_expiry = self
+            .orders()
+            .filter_map(|order| expires_at(order, max_order_age))
+            .min();
+    }
+
+    /// Drains the resting-order changes accumulated since the last call, so
+    /// they can be persisted outside of the book lock.
+    pub fn take_changes(&mut self) -> Vec<OrderChange<N>> {
+        let dirty_orders = std::mem::take(&mut self.dirty_orders);
+        dirty_orders
+            .into_iter()
+            .map(|id| match self.find_order(id) {
+                Some(order) => OrderChange::Upsert(order.clone()),
+                None => OrderChange::Delete(id),
+            })
+            .collect()
+    }
+
+    /// Drains the price levels touched since the last call as deltas
+    /// carrying each level's current total.
+    pub fn take_deltas(&mut self, symbol: &str) -> Vec<BookDelta<N>> {
+        let dirty_levels = std::mem::take(&mut self.dirty_levels);
+        let checksum = self.checksum(CHECKSUM_LEVELS);
+        dirty_levels
+            .into_iter()
+            .map(|(side, price)| {
+                let book_side = match side {
+                    Side::Buy => &self.bids,
+                    Side::Sell => &self.asks,
+                };
+                BookDelta {
+                    symbol: symbol.to_string(),
+                    side,
+                    price,
+                    new_total_quantity: book_side
+                        .get(&price)
+                        .map(|orders| orders.iter().map(visible_quantity).sum())
+                        .unwrap_or(N::ZERO),
+                    checksum,
+                }
+            })
+            .collect()
+    }
+
+    /// Looks up a resting or parked stop order by id, reflecting any partial
+    /// fills so far.
+    pub fn find_order(&self, id: Uuid) -> Option<&Order<N>> {
+        self.orders().find(|o| o.id == id)
+    }
+
+    /// Rests a limit order on the book without matching it. Orders that
+    /// would cross the spread are rejected, since resting them would leave
+    /// the book crossed; send those through `match_order` instead. Stop
+    /// orders are parked off-book until triggered.
+    pub fn add_order(&mut self, order: Order<N>) -> Result<(), OrderBookError<N>> {
+        self.rest_order(order, false)
+    }
+
+    /// Rests a limit order for the next `run_auction`, even if it crosses
+    /// the spread. Until the auction uncrosses the book, only further
+    /// auction orders and cancels should reach it. Under
+    /// `CancelResting`, the account's own opposite orders it crosses are
+    /// cancelled, since the auction cannot keep them apart.
+    pub fn add_auction_order(&mut self, order: Order<N>) -> Result<(), OrderBookError<N>> {
+        let (side, price, account_id) = (order.side, order.price, order.account_id);
+        self.rest_order(order, true)?;
+        if self.self_trade_prevention == SelfTradePrevention::CancelResting
+            && let Some(price) = price
+        {
+            let own: Vec<Uuid> = self
+                .matchable_levels(side, Some(price))
+                .flat_map(|(_, orders_at_level)| orders_at_level)
+                .filter(|resting| resting.account_id == account_id)
+                .map(|resting| resting.id)
+                .collect();
+            for id in own {
+                println!("Self-trade prevented, cancelling resting order {}.", id);
+                self.cancel_order(id);
+            }
+        }
+        Ok(())
+    }
+
+    fn rest_order(&mut self, order: Order<N>, may_cross: bool) -> Result<(), OrderBookError<N>> {
+        let price = order.price.ok_or(OrderBookError::MissingPrice)?;
+        let id = order.id;
+        let order_side = order.side;
+        let account_id = order.account_id;
+        let expires_at = expires_at(&order, self.max_order_age);
+        let is_pegged = order.order_type.peg().is_some();
+
+        if let OrderType::StopLimit { .. } = order.order_type {
+            self.stops.push(order);
+            self.dirty_orders.insert(id);
+            *self.open_orders.entry(account_id).or_default() += 1;
+            self.note_expiry(expires_at);
+            return Ok(());
+        }
+
+        match order.side {
+            Side::Buy => {
+                if !may_cross
+                    && let Some(best_ask) = self.best_ask()
+                    && price >= best_ask
+                {
+                    return Err(OrderBookError::WouldCross {
+                        price,
+                        opposite: best_ask,
+                    });
+                }
+                self.make_room_for_level(Side::Buy, price)?;
+                insert_by_time(
+                    self.bids.entry(price).or_default(),
+                    order,
+                    self.level_tiebreaker,
+                );
+            }
+            Side::Sell => {
+                if !may_cross
+                    && let Some(best_bid) = self.best_bid()
+                    && price <= best_bid
+                {
+                    return Err(OrderBookError::WouldCross {
+                        price,
+                        opposite: best_bid,
+                    });
+                }
+                self.make_room_for_level(Side::Sell, price)?;
+                insert_by_time(
+                    self.asks.entry(price).or_default(),
+                    order,
+                    self.level_tiebreaker,
+                );
+            }
+        }
+        if is_pegged {
+            self.pegged.insert(id);
+        }
+        self.dirty_orders.insert(id);
+        self.dirty_levels.insert((order_side, price));
+        *self.open_orders.entry(account_id).or_default() += 1;
+        self.note_expiry(expires_at);
+        Ok(())
+    }
+
+    fn note_expiry(&mut self, expires_at: Option<DateTime<Utc>>) {
+        if let Some(expires_at) = expires_at {
+            self.next_expiry = Some(
+                self.next_expiry
+                    .map_or(expires_at, |next_expiry| next_expiry.min(expires_at)),
+            );
+        }
+    }
+
+    /// Whether an order resting at `price` on `side` fits under
+    /// `max_price_levels_per_side`, either at an existing level or by
+    /// displacing a worse one.
+    pub fn check_level_capacity(&self, side: Side, price: N) -> Result<(), OrderBookError<N>> {
+        let (book_side, worst) = match side {
+            Side::Buy => (&self.bids, self.bids.keys().next().copied()),
+            Side::Sell => (&self.asks, self.asks.keys().next_back().copied()),
+        };
+        if book_side.contains_key(&price) || book_side.len() < self.max_price_levels_per_side {
+            return Ok(());
+        }
+
+        let better = worst.is_some_and(|worst| match side {
+            Side::Buy => price > worst,
+            Side::Sell => price < worst,
+        });
+        if better {
+            Ok(())
+        } else {
+            Err(OrderBookError::BookFull { price, worst })
+        }
+    }
+
+    /// Evicts the worst level on `side` if resting at `price` needs a new
+    /// level and the side is full. The evicted orders are cancelled.
+    fn make_room_for_level(&mut self, side: Side, price: N) -> Result<(), OrderBookError<N>> {
+        self.check_level_capacity(side, price)?;
+        let book_side = match side {
+            Side::Buy => &mut self.bids,
+            Side::Sell => &mut self.asks,
+        };
+        if book_side.contains_key(&price) || book_side.len() < self.max_price_levels_per_side {
+            return Ok(());
+        }
+
+        let evicted = match side {
+            Side::Buy => book_side.pop_first(),
+            Side::Sell => book_side.pop_last(),
+        };
+        if let Some((worst, orders_at_level)) = evicted {
+            for order in orders_at_level {
+                println!(
+                    "Book full, cancelling order {} at worst level {}.",
+                    order.id, worst
+                );
+                self.dirty_orders.insert(order.id);
+                release_open_order(&mut self.open_orders, order.account_id);
+            }
+            self.dirty_levels.insert((side, worst));
+        }
+        Ok(())
+    }
+
+    pub fn best_bid(&self) -> Option<N> {
+        self.bids.keys().next_back().copied()
+    }
+
+    pub fn best_ask(&self) -> Option<N> {
+        self.asks.keys().next().copied()
+    }
+
+    /// Best ask minus best bid, or `None` unless both sides have orders.
+    pub fn spread(&self) -> Option<N> {
+        Some(self.best_ask()? - self.best_bid()?)
+    }
+
+    /// Halfway between the best bid and best ask, rounded to `PRICE_SCALE`.
+    pub fn mid_price(&self) -> Option<N> {
+        Some(((self.best_ask()? + self.best_bid()?) / N::from(2)).round_to_scale(PRICE_SCALE))
+    }
+
+    pub fn top_of_book(&self) -> TopOfBook<N> {
+        TopOfBook {
+            bid: self
+                .bids
+                .iter()
+                .next_back()
+                .map(|(&price, orders)| PriceLevel::aggregate(price, orders)),
+            ask: self
+                .asks
+                .iter()
+                .next()
+                .map(|(&price, orders)| PriceLevel::aggregate(price, orders)),
+        }
+    }
+
+    /// Compares the top of book with what the previous call saw, and keeps
+    /// the current one for the next. Edits below the best levels leave it
+    /// unchanged.
+    pub fn top_of_book_changed(&mut self) -> TopOfBookChange<N> {
+        let current = self.top_of_book();
+        let previous = std::mem::replace(&mut self.reported_top, current.clone());
+        TopOfBookChange {
+            changed: previous != current,
+            previous,
+            current,
+        }
+    }
+
+    /// Best price on `side` among orders that are not pegged, which is
+    /// what pegged orders follow.
+    fn best_unpegged(&self, side: Side) -> Option<N> {
+        let has_unpegged = |orders: &VecDeque<Order<N>>| {
+            orders.iter().any(|order| order.order_type.peg().is_none())
+        };
+        match side {
+            Side::Buy => self.bids.iter().rev().find(|(_, o)| has_unpegged(o)),
+            Side::Sell => self.asks.iter().find(|(_, o)| has_unpegged(o)),
+        }
+        .map(|(&price, _)| price)
+    }
+
+    /// Where an order pegged `offset` from `reference` rests right now, or
+    /// `None` while the book has no reference to peg to.
+    pub fn peg_price(&self, reference: PegReference, offset: N) -> Option<N> {
+        let reference_price = match reference {
+            PegReference::BestBid => self.best_unpegged(Side::Buy)?,
+            PegReference::BestAsk => self.best_unpegged(Side::Sell)?,
+            PegReference::Mid => {
+                ((self.best_unpegged(Side::Buy)? + self.best_unpegged(Side::Sell)?) / N::from(2))
+                    .round_to_scale(PRICE_SCALE)
+            }
+        };
+        Some(reference_price + offset)
+    }
+
+    /// Moves every pegged order whose reference has moved to its new price,
+    /// at the back of the queue there as of `at`. An order the move would
+    /// cross the spread with, or that finds no reference or no room for its
+    /// level, stays where it is until the next reprice.
+    pub fn reprice_pegged(&mut self, at: DateTime<Utc>) {
+        if self.pegged.is_empty() {
+            return;
+        }
+        let mut resting = HashSet::new();
+        let mut moves = Vec::new();
+        for (side, book_side) in [(Side::Buy, &self.bids), (Side::Sell, &self.asks)] {
+            for (&price, orders_at_level) in book_side {
+                for order in orders_at_level {
+                    let Some((reference, offset)) = order.order_type.peg() else {
+                        continue;
+                    };
+                    resting.insert(order.id);
+                    if let Some(target) = self.peg_price(reference, offset)
+                        && target != price
+                    {
+                        moves.push((side, price, order.id, target));
+                    }
+                }
+            }
+        }
+        self.pegged = resting;
+
+        for (side, price, id, target) in moves {
+            let crosses = match side {
+                Side::Buy => self.best_ask().is_some_and(|ask| target >= ask),
+                Side::Sell => self.best_bid().is_some_and(|bid| target <= bid),
+            };
+            let book_side = match side {
+                Side::Buy => &mut self.bids,
+                Side::Sell => &mut self.asks,
+            };
+            // A move may not add a level to a full side, unless it empties
+            // the peg's old one. Nothing is evicted to make room for it.
+            let leaves_level = book_side.get(&price).is_some_and(|level| level.len() == 1);
+            let full = !book_side.contains_key(&target)
+                && !leaves_level
+                && book_side.len() >= self.max_price_levels_per_side;
+            if crosses || full {
+                continue;
+            }
+            let Some(orders_at_level) = book_side.get_mut(&price) else {
+                continue;
+            };
+            let Some(mut order) = orders_at_level
+                .iter()
+                .position(|order| order.id == id)
+                .and_then(|index| orders_at_level.remove(index))
+            else {
+                continue;
+            };
+            if orders_at_level.is_empty() {
+                book_side.remove(&price);
+            }
+            order.price = Some(target);
+            order.timestamp = at;
+            insert_by_time(
+                book_side.entry(target).or_default(),
+                order,
+                self.level_tiebreaker,
+            );
+            self.dirty_orders.insert(id);
+            self.dirty_levels.insert((side, price));
+            self.dirty_levels.insert((side, target));
+        }
+    }
+
+    /// A book is crossed when the best bid is at or above the best ask.
+    pub fn is_crossed(&self) -> bool {
+        match (self.best_bid(), self.best_ask()) {
+            (Some(best_bid), Some(best_ask)) => best_bid >= best_ask,
+            _ => false,
+        }
+    }
+
+    /// Rough bytes the book takes up, for capacity planning: its own size,
+    /// each order held with its symbol, each price level and each account
+    /// counted in `open_orders`. Allocator overhead and spare capacity are
+    /// left out, so the real figure is somewhat higher.
+    pub fn approx_memory_bytes(&self) -> usize {
+        let orders: usize = self
+            .orders()
+            .map(|order| std::mem::size_of::<Order<N>>() + order.symbol.len())
+            .sum();
+        let level = std::mem::size_of::<N>() + std::mem::size_of::<VecDeque<Order<N>>>();
+        let levels = (self.bids.len() + self.asks.len()) * level;
+        let accounts = self.open_orders.len() * std::mem::size_of::<(Uuid, usize)>();
+        std::mem::size_of::<Self>() + orders + levels + accounts
+    }
+
+    /// Checks what every operation must leave true of the book and
+    /// describes the first thing found wrong: each level holds at least one
+    /// order, each resting order is on its own side at its own price with
+    /// quantity left, the book is not crossed, and the open order counts
+    /// match the orders held. For tests that drive the book at random.
+    pub fn validate_invariants(&self) -> Result<(), String> {
+        for (side, book_side) in [(Side::Buy, &self.bids), (Side::Sell, &self.asks)] {
+            for (&price, orders_at_level) in book_side {
+                if orders_at_level.is_empty() {
+                    return Err(format!("empty {:?} level at {}", side, price));
+                }
+                for order in orders_at_level {
+                    if order.price != Some(price) {
+                        return Err(format!(
+                            "order {} priced {:?} rests at {}",
+                            order.id, order.price, price
+                        ));
+                    }
+                    if order.side != side {
+                        return Err(format!(
+                            "{:?} order {} rests among the {:?} orders",
+                            order.side, order.id, side
+                        ));
+                    }
+                    if is_effectively_zero(order.quantity, N::EPSILON) {
+                        return Err(format!(
+                            "order {} rests with quantity {}",
+                            order.id, order.quantity
+                        ));
+                    }
+                }
+            }
+        }
+        if self.is_crossed() {
+            return Err(format!(
+                "book is crossed: best bid {:?}, best ask {:?}",
+                self.best_bid(),
+                self.best_ask()
+            ));
+        }
+
+        let mut open_orders: HashMap<Uuid, usize> = HashMap::new();
+        for order in self.orders() {
+            *open_orders.entry(order.account_id).or_default() += 1;
+        }
+        if open_orders != self.open_orders {
+            return Err(format!(
+                "open order counts {:?} do not match the orders held {:?}",
+                self.open_orders, open_orders
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn cancel_order(&mut self, id: Uuid) -> Option<Order<N>> {
+        for (side, book_side) in [(Side::Buy, &mut self.bids), (Side::Sell, &mut self.asks)] {
+            let mut removed = None;
+            for (&price, orders_at_level) in book_side.iter_mut() {
+                if let Some(index) = orders_at_level.iter().position(|o| o.id == id) {
+                    removed = orders_at_level.remove(index).map(|order| (price, order));
+                    break;
+                }
+            }
+
+            if let Some((price, order)) = removed {
+                if book_side.get(&price).is_some_and(VecDeque::is_empty) {
+                    book_side.remove(&price);
+                }
+                self.dirty_orders.insert(order.id);
+                self.dirty_levels.insert((side, price));
+                release_open_order(&mut self.open_orders, order.account_id);
+                return Some(order);
+            }
+        }
+
+        let index = self.stops.iter().position(|o| o.id == id)?;
+        let order = self.stops.remove(index);
+        self.dirty_orders.insert(id);
+        release_open_order(&mut self.open_orders, order.account_id);
+        Some(order)
+    }
+
+    /// Pulls every order `account_id` has on the book, resting or parked,
+    /// and returns them: bids best first, then asks best first, then stops.
+    pub fn cancel_all_for_account(&mut self, account_id: Uuid) -> Vec<Order<N>> {
+        self.remove_where(|order| order.account_id == account_id)
+    }
+
+    /// Pulls every order resting at `price` on `side` and the level with
+    /// them, returned in queue order. Empty if there is no such level.
+    pub fn cancel_level(&mut self, side: Side, price: N) -> Vec<Order<N>> {
+        let book_side = match side {
+            Side::Buy => &mut self.bids,
+            Side::Sell => &mut self.asks,
+        };
+        let Some(orders_at_level) = book_side.remove(&price) else {
+            return Vec::new();
+        };
+        self.dirty_levels.insert((side, price));
+        for order in &orders_at_level {
+            self.dirty_orders.insert(order.id);
+            release_open_order(&mut self.open_orders, order.account_id);
+        }
+        orders_at_level.into()
+    }
+
+    /// Whether `expire_orders(now)` may have anything to take off.
+    pub fn expiry_due(&self, now: DateTime<Utc>) -> bool {
+        self.next_expiry
+            .is_some_and(|next_expiry| next_expiry <= now)
+    }
+
+    /// Removes every order, resting or parked, whose good-till-date expiry
+    /// is at or before `now` or that has been held for `max_order_age`,
+    /// and returns them in the same order as `cancel_all_for_account`.
+    pub fn expire_orders(&mut self, now: DateTime<Utc>) -> Vec<Order<N>> {
+        if self.next_expiry.is_none_or(|next_expiry| next_expiry > now) {
+            return Vec::new();
+        }
+
+        let max_order_age = self.max_order_age;
+        let expired = self.remove_where(|order| {
+            expires_at(order, max_order_age).is_some_and(|expires_at| expires_at <= now)
+        });
+        self.next_expiry = self
+            .orders()
+            .filter_map(|order| expires_at(order, max_order_age))
+            .min();
+        for order in &expired {
+            println!("Order {} expired, removing it from the book.", order.id);
+        }
+        expired
+    }
+
+    /// Empties the book: every resting and parked order goes, along with
+    /// the rolling stats, while settings such as fees and policies stay.
+    /// Removed orders are reported by `take_changes` and their levels by
+    /// `take_deltas` like any other removal.
+    pub fn clear(&mut self) {
+        self.remove_where(|_| true);
+        self.next_expiry = None;
+        self.pegged.clear();
+        self.stats.clear();
+        debug_assert!(self.open_orders.is_empty(), "open orders left after clear");
+    }
+
+    /// Takes every order matching `remove` off the book, dropping emptied
+    /// levels: bids best first, then asks best first, then stops.
+    fn remove_where(&mut self, remove: impl Fn(&Order<N>) -> bool) -> Vec<Order<N>> {
+        let mut removed = Vec::new();
+        for (side, book_side) in [(Side::Buy, &mut self.bids), (Side::Sell, &mut self.asks)] {
+            let mut emptied = Vec::new();
+            let levels: Box<dyn Iterator<Item = (&N, &mut VecDeque<Order<N>>)>> = match side {
+                Side::Buy => Box::new(book_side.iter_mut().rev()),
+                Side::Sell => Box::new(book_side.iter_mut()),
+            };
+            for (&price, orders_at_level) in levels {
+                let before = removed.len();
+                let (gone, kept): (VecDeque<_>, VecDeque<_>) = std::mem::take(orders_at_level)
+                    .into_iter()
+                    .partition(|order| remove(order));
+                *orders_at_level = kept;
+                removed.extend(gone);
+                if removed.len() > before {
+                    self.dirty_levels.insert((side, price));
+                }
+                if orders_at_level.is_empty() {
+                    emptied.push(price);
+                }
+            }
+            for price in emptied {
+                book_side.remove(&price);
+            }
+        }
+
+        let (gone, kept): (Vec<_>, Vec<_>) = std::mem::take(&mut self.stops)
+            .into_iter()
+            .partition(|order| remove(order));
+        self.stops = kept;
+        removed.extend(gone);
+
+        for order in &removed {
+            self.dirty_orders.insert(order.id);
+            release_open_order(&mut self.open_orders, order.account_id);
+        }
+        removed
+    }
+
+    /// Orders `account_id` has resting or parked on this book.
+    pub fn open_order_count(&self, account_id: Uuid) -> usize {
+        self.open_orders.get(&account_id).copied().unwrap_or(0)
+    }
+
+    /// Changes the price and size of a resting order in place of a cancel
+    /// and re-submit. Shrinking an order at the same price keeps its place in
+    /// the queue; a new price or a larger size sends it to the back of the
+    /// queue at its new price. Returns `Ok(None)` when no such order rests,
+    /// and refuses a new price that would cross the spread.
+    pub fn amend_order(
+        &mut self,
+        id: Uuid,
+        new_price: N,
+        new_quantity: N,
+    ) -> Result<Option<Order<N>>, OrderBookError<N>> {
+        self.amend_order_at(id, new_price, new_quantity, Utc::now())
+    }
+
+    /// `amend_order` with the clock supplied by the caller, which stamps an
+    /// order that loses its place in the queue.
+    pub fn amend_order_at(
+        &mut self,
+        id: Uuid,
+        new_price: N,
+        new_quantity: N,
+        now: DateTime<Utc>,
+    ) -> Result<Option<Order<N>>, OrderBookError<N>> {
+        let Some(order) = self.find_order(id) else {
+            return Ok(None);
+        };
+        let side = order.side;
+        let old_price = order.price;
+        let is_stop = matches!(order.order_type, OrderType::StopLimit { .. });
+
+        if !is_stop && order.price == Some(new_price) && new_quantity <= order.quantity {
+            let book_side = match side {
+                Side::Buy => &mut self.bids,
+                Side::Sell => &mut self.asks,
+            };
+            let order = book_side
+                .get_mut(&new_price)
+                .and_then(|orders_at_level| orders_at_level.iter_mut().find(|o| o.id == id));
+            if let Some(order) = order {
+                order.quantity = new_quantity;
+                self.dirty_orders.insert(id);
+                self.dirty_levels.insert((side, new_price));
+                return Ok(Some(order.clone()));
+            }
+        }
+
+        if !is_stop {
+            let opposite = match side {
+                Side::Buy => self.best_ask().filter(|&ask| new_price >= ask),
+                Side::Sell => self.best_bid().filter(|&bid| new_price <= bid),
+            };
+            if let Some(opposite) = opposite {
+                return Err(OrderBookError::WouldCross {
+                    price: new_price,
+                    opposite,
+                });
+            }
+        }
+
+        // Where a resting order stands now, so a failed re-add can put it
+        // back. Parked stops never fail to re-add.
+        let position = old_price.filter(|_| !is_stop).and_then(|price| {
+            let book_side = match side {
+                Side::Buy => &self.bids,
+                Side::Sell => &self.asks,
+            };
+            let index = book_side.get(&price)?.iter().position(|o| o.id == id)?;
+            Some((price, index))
+        });
+        let Some(original) = self.cancel_order(id) else {
+            return Ok(None);
+        };
+        let mut order = original.clone();
+        order.price = Some(new_price);
+        order.quantity = new_quantity;
+        order.timestamp = now;
+        if let Err(e) = self.add_order(order.clone()) {
+            if let Some((price, index)) = position {
+                self.reinstate(original, price, index);
+            }
+            return Err(e);
+        }
+        Ok(Some(order))
+    }
+
+    /// Puts `order`, just cancelled, back at `index` in its level at
+    /// `price`, as if it had never left.
+    fn reinstate(&mut self, order: Order<N>, price: N, index: usize) {
+        let (id, side, account_id) = (order.id, order.side, order.account_id);
+        let expires_at = expires_at(&order, self.max_order_age);
+        if order.order_type.peg().is_some() {
+            self.pegged.insert(id);
+        }
+        let book_side = match side {
+            Side::Buy => &mut self.bids,
+            Side::Sell => &mut self.asks,
+        };
+        let orders_at_level = book_side.entry(price).or_default();
+        orders_at_level.insert(index.min(orders_at_level.len()), order);
+        self.dirty_orders.insert(id);
+        self.dirty_levels.insert((side, price));
+        *self.open_orders.entry(account_id).or_default() += 1;
+        self.note_expiry(expires_at);
+    }
+
+    /// Takes `by` off a resting order's quantity without moving it in the
+    /// queue. Reducing by its whole remaining quantity or more cancels it,
+    /// and the returned order then shows a quantity of zero.
+    pub fn reduce_order(&mut self, id: Uuid, by: N) -> Result<Order<N>, ReduceError> {
+        self.reduce_order_at(id, by, Utc::now())
+    }
+
+    /// `reduce_order` with the clock supplied by the caller.
+    pub fn reduce_order_at(
+        &mut self,
+        id: Uuid,
+        by: N,
+        now: DateTime<Utc>,
+    ) -> Result<Order<N>, ReduceError> {
+        if by <= N::ZERO {
+            return Err(ReduceError::NonPositiveAmount);
+        }
+        let order = self.find_order(id).ok_or(ReduceError::UnknownOrder(id))?;
+
+        if by >= order.quantity {
+            let mut cancelled = self.cancel_order(id).ok_or(ReduceError::UnknownOrder(id))?;
+            cancelled.quantity = N::ZERO;
+            return Ok(cancelled);
+        }
+
+        let (price, remaining) = (order.price, order.quantity - by);
+        let price = price.ok_or(ReduceError::UnknownOrder(id))?;
+        // Same price and a smaller size never crosses and keeps priority.
+        self.amend_order_at(id, price, remaining, now)
+            .ok()
+            .flatten()
+            .ok_or(ReduceError::UnknownOrder(id))
+    }
+
+    /// Removes every stop order that a trade at `last_trade_price` sets off
+    /// and returns them, oldest first, converted to plain limit orders ready
+    /// to be matched. Buy stops fire at or above their stop price, sell
+    /// stops at or below it.
+    pub fn trigger_stops(&mut self, last_trade_price: N) -> Vec<Order<N>> {
+        self.trigger_stops_at(last_trade_price, Utc::now())
+    }
+
+    fn trigger_stops_at(&mut self, last_trade_price: N, now: DateTime<Utc>) -> Vec<Order<N>> {
+        let (triggered, waiting): (Vec<Order<N>>, Vec<Order<N>>) = std::mem::take(&mut self.stops)
+            .into_iter()
+            .partition(|o| match (&o.order_type, o.side) {
+                (OrderType::StopLimit { stop_price }, Side::Buy) => last_trade_price >= *stop_price,
+                (OrderType::StopLimit { stop_price }, Side::Sell) => {
+                    last_trade_price <= *stop_price
+                }
+                _ => true,
+            });
+        self.stops = waiting;
+
+        triggered
+            .into_iter()
+            .map(|mut order| {
+                println!(
+                    "Stop order {} triggered at {}, activating as a limit order.",
+                    order.id, last_trade_price
+                );
+                self.dirty_orders.insert(order.id);
+                release_open_order(&mut self.open_orders, order.account_id);
+                order.order_type = OrderType::Limit;
+                // The order joins the live book now, behind everything that
+                // rested while it was parked.
+                order.timestamp = now;
+                order
+            })
+            .collect()
+    }
+
+    /// Aggregates the best `max_levels` price levels on each side, bids
+    /// highest first and asks lowest first.
+    pub fn depth(&self, max_levels: usize) -> DepthSnapshot<N> {
+        DepthSnapshot {
+            bids: self
+                .bids
+                .iter()
+                .rev()
+                .take(max_levels)
+                .map(|(&price, orders)| PriceLevel::aggregate(price, orders))
+                .collect(),
+            asks: self
+                .asks
+                .iter()
+                .take(max_levels)
+                .map(|(&price, orders)| PriceLevel::aggregate(price, orders))
+                .collect(),
+            checksum: self.checksum(CHECKSUM_LEVELS),
+        }
+    }
+
+    /// The best `max_levels` levels on each side with their visible
+    /// quantity, best first, and the total shown beyond them.
+    pub fn level2(&self, max_levels: usize) -> Level2<N> {
+        fn side<'a, N: Amount + 'a>(
+            levels: impl Iterator<Item = (&'a N, &'a VecDeque<Order<N>>)>,
+            max_levels: usize,
+        ) -> Level2Side<N> {
+            let mut side = Level2Side::default();
+            for (index, (&price, orders)) in levels.enumerate() {
+                let quantity: N = orders.iter().map(visible_quantity).sum();
+                if index < max_levels {
+                    side.levels.push([price, quantity]);
+                } else {
+                    side.rest += quantity;
+                }
+            }
+            side
+        }
+
+        Level2 {
+            bids: side(self.bids.iter().rev(), max_levels),
+            asks: side(self.asks.iter(), max_levels),
+        }
+    }
+
+    /// CRC32 of the top `levels` asks then the top `levels` bids, each
+    /// side best first, written as `price:visible quantity` and joined by
+    /// `|`. Lets a client check that the book it rebuilt from deltas
+    /// matches ours. Numbers are written as they display, so `100.0` and
+    /// `100` differ. An empty book sums to 0.
+    pub fn checksum(&self, levels: usize) -> u32 {
+        let level = |(price, orders): (&N, &VecDeque<Order<N>>)| {
+            let quantity: N = orders.iter().map(visible_quantity).sum();
+            format!("{}:{}", price, quantity)
+        };
+        let canonical: Vec<String> = self
+            .asks
+            .iter()
+            .take(levels)
+            .map(level)
+            .chain(self.bids.iter().rev().take(levels).map(level))
+            .collect();
+        CRC32.checksum(canonical.join("|").as_bytes())
+    }
+
+    /// Every level on `side`, best price first, listing its orders in
+    /// queue order rather than summing them.
+    pub fn raw_levels(&self, side: Side) -> Vec<RawLevel<N>> {
+        let list = |(&price, orders): (&N, &VecDeque<Order<N>>)| RawLevel::list(price, orders);
+        match side {
+            Side::Buy => self.bids.iter().rev().map(list).collect(),
+            Side::Sell => self.asks.iter().map(list).collect(),
+        }
+    }
+
+    /// Orders resting on `side` that a taker from the other side limited to
+    /// `price_limit` could reach, best price first. `None` means no limit.
+    fn resting_at_or_better(
+        &self,
+        side: Side,
+        price_limit: Option<N>,
+    ) -> impl Iterator<Item = &Order<N>> {
+        let taker_side = match side {
+            Side::Buy => Side::Sell,
+            Side::Sell => Side::Buy,
+        };
+        self.matchable_levels(taker_side, price_limit)
+            .flat_map(|(_, orders_at_level)| orders_at_level)
+    }
+
+    /// The levels a taker on `taker_side` may fill against without trading
+    /// through `taker_price`, in the order it reaches them: asks from the
+    /// lowest up for a buy, bids from the highest down for a sell. `None`
+    /// is no price limit, as for a market order.
+    pub fn matchable_levels(
+        &self,
+        taker_side: Side,
+        taker_price: Option<N>,
+    ) -> impl Iterator<Item = (N, &VecDeque<Order<N>>)> {
+        let levels: Box<dyn Iterator<Item = (&N, &VecDeque<Order<N>>)>> = match taker_side {
+            Side::Buy => Box::new(self.asks.iter()),
+            Side::Sell => Box::new(self.bids.iter().rev()),
+        };
+        levels
+            .map(|(&price, orders_at_level)| (price, orders_at_level))
+            .take_while(move |&(price, _)| !trades_through(taker_side, price, taker_price))
+    }
+
+    /// Total quantity resting on `side` that an opposite-side taker at
+    /// `price` could fill against: asks at or below `price` when `side` is
+    /// `Sell`, bids at or above it when `side` is `Buy`.
+    pub fn total_volume_at_or_better(&self, side: Side, price: N) -> N {
+        self.resting_at_or_better(side, Some(price))
+            .map(|o| o.quantity)
+            .sum()
+    }
+
+    /// Total resting quantity `taker` could trade against without crossing
+    /// `price_limit`. `None` means no limit. Orders that self-trade
+    /// prevention would cancel instead of fill are not counted.
+    fn available_liquidity(&self, taker: &Order<N>, price_limit: Option<N>) -> N {
+        let resting_side = match taker.side {
+            Side::Buy => Side::Sell,
+            Side::Sell => Side::Buy,
+        };
+
+        self.resting_at_or_better(resting_side, price_limit)
+            .filter(|o| {
+                self.self_trade_prevention == SelfTradePrevention::None
+                    || o.account_id != taker.account_id
+            })
+            .map(|o| o.quantity)
+            .sum()
+    }
+
+    /// Notional a market order for `quantity` on `side` would trade if it
+    /// swept the book right now. Quantity beyond the available liquidity
+    /// would be dropped, so it adds nothing.
+    pub fn estimate_market_notional(&self, side: Side, quantity: N) -> N {
+        self.quote_fill(side, quantity).total_cost
+    }
+
+    /// Walks the opposite side best first, hidden iceberg quantity
+    /// included, to price a market order for `quantity` on `side` without
+    /// touching the book. Self-trade prevention is not considered.
+    pub fn quote_fill(&self, side: Side, quantity: N) -> FillQuote<N> {
+        let mut remaining = quantity;
+        let mut total_cost = N::ZERO;
+        for (price, orders_at_level) in self.matchable_levels(side, None) {
+            if remaining <= N::ZERO {
+                break;
+            }
+            let level_quantity: N = orders_at_level.iter().map(|o| o.quantity).sum();
+            let fill = remaining.min(level_quantity);
+            total_cost += price * fill;
+            remaining -= fill;
+        }
+
+        let filled_quantity = quantity - remaining.max(N::ZERO);
+        FillQuote {
+            filled_quantity,
+            average_price: (filled_quantity > N::ZERO)
+                .then(|| (total_cost / filled_quantity).round_to_scale(PRICE_SCALE)),
+            total_cost,
+            fully_fillable: remaining <= N::ZERO,
+        }
+    }
+
+    pub fn detect_arbitrage(&self, new_order: &Order<N>) -> Option<ArbitrageEvent<N>> {
+        // Stop orders do not interact with the book until they trigger.
+        if let OrderType::StopLimit { .. } = new_order.order_type {
+            return None;
+        }
+        let best_opposite_price = match new_order.side {
+            Side::Buy => self.best_ask()?,
+            Side::Sell => self.best_bid()?,
+        };
+        // A market order crosses whatever rests on the other side.
+        let spread = match (new_order.side, new_order.price) {
+            (_, None) => None,
+            (Side::Buy, Some(price)) if price > best_opposite_price => {
+                Some(price - best_opposite_price)
+            }
+            (Side::Sell, Some(price)) if price < best_opposite_price => {
+                Some(best_opposite_price - price)
+            }
+            (_, Some(_)) => return None,
+        };
+        let available_quantity = self
+            .available_liquidity(new_order, new_order.price)
+            .min(new_order.quantity);
+
+        Some(ArbitrageEvent {
+            symbol: new_order.symbol.clone(),
+            order_id: new_order.id,
+            side: new_order.side,
+            order_price: new_order.price,
+            best_opposite_price,
+            spread,
+            available_quantity,
+            timestamp: new_order.timestamp,
+        })
+    }
+
+    /// The worst price a market order with a slippage cap may fill at:
+    /// `max_slippage_bps` basis points through the best opposite price as
+    /// the order arrives. `None` for orders without a cap, or with nothing
+    /// to fill against.
+    fn slippage_limit(&self, taker_order: &Order<N>) -> Option<N> {
+        let max_slippage_bps = taker_order.max_slippage_bps?;
+        let best = match taker_order.side {
+            Side::Buy => self.best_ask()?,
+            Side::Sell => self.best_bid()?,
+        };
+        // Measured from the magnitude so negative prices slip the same way.
+        let magnitude = if best < N::ZERO { N::ZERO - best } else { best };
+        let slippage = magnitude * max_slippage_bps / N::from(BASIS_POINTS_PER_UNIT);
+        Some(match taker_order.side {
+            Side::Buy => best + slippage,
+            Side::Sell => best - slippage,
+        })
+    }
+
+    pub fn match_order(&mut self, taker_order: Order<N>) -> OrderResult<N> {
+        self.match_order_at(taker_order, Utc::now())
+    }
+
+    /// `match_order` with the clock supplied by the caller. Every fill of
+    /// this taker, and of any stop it triggers, is stamped `match_time`, so
+    /// its trades can never appear out of order.
+    pub fn match_order_at(
+        &mut self,
+        mut taker_order: Order<N>,
+        match_time: DateTime<Utc>,
+    ) -> OrderResult<N> {
+        let mut trades = Vec::new();
+        let taker_id = taker_order.id;
+        let original_quantity = taker_order.quantity;
+        let mut rested = false;
+
+        // Expired orders must not trade, so sweep them before matching.
+        self.expire_orders(match_time);
+        if taker_order
+            .time_in_force
+            .expires_at()
+            .is_some_and(|expires_at| expires_at <= match_time)
+        {
+            println!("Order {} expired before it could match.", taker_id);
+            return OrderResult::rejected(&taker_order);
+        }
+
+        if let OrderType::StopLimit { .. } = taker_order.order_type {
+            return match self.add_order(taker_order.clone()) {
+                Ok(()) => OrderResult::new(taker_id, original_quantity, trades, true),
+                Err(e) => {
+                    println!("Failed to park stop order {}: {}", taker_id, e);
+                    OrderResult::rejected(&taker_order)
+                }
+            };
+        }
+
+        if let Some((reference, offset)) = taker_order.order_type.peg() {
+            match self.peg_price(reference, offset) {
+                Some(price) => taker_order.price = Some(price),
+                None => {
+                    println!(
+                        "Pegged order {} has no {} to peg to, rejecting it.",
+                        taker_id,
+                        reference.as_str()
+                    );
+                    return OrderResult::rejected(&taker_order);
+                }
+            }
+        }
+
+        // Market orders sweep the book with no price limit of their own.
+        let quoted_limit = match taker_order.order_type {
+            OrderType::Market => None,
+            OrderType::Limit | OrderType::StopLimit { .. } | OrderType::Pegged { .. } => {
+                match taker_order.price {
+                    Some(price) => Some(price),
+                    None => {
+                        println!("Limit order {} has no price, ignoring.", taker_order.id);
+                        return OrderResult::rejected(&taker_order);
+                    }
+                }
+            }
+        };
+        let price_limit = quoted_limit.or_else(|| self.slippage_limit(&taker_order));
+
+        if taker_order.time_in_force == TimeInForce::Fok
+            && self.available_liquidity(&taker_order, price_limit) < taker_order.quantity
+        {
+            println!(
+                "Fill-or-kill order {} cannot be fully filled, killing it.",
+                taker_order.id
+            );
+            return OrderResult::rejected(&taker_order);
+        }
+
+        if taker_order.post_only {
+            let would_take = match taker_order.side {
+                Side::Buy => self
+                    .best_ask()
+                    .is_some_and(|ask| price_limit.is_none_or(|limit| limit >= ask)),
+                Side::Sell => self
+                    .best_bid()
+                    .is_some_and(|bid| price_limit.is_none_or(|limit| limit <= bid)),
+            };
+            if would_take {
+                println!(
+                    "Post-only order {} would take liquidity, rejecting it.",
+                    taker_order.id
+                );
+                return OrderResult::rejected(&taker_order);
+            }
+        }
+
+        let maker_side = match taker_order.side {
+            Side::Buy => Side::Sell,
+            Side::Sell => Side::Buy,
+        };
+        let book_side = match maker_side {
+            Side::Buy => &mut self.bids,
+            Side::Sell => &mut self.asks,
+        };
+        let mut filled_levels = Vec::new();
+        // Only market buys carry a quote budget.
+        let mut quote_left = taker_order
+            .quote_quantity
+            .filter(|_| taker_order.side == Side::Buy);
+
+        'levels: for (level_price, orders_at_level) in
+            matchable_levels_mut(book_side, taker_order.side, price_limit)
+        {
+            if is_effectively_zero(taker_order.quantity, N::EPSILON) {
+                break;
+            }
+            // A quote budget caps how much this level may fill, in the
+            // precision the order's quantity is written in. Asks only get
+            // dearer, so once the cap reaches zero no later level is
+            // affordable either.
+            if let Some(budget) = quote_left {
+                let affordable = (budget / level_price).round_down_like(original_quantity);
+                if affordable == N::ZERO {
+                    break;
+                }
+                taker_order.quantity = taker_order.quantity.min(affordable);
+            }
+            let level_start = trades.len();
+            let dust = remove_dust(orders_at_level, level_price, self.min_trade_notional);
+            if !dust.is_empty() {
+                self.dirty_levels.insert((maker_side, level_price));
+            }
+            for order in dust {
+                println!(
+                    "Resting order {} is worth less than the minimum trade notional, cancelling it.",
+                    order.id
+                );
+                self.dirty_orders.insert(order.id);
+                release_open_order(&mut self.open_orders, order.account_id);
+            }
+
+            if self.matching_policy == MatchingPolicy::ProRata {
+                let mut removed = Vec::new();
+                let level_trades = fill_pro_rata(
+                    orders_at_level,
+                    self.pricing_mode.trade_price(level_price, quoted_limit),
+                    &mut taker_order,
+                    self.self_trade_prevention,
+                    self.fee_schedule,
+                    &mut removed,
+                    match_time,
+                );
+                if !removed.is_empty() {
+                    self.dirty_levels.insert((maker_side, level_price));
+                }
+                for order in removed {
+                    self.dirty_orders.insert(order.id);
+                    release_open_order(&mut self.open_orders, order.account_id);
+                }
+                if let Some(level_trades) = level_trades {
+                    self.dirty_orders
+                        .extend(level_trades.iter().map(|trade| trade.maker_order_id));
+                    trades.extend(level_trades);
+                    self.dirty_levels.insert((maker_side, level_price));
+                    break;
+                }
+            }
+
+            // Makers are consumed from the front of the queue. Those the
+            // taker has taken its fill of stay where they are and the
+            // taker moves past them, so `index` is the next maker to try.
+            let mut index = 0;
+            let mut filled_by_maker: HashMap<Uuid, N> = HashMap::new();
+            while !is_effectively_zero(taker_order.quantity, N::EPSILON) {
+                let Some(maker_order) = orders_at_level.get_mut(index) else {
+                    break;
+                };
+
+                // Only priced limit orders can rest, so a priceless maker means
+                // the book was corrupted. Evict it rather than trade at a guess.
+                let Some(maker_price) = maker_order.price else {
+                    eprintln!(
+                        "Resting order {} has no price, dropping it from the book.",
+                        maker_order.id
+                    );
+                    self.dirty_orders.insert(maker_order.id);
+                    self.dirty_levels.insert((maker_side, level_price));
+                    if let Some(removed) = orders_at_level.remove(index) {
+                        release_open_order(&mut self.open_orders, removed.account_id);
+                    }
+                    continue;
+                };
+                if trades_through(taker_order.side, maker_price, price_limit) {
+                    debug_assert!(
+                        false,
+                        "trade-through: order {} would fill at {} past its limit {:?}",
+                        taker_order.id, maker_price, price_limit
+                    );
+                    eprintln!(
+                        "Resting order {} at {} would trade through the limit of order {}, stopping matching.",
+                        maker_order.id, maker_price, taker_order.id
+                    );
+                    break 'levels;
+                }
+
+                if self.self_trade_prevention == SelfTradePrevention::CancelResting
+                    && maker_order.account_id == taker_order.account_id
+                {
+                    println!(
+                        "Self-trade prevented, cancelling resting order {}.",
+                        maker_order.id
+                    );
+                    self.dirty_orders.insert(maker_order.id);
+                    self.dirty_levels.insert((maker_side, level_price));
+                    if let Some(removed) = orders_at_level.remove(index) {
+                        release_open_order(&mut self.open_orders, removed.account_id);
+                    }
+                    continue;
+                }
+
+                let shown_quantity = visible_quantity(maker_order);
+                let mut trade_quantity = taker_order.quantity.min(shown_quantity);
+                if let Some(cap) = self.max_fill_per_maker {
+                    let filled = filled_by_maker.entry(maker_order.id).or_default();
+                    if *filled >= cap {
+                        index += 1;
+                        continue;
+                    }
+                    trade_quantity = trade_quantity.min(cap - *filled);
+                    *filled += trade_quantity;
+                }
+
+                let trade_price = self.pricing_mode.trade_price(maker_price, quoted_limit);
+                trades.push(Trade {
+                    id: Uuid::nil(),
+                    symbol: taker_order.symbol.clone(),
+                    maker_order_id: maker_order.id,
+                    taker_order_id: taker_order.id,
+                    maker_account_id: maker_order.account_id,
+                    taker_account_id: taker_order.account_id,
+                    taker_side: taker_order.side,
+                    price: trade_price,
+                    quantity: trade_quantity,
+                    price_improvement: price_improvement(&taker_order, trade_price),
+                    maker_fee: self.fee_schedule.maker_fee(trade_price, trade_quantity),
+                    taker_fee: self.fee_schedule.taker_fee(trade_price, trade_quantity),
+                    timestamp: match_time,
+                    seq: 0,
+                });
+
+                maker_order.quantity -= trade_quantity;
+                taker_order.quantity -= trade_quantity;
+                self.dirty_orders.insert(maker_order.id);
+                self.dirty_levels.insert((maker_side, level_price));
+
+                if is_effectively_zero(maker_order.quantity, N::EPSILON) {
+                    if let Some(removed) = orders_at_level.remove(index) {
+                        release_open_order(&mut self.open_orders, removed.account_id);
+                    }
+                } else if trade_quantity == shown_quantity {
+                    // An iceberg's shown slice filled: replenish it
+                    // from the reserve at the back of the queue.
+                    if let Some(mut replenished) = orders_at_level.remove(index) {
+                        replenished.timestamp = match_time;
+                        orders_at_level.push_back(replenished);
+                    }
+                } else {
+                    index += 1;
+                }
+            }
+
+            if let Some(budget) = quote_left.as_mut() {
+                *budget -= trades[level_start..]
+                    .iter()
+                    .map(|trade| trade.price * trade.quantity)
+                    .sum();
+            }
+
+            if orders_at_level.is_empty() {
+                filled_levels.push(level_price);
+            }
+        }
+
+        for price in filled_levels {
+            book_side.remove(&price);
+        }
+
+        if !is_effectively_zero(taker_order.quantity, N::EPSILON) {
+            match (&taker_order.order_type, taker_order.time_in_force) {
+                _ if taker_order.reduce_only => println!(
+                    "Reduce-only order {} would rest, cancelling unfilled quantity {}.",
+                    taker_order.id, taker_order.quantity
+                ),
+                (OrderType::Market, _) => println!(
+                    "Market order {} ran out of liquidity, dropping unfilled quantity {}.",
+                    taker_order.id, taker_order.quantity
+                ),
+                _ if self.sub_lot_remainder == SubLotRemainder::Cancel
+                    && self.lot_size.is_some_and(|lot| taker_order.quantity < lot) =>
+                {
+                    println!(
+                        "Order {} left {} below the lot size, cancelling it.",
+                        taker_order.id, taker_order.quantity
+                    )
+                }
+                (_, TimeInForce::Gtc | TimeInForce::Gtd { .. }) => {
+                    let id = taker_order.id;
+                    match self.add_order(taker_order) {
+                        Ok(()) => rested = true,
+                        Err(e) => println!("Failed to rest remainder of order {}: {}", id, e),
+                    }
+                }
+                (_, TimeInForce::Ioc | TimeInForce::Fok) => println!(
+                    "{:?} order {} not fully filled, cancelling unfilled quantity {}.",
+                    taker_order.time_in_force, taker_order.id, taker_order.quantity
+                ),
+            }
+        }
+
+        debug_assert!(!self.is_crossed(), "order book crossed after matching");
+
+        for trade in &mut trades {
+            trade.id = self.trade_ids.next_id();
+            self.stats.record(trade);
+        }
+        self.stats.expire(match_time);
+
+        let last_trade_price = trades.last().map(|t| t.price);
+        let mut result = OrderResult::new(taker_id, original_quantity, trades, rested);
+
+        // Activated stops can trade and trigger further stops in turn; each
+        // level of the cascade is reported under the order that caused it.
+        if let Some(last_trade_price) = last_trade_price {
+            result.triggered = self
+                .trigger_stops_at(last_trade_price, match_time)
+                .into_iter()
+                .map(|order| self.match_order_at(order, match_time))
+                .collect();
+        }
+
+        result
+    }
+
+    pub fn run_auction(&mut self) -> Option<(N, Vec<Trade<N>>)> {
+        self.run_auction_at(Utc::now())
+    }
+
+    /// Uncrosses the book in one call auction: finds the clearing price at
+    /// which the most quantity trades, then fills every crossing order at
+    /// that one price, best prices first and in time order within a price.
+    /// Ties go to the price leaving the least unmatched quantity, then to
+    /// the lowest. Of each pair, the order that arrived later is reported
+    /// as the taker. Returns `None` when nothing crosses.
+    ///
+    /// Icebergs take part with their full quantity. Self-trade prevention
+    /// is applied as orders are added, not here. Stops the clearing price
+    /// sets off then match as after a continuous trade, and their trades
+    /// follow the auction's.
+    ///
+    /// The matching policy, pricing mode, per-maker fill cap and dust
+    /// notional do not apply; every fill is FIFO at the clearing price.
+    pub fn run_auction_at(&mut self, match_time: DateTime<Utc>) -> Option<(N, Vec<Trade<N>>)> {
+        let clearing_price = self.clearing_price()?;
+        let mut trades = Vec::new();
+
+        while let Some((&bid_price, bids_at_level)) = self.bids.iter_mut().next_back() {
+            let Some((&ask_price, asks_at_level)) = self.asks.iter_mut().next() else {
+                break;
+            };
+            if bid_price < clearing_price || ask_price > clearing_price {
+                break;
+            }
+            let (Some(bid), Some(ask)) = (bids_at_level.front_mut(), asks_at_level.front_mut())
+            else {
+                break;
+            };
+
+            let quantity = bid.quantity.min(ask.quantity);
+            let (maker, taker) = if ask.timestamp <= bid.timestamp {
+                (&*ask, &*bid)
+            } else {
+                (&*bid, &*ask)
+            };
+            trades.push(Trade {
+                id: Uuid::nil(),
+                symbol: taker.symbol.clone(),
+                maker_order_id: maker.id,
+                taker_order_id: taker.id,
+                maker_account_id: maker.account_id,
+                taker_account_id: taker.account_id,
+                taker_side: taker.side,
+                price: clearing_price,
+                quantity,
+                price_improvement: price_improvement(taker, clearing_price),
+                maker_fee: self.fee_schedule.maker_fee(clearing_price, quantity),
+                taker_fee: self.fee_schedule.taker_fee(clearing_price, quantity),
+                timestamp: match_time,
+                seq: 0,
+            });
+            bid.quantity -= quantity;
+            ask.quantity -= quantity;
+
+            for (side, price, orders_at_level) in [
+                (Side::Buy, bid_price, bids_at_level),
+                (Side::Sell, ask_price, asks_at_level),
+            ] {
+                if let Some(order) = orders_at_level.front() {
+                    self.dirty_orders.insert(order.id);
+                }
+                self.dirty_levels.insert((side, price));
+                if orders_at_level
+                    .front()
+                    .is_some_and(|order| is_effectively_zero(order.quantity, N::EPSILON))
+                    && let Some(filled) = orders_at_level.pop_front()
+                {
+                    release_open_order(&mut self.open_orders, filled.account_id);
+                }
+            }
+            if self.bids.get(&bid_price).is_some_and(VecDeque::is_empty) {
+                self.bids.remove(&bid_price);
+            }
+            if self.asks.get(&ask_price).is_some_and(VecDeque::is_empty) {
+                self.asks.remove(&ask_price);
+            }
+        }
+
+        debug_assert!(!self.is_crossed(), "order book crossed after the auction");
+
+        for trade in &mut trades {
+            trade.id = self.trade_ids.next_id();
+            self.stats.record(trade);
+        }
+        self.stats.expire(match_time);
+        println!(
+            "Auction cleared {} trades at {}.",
+            trades.len(),
+            clearing_price
+        );
+
+        for order in self.trigger_stops_at(clearing_price, match_time) {
+            let result = self.match_order_at(order, match_time);
+            trades.extend(result.all_trades().into_iter().cloned());
+        }
+        Some((clearing_price, trades))
+    }
+
+    /// The price `run_auction` clears at, or `None` when nothing crosses.
+    fn clearing_price(&self) -> Option<N> {
+        if !self.is_crossed() {
+            return None;
+        }
+        let candidates: BTreeSet<N> = self.bids.keys().chain(self.asks.keys()).copied().collect();
+
+        let mut best: Option<(N, N, N)> = None;
+        for price in candidates {
+            let demand = self.total_volume_at_or_better(Side::Buy, price);
+            let supply = self.total_volume_at_or_better(Side::Sell, price);
+            let volume = demand.min(supply);
+            let imbalance = demand.max(supply) - volume;
+            // Candidates run from lowest to highest, so strict comparisons
+            // keep the lowest of equally good prices.
+            let better = best.is_none_or(|(_, best_volume, best_imbalance)| {
+                volume > best_volume || (volume == best_volume && imbalance < best_imbalance)
+            });
+            if volume > N::ZERO && better {
+                best = Some((price, volume, imbalance));
+            }
+        }
+        best.map(|(price, _, _)| price)
+    }
+}
+
+impl OrderBook<Decimal> {
+    /// `(bid_volume - ask_volume) / (bid_volume + ask_volume)` over the top
+    /// `levels` of each side, from -1 (all asks) to 1 (all bids). Only the
+    /// visible part of icebergs counts. `None` when both sides are empty.
+    ///
+    /// Only defined for `Decimal`, since the ratio is signed and fractional.
+    pub fn imbalance(&self, levels: usize) -> Option<Decimal> {
+        let depth = self.depth(levels);
+        let volume = |side: &[PriceLevel]| side.iter().map(|level| level.total_quantity).sum();
+        let bid_volume: Decimal = volume(&depth.bids);
+        let ask_volume: Decimal = volume(&depth.asks);
+
+        let total = bid_volume + ask_volume;
+        if total.is_zero() {
+            return None;
+        }
+        Some((bid_volume - ask_volume) / total)
+    }
+}
+
+/// Splits `taker_quantity`, which must be less than the sum of
+/// `maker_quantities`, across makers in proportion to their quantities.
+///
+/// Each share is rounded down to the precision of `taker_quantity`, so no
+/// maker trades finer than the taker asked for. Whatever rounding leaves
+/// over goes to makers in queue order, each taking as much as it has room
+/// for, which keeps a time-priority edge for the oldest makers.
+fn allocate_pro_rata<N: Amount>(taker_quantity: N, maker_quantities: &[N]) -> Vec<N> {
+    let total: N = maker_quantities.iter().copied().sum();
+    let mut allocations: Vec<N> = maker_quantities
+        .iter()
+        .map(|&quantity| (taker_quantity * quantity / total).round_down_like(taker_quantity))
+        .collect();
+
+    let mut leftover = taker_quantity - allocations.iter().copied().sum();
+    for (allocation, &quantity) in allocations.iter_mut().zip(maker_quantities) {
+        if leftover == N::ZERO {
+            break;
+        }
+        let extra = leftover.min(quantity - *allocation);
+        *allocation += extra;
+        leftover -= extra;
+    }
+    allocations
+}
+
+/// Fills `taker` pro-rata against one price level when it cannot clear the
+/// level, returning the trades. Returns `None`, leaving the level for the
+/// FIFO loop, when the taker is large enough to take every maker anyway.
+/// Makers the taker must not trade with are evicted first, as FIFO would.
+/// Every maker that leaves the level, evicted or filled, ends up in
+/// `removed`, even when `None` comes back.
+fn fill_pro_rata<N: Amount>(
+    orders_at_level: &mut VecDeque<Order<N>>,
+    price: N,
+    taker: &mut Order<N>,
+    self_trade_prevention: SelfTradePrevention,
+    fee_schedule: FeeSchedule<N>,
+    removed: &mut Vec<Order<N>>,
+    match_time: DateTime<Utc>,
+) -> Option<Vec<Trade<N>>> {
+    let makers = std::mem::take(orders_at_level);
+    for maker in makers {
+        let self_trade = self_trade_prevention == SelfTradePrevention::CancelResting
+            && maker.account_id == taker.account_id;
+        if maker.price.is_none() || self_trade {
+            println!(
+                "Resting order {} cannot trade with {}, dropping it from the book.",
+                maker.id, taker.id
+            );
+            removed.push(maker);
+        } else {
+            orders_at_level.push_back(maker);
+        }
+    }
+
+    let shown: Vec<N> = orders_at_level.iter().map(visible_quantity).collect();
+    if taker.quantity >= shown.iter().copied().sum() {
+        return None;
+    }
+
+    let allocations = allocate_pro_rata(taker.quantity, &shown);
+    let mut trades = Vec::new();
+    let mut replenished = Vec::new();
+    let makers = std::mem::take(orders_at_level);
+    for ((mut maker, allocation), shown_quantity) in makers.into_iter().zip(allocations).zip(shown)
+    {
+        if allocation > N::ZERO {
+            trades.push(Trade {
+                id: Uuid::nil(),
+                symbol: taker.symbol.clone(),
+                maker_order_id: maker.id,
+                taker_order_id: taker.id,
+                maker_account_id: maker.account_id,
+                taker_account_id: taker.account_id,
+                taker_side: taker.side,
+                price,
+                quantity: allocation,
+                price_improvement: price_improvement(taker, price),
+                maker_fee: fee_schedule.maker_fee(price, allocation),
+                taker_fee: fee_schedule.taker_fee(price, allocation),
+                timestamp: match_time,
+                seq: 0,
+            });
+            maker.quantity -= allocation;
+            taker.quantity -= allocation;
+        }
+
+        if is_effectively_zero(maker.quantity, N::EPSILON) {
+            removed.push(maker);
+            continue;
+        }
+        if allocation == shown_quantity {
+            // An iceberg's shown slice filled, so it rejoins at the back.
+            maker.timestamp = match_time;
+            replenished.push(maker);
+        } else {
+            orders_at_level.push_back(maker);
+        }
+    }
+    orders_at_level.extend(replenished);
+    Some(trades)
+}
+
+/// Takes the makers at `price` worth less than `min_notional` out of the
+/// level, keeping the rest in queue order.
+fn remove_dust<N: Amount>(
+    orders_at_level: &mut VecDeque<Order<N>>,
+    price: N,
+    min_notional: N,
+) -> Vec<Order<N>> {
+    if min_notional == N::ZERO {
+        return Vec::new();
+    }
+    let mut dust = Vec::new();
+    for order in std::mem::take(orders_at_level) {
+        let notional = price * order.quantity;
+        // Negative prices are worth their magnitude.
+        let notional = if notional < N::ZERO {
+            N::ZERO - notional
+        } else {
+            notional
+        };
+        if notional < min_notional {
+            dust.push(order);
+        } else {
+            orders_at_level.push_back(order);
+        }
+    }
+    dust
+}
+
+/// Takes one order off `account_id`'s open order count.
+fn release_open_order(open_orders: &mut HashMap<Uuid, usize>, account_id: Uuid) {
+    if let Some(count) = open_orders.get_mut(&account_id) {
+        *count -= 1;
+        if *count == 0 {
+            open_orders.remove(&account_id);
+        }
+    }
+}
+
+/// Whether a fill at `price` would be worse than a `side` taker's
+/// `price_limit` allows. Each level is checked before it is matched, so
+/// this only holds for a maker resting at a price other than its level's.
+fn trades_through<N: Amount>(side: Side, price: N, price_limit: Option<N>) -> bool {
+    price_limit.is_some_and(|limit| match side {
+        Side::Buy => price > limit,
+        Side::Sell => price < limit,
+    })
+}
+
+/// `OrderBook::matchable_levels` over `book_side`, the side opposite the
+/// taker, for matching to fill from.
+fn matchable_levels_mut<N: Amount>(
+    book_side: &mut BTreeMap<N, VecDeque<Order<N>>>,
+    taker_side: Side,
+    taker_price: Option<N>,
+) -> impl Iterator<Item = (N, &mut VecDeque<Order<N>>)> {
+    let levels: Box<dyn Iterator<Item = (&N, &mut VecDeque<Order<N>>)>> = match taker_side {
+        Side::Buy => Box::new(book_side.iter_mut()),
+        Side::Sell => Box::new(book_side.iter_mut().rev()),
+    };
+    levels
+        .map(|(&price, orders_at_level)| (price, orders_at_level))
+        .take_while(move |&(price, _)| !trades_through(taker_side, price, taker_price))
+}
+
+/// The part of `order` other traders can see and match against. An iceberg
+/// shows whatever is left of its current slice; slices are cut from the
+/// total so that the odd remainder shows first and every later slice is
+/// full, which needs no state beyond the remaining quantity.
+fn visible_quantity<N: Amount>(order: &Order<N>) -> N {
+    match order.display_quantity {
+        Some(display_quantity) if display_quantity < order.quantity => {
+            let partial_slice = order.quantity % display_quantity;
+            if partial_slice == N::ZERO {
+                display_quantity
+            } else {
+                partial_slice
+            }
+        }
+        _ => order.quantity,
+    }
+}
+
+/// How much better than its limit `taker` trades at `price`: below it for
+/// a buy, above it for a sell.
+fn price_improvement<N: Amount>(taker: &Order<N>, price: N) -> N {
+    match (&taker.order_type, taker.price) {
+        (OrderType::Market, _) | (_, None) => N::ZERO,
+        (_, Some(limit)) => match taker.side {
+            Side::Buy => limit - price,
+            Side::Sell => price - limit,
+        },
+    }
+}
+
+/// When `order` leaves the book unless it trades first: its good-till-date
+/// expiry or `max_order_age` after it was placed, whichever comes sooner.
+fn expires_at<N: Amount>(
+    order: &Order<N>,
+    max_order_age: Option<Duration>,
+) -> Option<DateTime<Utc>> {
+    let aged_out = max_order_age
+        .and_then(|age| chrono::Duration::from_std(age).ok())
+        .and_then(|age| order.timestamp.checked_add_signed(age));
+    order
+        .time_in_force
+        .expires_at()
+        .into_iter()
+        .chain(aged_out)
+        .min()
+}
+
+/// Keeps a price level sorted by timestamp so older orders match first. Orders
+/// with equal timestamps are ordered by `tiebreaker`.
+fn insert_by_time<N: Amount>(
+    orders_at_level: &mut VecDeque<Order<N>>,
+    order: Order<N>,
+    tiebreaker: LevelTiebreaker,
+) {
+    let index =
+        orders_at_level.partition_point(|resting| match resting.timestamp.cmp(&order.timestamp) {
+            Ordering::Less => true,
+            Ordering::Equal => tiebreaker.keeps_ahead(resting, &order),
+            Ordering::Greater => false,
+        });
+    orders_at_level.insert(index, order);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Order, OrderType};
+    use rust_decimal_macros::dec;
+
+    fn create_test_order(side: Side, price: Decimal, quantity: Decimal) -> Order {
+        Order::test_limit(side, price, quantity, Utc::now())
+    }
+
+    fn create_market_order(side: Side, quantity: Decimal) -> Order {
+        Order {
+            order_type: OrderType::Market,
+            price: None,
+            ..Order::test_limit(side, Decimal::ZERO, quantity, Utc::now())
+        }
+    }
+
+    #[test]
+    fn test_arbitrage_detection_sell_side() {
+        let mut order_book = OrderBook::new();
+        order_book
+            .add_order(create_test_order(Side::Buy, dec!(101.0), dec!(10.0)))
+            .unwrap();
+
+        let new_sell_order = create_test_order(Side::Sell, dec!(100.0), dec!(5.0));
+
+        let mev = order_book.detect_arbitrage(&new_sell_order).unwrap();
+        assert_eq!(
+            mev,
+            ArbitrageEvent {
+                symbol: "BTC-USD".to_string(),
+                order_id: new_sell_order.id,
+                side: Side::Sell,
+                order_price: Some(dec!(100.0)),
+                best_opposite_price: dec!(101.0),
+                spread: Some(dec!(1.0)),
+                available_quantity: dec!(5.0),
+                timestamp: new_sell_order.timestamp,
+            }
+        );
+        println!("Detected MEV: {}", mev);
+    }
+
+    #[test]
+    fn test_arbitrage_detection_buy_side() {
+        let mut order_book = OrderBook::new();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(100.0), dec!(10.0)))
+            .unwrap();
+
+        let new_buy_order = create_test_order(Side::Buy, dec!(101.0), dec!(5.0));
+
+        let mev = order_book.detect_arbitrage(&new_buy_order).unwrap();
+        assert_eq!(mev.order_id, new_buy_order.id);
+        assert_eq!(mev.side, Side::Buy);
+        assert_eq!(mev.order_price, Some(dec!(101.0)));
+        assert_eq!(mev.best_opposite_price, dec!(100.0));
+        assert_eq!(mev.spread, Some(dec!(1.0)));
+        assert!(mev.to_string().contains("higher than best ASK of 100.0"));
+    }
+
+    #[test]
+    fn test_arbitrage_sizes_across_crossed_levels() {
+        let mut order_book = OrderBook::new();
+        for (price, quantity) in [
+            (dec!(100.0), dec!(2.0)),
+            (dec!(100.0), dec!(1.0)),
+            (dec!(101.0), dec!(4.0)),
+            (dec!(103.0), dec!(8.0)),
+        ] {
+            order_book
+                .add_order(create_test_order(Side::Sell, price, quantity))
+                .unwrap();
+        }
+
+        let large_buy = create_test_order(Side::Buy, dec!(102.0), dec!(50.0));
+        let mev = order_book.detect_arbitrage(&large_buy).unwrap();
+        assert_eq!(mev.best_opposite_price, dec!(100.0));
+        assert_eq!(mev.available_quantity, dec!(7.0));
+
+        let small_buy = create_test_order(Side::Buy, dec!(102.0), dec!(5.0));
+        let mev = order_book.detect_arbitrage(&small_buy).unwrap();
+        assert_eq!(mev.available_quantity, dec!(5.0));
+    }
+
+    #[test]
+    fn test_arbitrage_detection_market_buy() {
+        let mut order_book = OrderBook::new();
+        assert!(
+            order_book
+                .detect_arbitrage(&create_market_order(Side::Buy, dec!(1.0)))
+                .is_none()
+        );
+
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(100.0), dec!(10.0)))
+            .unwrap();
+
+        let mev = order_book.detect_arbitrage(&create_market_order(Side::Buy, dec!(1.0)));
+        let mev = mev.unwrap();
+        assert_eq!(mev.spread, None);
+        assert!(mev.to_string().contains("best ASK of 100.0"));
+    }
+
+    #[test]
+    fn test_arbitrage_detection_market_sell() {
+        let mut order_book = OrderBook::new();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(100.0), dec!(10.0)))
+            .unwrap();
+        // Resting asks are not crossable by a market sell.
+        assert!(
+            order_book
+                .detect_arbitrage(&create_market_order(Side::Sell, dec!(1.0)))
+                .is_none()
+        );
+
+        order_book
+            .add_order(create_test_order(Side::Buy, dec!(99.0), dec!(10.0)))
+            .unwrap();
+
+        let mev = order_book.detect_arbitrage(&create_market_order(Side::Sell, dec!(1.0)));
+        assert!(mev.unwrap().to_string().contains("best BID of 99.0"));
+    }
+
+    #[test]
+    fn test_negative_prices_match_and_order_like_positive_ones() {
+        let mut order_book = OrderBook::new();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(-6.0), dec!(1.0)))
+            .unwrap();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(-4.0), dec!(1.0)))
+            .unwrap();
+        order_book
+            .add_order(create_test_order(Side::Buy, dec!(-8.0), dec!(1.0)))
+            .unwrap();
+        assert_eq!(order_book.best_bid(), Some(dec!(-8.0)));
+        assert_eq!(order_book.best_ask(), Some(dec!(-6.0)));
+        assert_eq!(order_book.spread(), Some(dec!(2.0)));
+
+        let buy = create_test_order(Side::Buy, dec!(-5.0), dec!(1.0));
+        let mev = order_book.detect_arbitrage(&buy).unwrap();
+        assert_eq!(mev.best_opposite_price, dec!(-6.0));
+        assert_eq!(mev.spread, Some(dec!(1.0)));
+        assert_eq!(mev.available_quantity, dec!(1.0));
+
+        let result = order_book.match_order(buy);
+        assert_eq!(result.trades.len(), 1);
+        assert_eq!(result.trades[0].price, dec!(-6.0));
+        assert_eq!(result.trades[0].quantity, dec!(1.0));
+        assert_eq!(order_book.best_ask(), Some(dec!(-4.0)));
+        assert_eq!(order_book.spread(), Some(dec!(4.0)));
+    }
+
+    #[test]
+    fn test_validate_invariants_reports_violations() {
+        let mut order_book = OrderBook::new();
+        let bid = create_test_order(Side::Buy, dec!(99.0), dec!(1.0));
+        let bid_id = bid.id;
+        order_book.add_order(bid).unwrap();
+        assert_eq!(order_book.validate_invariants(), Ok(()));
+
+        order_book.asks.insert(dec!(101.0), VecDeque::new());
+        assert_eq!(
+            order_book.validate_invariants(),
+            Err("empty Sell level at 101.0".to_string())
+        );
+        order_book.asks.clear();
+
+        order_book.bids.get_mut(&dec!(99.0)).unwrap()[0].quantity = Decimal::ZERO;
+        assert_eq!(
+            order_book.validate_invariants(),
+            Err(format!("order {} rests with quantity 0", bid_id))
+        );
+        order_book.bids.get_mut(&dec!(99.0)).unwrap()[0].quantity = dec!(1.0);
+
+        // Bypass add_order, which would refuse an ask below the bid.
+        let ask = create_test_order(Side::Sell, dec!(98.0), dec!(1.0));
+        order_book
+            .asks
+            .entry(dec!(98.0))
+            .or_default()
+            .push_back(ask);
+        assert!(
+            order_book
+                .validate_invariants()
+                .unwrap_err()
+                .starts_with("book is crossed")
+        );
+    }
+
+    #[derive(Debug, Clone)]
+    enum Operation {
+        /// A limit order, added passively or matched as a taker.
+        Limit {
+            side: Side,
+            ticks: u32,
+            lots: u32,
+            account: usize,
+            passive: bool,
+        },
+        Market {
+            side: Side,
+            lots: u32,
+        },
+        Cancel {
+            index: usize,
+        },
+    }
+
+    fn operation() -> impl proptest::strategy::Strategy<Value = Operation> {
+        use proptest::prelude::*;
+        let side = prop_oneof![Just(Side::Buy), Just(Side::Sell)];
+        prop_oneof![
+            (side.clone(), 0..20u32, 1..50u32, 0..3usize, any::<bool>()).prop_map(
+                |(side, ticks, lots, account, passive)| Operation::Limit {
+                    side,
+                    ticks,
+                    lots,
+                    account,
+                    passive,
+                }
+            ),
+            (side, 1..80u32).prop_map(|(side, lots)| Operation::Market { side, lots }),
+            any::<usize>().prop_map(|index| Operation::Cancel { index }),
+        ]
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn test_random_operations_keep_invariants(
+            operations in proptest::collection::vec(operation(), 1..60)
+        ) {
+            let accounts = [Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4()];
+            let lot = |lots: u32| Decimal::from(lots) * dec!(0.1);
+            let mut order_book = OrderBook::new();
+            order_book.self_trade_prevention = SelfTradePrevention::CancelResting;
+            let mut ids = Vec::new();
+
+            for operation in operations {
+                match operation {
+                    Operation::Limit { side, ticks, lots, account, passive } => {
+                        let price = dec!(90) + Decimal::from(ticks) * dec!(0.5);
+                        let mut order = create_test_order(side, price, lot(lots));
+                        order.account_id = accounts[account];
+                        ids.push(order.id);
+                        if passive {
+                            let _ = order_book.add_order(order);
+                        } else {
+                            order_book.match_order(order);
+                        }
+                    }
+                    Operation::Market { side, lots } => {
+                        order_book.match_order(create_market_order(side, lot(lots)));
+                    }
+                    Operation::Cancel { index } => {
+                        if !ids.is_empty() {
+                            order_book.cancel_order(ids[index % ids.len()]);
+                        }
+                    }
+                }
+                proptest::prop_assert_eq!(order_book.validate_invariants(), Ok(()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_no_arbitrage() {
+        let mut order_book = OrderBook::new();
+        order_book
+            .add_order(create_test_order(Side::Buy, dec!(100.0), dec!(10.0)))
+            .unwrap();
+        let new_sell_order = create_test_order(Side::Sell, dec!(101.0), dec!(5.0));
+        assert!(order_book.detect_arbitrage(&new_sell_order).is_none());
+    }
+
+    #[test]
+    fn test_add_order() {
+        let mut order_book = OrderBook::new();
+        let buy_order = create_test_order(Side::Buy, dec!(100.0), dec!(10.0));
+        let sell_order = create_test_order(Side::Sell, dec!(101.0), dec!(5.0));
+
+        order_book.add_order(buy_order).unwrap();
+        order_book.add_order(sell_order).unwrap();
+
+        assert_eq!(order_book.bids.len(), 1);
+        assert_eq!(order_book.asks.len(), 1);
+        assert_eq!(
+            order_book.bids.get(&dec!(100.0)).unwrap()[0].quantity,
+            dec!(10.0)
+        );
+        assert_eq!(
+            order_book.asks.get(&dec!(101.0)).unwrap()[0].quantity,
+            dec!(5.0)
+        );
+    }
+
+    #[test]
+    fn test_simple_match_full_fill() {
+        let mut order_book = OrderBook::new();
+        let sell_maker = create_test_order(Side::Sell, dec!(100.0), dec!(10.0));
+        order_book.add_order(sell_maker).unwrap();
+
+        let buy_taker = create_test_order(Side::Buy, dec!(100.0), dec!(10.0));
+        let trades = order_book.match_order(buy_taker).trades;
+
+        assert_eq!(trades.len(), 1);
+        assert_eq!(trades[0].quantity, dec!(10.0));
+        assert_eq!(trades[0].price, dec!(100.0));
+        assert!(order_book.asks.is_empty());
+        assert!(order_book.bids.is_empty());
+    }
+
+    #[test]
+    fn test_simple_match_partial_fill_of_maker() {
+        let mut order_book = OrderBook::new();
+        let sell_maker = create_test_order(Side::Sell, dec!(100.0), dec!(10.0));
+        order_book.add_order(sell_maker).unwrap();
+
+        let buy_taker = create_test_order(Side::Buy, dec!(100.0), dec!(5.0));
+        let trades = order_book.match_order(buy_taker).trades;
+
+        assert_eq!(trades.len(), 1);
+        assert_eq!(trades[0].quantity, dec!(5.0));
+        assert_eq!(
+            order_book.asks.get(&dec!(100.0)).unwrap()[0].quantity,
+            dec!(5.0)
+        );
+        assert!(order_book.bids.is_empty());
+    }
+
+    #[test]
+    fn test_partial_fill_of_taker() {
+        let mut order_book = OrderBook::new();
+        let sell_maker = create_test_order(Side::Sell, dec!(100.0), dec!(10.0));
+        order_book.add_order(sell_maker).unwrap();
+
+        let buy_taker = create_test_order(Side::Buy, dec!(100.0), dec!(15.0));
+        let trades = order_book.match_order(buy_taker).trades;
+
+        assert_eq!(trades.len(), 1);
+        assert_eq!(trades[0].quantity, dec!(10.0));
+        assert!(order_book.asks.is_empty());
+        assert_eq!(
+            order_book.bids.get(&dec!(100.0)).unwrap()[0].quantity,
+            dec!(5.0)
+        );
+    }
+
+    #[test]
+    fn test_multi_level_match() {
+        let mut order_book = OrderBook::new();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(100.0), dec!(5.0)))
+            .unwrap();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(101.0), dec!(5.0)))
+            .unwrap();
+
+        let buy_taker = create_test_order(Side::Buy, dec!(101.0), dec!(8.0));
+        let trades = order_book.match_order(buy_taker).trades;
+
+        assert_eq!(trades.len(), 2);
+        assert_eq!(trades[0].price, dec!(100.0));
+        assert_eq!(trades[0].quantity, dec!(5.0));
+        assert_eq!(trades[1].price, dec!(101.0));
+        assert_eq!(trades[1].quantity, dec!(3.0));
+
+        assert!(order_book.bids.is_empty());
+        assert_eq!(order_book.asks.len(), 1);
+        assert_eq!(
+            order_book.asks.get(&dec!(101.0)).unwrap()[0].quantity,
+            dec!(2.0)
+        );
+    }
+
+    #[test]
+    #[cfg(not(feature = "decimal-as-number"))]
+    fn test_amounts_serialize_as_strings() {
+        let mut order_book = OrderBook::new();
+        let maker = create_test_order(Side::Sell, dec!(100.5), dec!(2));
+        let order_json = serde_json::to_string(&maker).unwrap();
+        assert!(order_json.contains(r#""price":"100.5""#));
+        assert!(order_json.contains(r#""quantity":"2""#));
+        order_book.add_order(maker).unwrap();
+
+        let result = order_book.match_order(create_market_order(Side::Buy, dec!(1)));
+        let trade_json = serde_json::to_string(&result.trades[0]).unwrap();
+        assert!(trade_json.contains(r#""price":"100.5""#));
+        let result_json = serde_json::to_string(&result).unwrap();
+        assert!(result_json.contains(r#""average_price":"100.5""#));
+        assert!(result_json.contains(r#""filled_quantity":"1""#));
+    }
+
+    #[test]
+    fn test_quote_fill_walks_levels_without_touching_the_book() {
+        let mut order_book = OrderBook::new();
+        for (price, quantity) in [
+            (dec!(100.0), dec!(2.0)),
+            (dec!(101.0), dec!(3.0)),
+            (dec!(101.0), dec!(1.0)),
+            (dec!(103.0), dec!(4.0)),
+        ] {
+            order_book
+                .add_order(create_test_order(Side::Sell, price, quantity))
+                .unwrap();
+        }
+        let before = order_book.depth(usize::MAX);
+
+        let quote = order_book.quote_fill(Side::Buy, dec!(5.0));
+        assert_eq!(quote.filled_quantity, dec!(5.0));
+        assert_eq!(quote.total_cost, dec!(503.0));
+        assert_eq!(quote.average_price, Some(dec!(100.6)));
+        assert!(quote.fully_fillable);
+
+        let short = order_book.quote_fill(Side::Buy, dec!(12.0));
+        assert_eq!(short.filled_quantity, dec!(10.0));
+        assert_eq!(short.total_cost, dec!(1016.0));
+        assert_eq!(short.average_price, Some(dec!(101.6)));
+        assert!(!short.fully_fillable);
+
+        let nothing = order_book.quote_fill(Side::Sell, dec!(1.0));
+        assert_eq!(nothing.average_price, None);
+        assert!(!nothing.fully_fillable);
+        assert_eq!(order_book.depth(usize::MAX), before);
+    }
+
+    #[test]
+    fn test_trades_tag_the_taker_side_and_accounts() {
+        let mut order_book = OrderBook::new();
+        let ask = create_test_order(Side::Sell, dec!(101.0), dec!(1.0));
+        let bid = create_test_order(Side::Buy, dec!(99.0), dec!(1.0));
+        let (ask_account, bid_account) = (ask.account_id, bid.account_id);
+        order_book.add_order(ask).unwrap();
+        order_book.add_order(bid).unwrap();
+
+        let buyer = create_test_order(Side::Buy, dec!(101.0), dec!(1.0));
+        let buyer_account = buyer.account_id;
+        let bought = order_book.match_order(buyer);
+        assert_eq!(bought.trades[0].taker_side, Side::Buy);
+        assert_eq!(bought.trades[0].taker_account_id, buyer_account);
+        assert_eq!(bought.trades[0].maker_account_id, ask_account);
+
+        let seller = create_market_order(Side::Sell, dec!(1.0));
+        let seller_account = seller.account_id;
+        let sold = order_book.match_order(seller);
+        assert_eq!(sold.trades[0].taker_side, Side::Sell);
+        assert_eq!(sold.trades[0].taker_account_id, seller_account);
+        assert_eq!(sold.trades[0].maker_account_id, bid_account);
+    }
+
+    #[test]
+    fn test_price_improvement_against_the_taker_limit() {
+        let mut order_book = OrderBook::new();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(100.0), dec!(1.0)))
+            .unwrap();
+        let improved = order_book.match_order(create_test_order(Side::Buy, dec!(105.0), dec!(1.0)));
+        assert_eq!(improved.trades[0].price, dec!(100.0));
+        assert_eq!(improved.trades[0].price_improvement, dec!(5.0));
+
+        order_book
+            .add_order(create_test_order(Side::Buy, dec!(99.0), dec!(2.0)))
+            .unwrap();
+        let at_limit = order_book.match_order(create_test_order(Side::Sell, dec!(99.0), dec!(1.0)));
+        assert_eq!(at_limit.trades[0].price_improvement, Decimal::ZERO);
+        let market = order_book.match_order(create_market_order(Side::Sell, dec!(1.0)));
+        assert_eq!(market.trades[0].price_improvement, Decimal::ZERO);
+    }
+
+    #[test]
+    fn test_cancel_level_removes_every_order_at_the_price() {
+        let mut order_book = OrderBook::new();
+        let mut ids = Vec::new();
+        for quantity in [dec!(1.0), dec!(2.0), dec!(3.0)] {
+            let order = create_test_order(Side::Buy, dec!(99.0), quantity);
+            ids.push(order.id);
+            order_book.add_order(order).unwrap();
+        }
+        order_book
+            .add_order(create_test_order(Side::Buy, dec!(98.0), dec!(1.0)))
+            .unwrap();
+        order_book.take_changes();
+
+        let cancelled = order_book.cancel_level(Side::Buy, dec!(99.0));
+
+        assert_eq!(cancelled.iter().map(|o| o.id).collect::<Vec<_>>(), ids);
+        assert!(!order_book.bids.contains_key(&dec!(99.0)));
+        assert_eq!(order_book.bids.len(), 1);
+        assert_eq!(order_book.take_changes().len(), 3);
+        assert!(order_book.validate_invariants().is_ok());
+        assert!(order_book.cancel_level(Side::Buy, dec!(99.0)).is_empty());
+        assert!(order_book.cancel_level(Side::Sell, dec!(98.0)).is_empty());
+    }
+
+    #[test]
+    fn test_pegged_order_never_overfills_a_full_side() {
+        let mut order_book = OrderBook::new();
+        order_book.max_price_levels_per_side = 2;
+        order_book
+            .add_order(create_test_order(Side::Buy, dec!(100.0), dec!(1.0)))
+            .unwrap();
+        let near_ask = create_test_order(Side::Sell, dec!(101.0), dec!(1.0));
+        let near_ask_id = near_ask.id;
+        order_book.add_order(near_ask).unwrap();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(102.0), dec!(1.0)))
+            .unwrap();
+        let pegged = Order {
+            order_type: OrderType::Pegged {
+                reference: PegReference::Mid,
+                offset: dec!(-1.5),
+            },
+            ..create_test_order(Side::Buy, dec!(99.0), dec!(1.0))
+        };
+        let pegged_id = pegged.id;
+        order_book.add_order(pegged).unwrap();
+        let shared = create_test_order(Side::Buy, dec!(99.0), dec!(1.0));
+        let shared_id = shared.id;
+        order_book.add_order(shared).unwrap();
+
+        // The mid moves to 101, but 99.5 would be a third bid level.
+        order_book.cancel_order(near_ask_id).unwrap();
+        order_book.reprice_pegged(Utc::now());
+        assert_eq!(order_book.bids.len(), 2);
+        assert_eq!(order_book.bids[&dec!(99.0)][0].id, pegged_id);
+
+        // Alone at its level, the peg can move without adding one.
+        order_book.cancel_order(shared_id).unwrap();
+        order_book.reprice_pegged(Utc::now());
+        assert_eq!(order_book.bids.len(), 2);
+        assert_eq!(order_book.bids[&dec!(99.5)][0].id, pegged_id);
+        assert!(order_book.validate_invariants().is_ok());
+    }
+
+    #[test]
+    fn test_pegged_order_prices_from_unpegged_orders() {
+        let mut order_book = OrderBook::new();
+        let mut pegged = create_test_order(Side::Sell, dec!(0), dec!(1.0));
+        pegged.order_type = OrderType::Pegged {
+            reference: PegReference::Mid,
+            offset: dec!(0.5),
+        };
+        pegged.price = None;
+        assert_eq!(
+            order_book.match_order(pegged.clone()).status,
+            OrderStatus::Rejected
+        );
+
+        order_book
+            .add_order(create_test_order(Side::Buy, dec!(99.0), dec!(1.0)))
+            .unwrap();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(102.0), dec!(1.0)))
+            .unwrap();
+        let result = order_book.match_order(pegged.clone());
+
+        assert_eq!(result.status, OrderStatus::Resting);
+        assert_eq!(order_book.best_ask(), Some(dec!(101.0)));
+        // The resting peg is not a reference of its own.
+        assert_eq!(
+            order_book.peg_price(PegReference::BestAsk, dec!(0)),
+            Some(dec!(102.0))
+        );
+        assert_eq!(order_book.mid_price(), Some(dec!(100.0)));
+    }
+
+    #[test]
+    fn test_sub_lot_remainder_rests_or_cancels_by_policy() {
+        let remainder = |sub_lot_remainder| {
+            let mut order_book = OrderBook::new();
+            order_book.lot_size = Some(dec!(1.0));
+            order_book.sub_lot_remainder = sub_lot_remainder;
+            order_book
+                .add_order(create_test_order(Side::Sell, dec!(100.0), dec!(1.5)))
+                .unwrap();
+            let taker = create_test_order(Side::Buy, dec!(100.0), dec!(2.0));
+            let taker_id = taker.id;
+            let result = order_book.match_order(taker);
+            assert_eq!(result.filled_quantity, dec!(1.5));
+            order_book.find_order(taker_id).map(|o| o.quantity)
+        };
+
+        assert_eq!(remainder(SubLotRemainder::Rest), Some(dec!(0.5)));
+        assert_eq!(remainder(SubLotRemainder::Cancel), None);
+    }
+
+    #[test]
+    fn test_memory_estimate_tracks_orders() {
+        let mut order_book = OrderBook::new();
+        let mut estimates = vec![order_book.approx_memory_bytes()];
+        let mut ids = Vec::new();
+        for price in [dec!(99.0), dec!(99.0), dec!(98.0)] {
+            let order = create_test_order(Side::Buy, price, dec!(1.0));
+            ids.push(order.id);
+            order_book.add_order(order).unwrap();
+            estimates.push(order_book.approx_memory_bytes());
+        }
+        assert!(estimates.windows(2).all(|pair| pair[0] < pair[1]));
+
+        for id in ids {
+            order_book.cancel_order(id).unwrap();
+            let estimate = order_book.approx_memory_bytes();
+            assert!(estimate < *estimates.last().unwrap());
+            estimates.push(estimate);
+        }
+        assert_eq!(estimates.last(), estimates.first());
+    }
+
+    #[test]
+    fn test_no_match() {
+        let mut order_book = OrderBook::new();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(101.0), dec!(10.0)))
+            .unwrap();
+
+        let buy_taker = create_test_order(Side::Buy, dec!(100.0), dec!(10.0));
+        let trades = order_book.match_order(buy_taker).trades;
+
+        assert!(trades.is_empty());
+        assert_eq!(order_book.bids.len(), 1);
+        assert_eq!(order_book.asks.len(), 1);
+    }
+
+    #[test]
+    fn test_market_buy_sweeps_all_asks() {
+        let mut order_book = OrderBook::new();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(100.0), dec!(5.0)))
+            .unwrap();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(105.0), dec!(3.0)))
+            .unwrap();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(250.0), dec!(2.0)))
+            .unwrap();
+
+        let trades = order_book
+            .match_order(create_market_order(Side::Buy, dec!(20.0)))
+            .trades;
+
+        assert_eq!(trades.len(), 3);
+        assert_eq!(trades[0].price, dec!(100.0));
+        assert_eq!(trades[1].price, dec!(105.0));
+        assert_eq!(trades[2].price, dec!(250.0));
+        let filled: Decimal = trades.iter().map(|t| t.quantity).sum();
+        assert_eq!(filled, dec!(10.0));
+
+        // The unfilled remainder must not rest on the book.
+        assert!(order_book.asks.is_empty());
+        assert!(order_book.bids.is_empty());
+    }
+
+    #[test]
+    fn test_quote_market_buy_stays_within_budget() {
+        let mut order_book = OrderBook::new();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(100), dec!(5)))
+            .unwrap();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(110), dec!(10)))
+            .unwrap();
+
+        let mut buy = create_market_order(Side::Buy, dec!(1000.00));
+        buy.quote_quantity = Some(dec!(1000));
+        let result = order_book.match_order(buy);
+
+        let fills: Vec<(Decimal, Decimal)> = result
+            .trades
+            .iter()
+            .map(|trade| (trade.price, trade.quantity))
+            .collect();
+        assert_eq!(fills, vec![(dec!(100), dec!(5)), (dec!(110), dec!(4.54))]);
+        let spent: Decimal = result
+            .trades
+            .iter()
+            .map(|trade| trade.price * trade.quantity)
+            .sum();
+        assert_eq!(spent, dec!(999.40));
+        assert!(spent <= dec!(1000));
+        assert_eq!(order_book.asks[&dec!(110)][0].quantity, dec!(5.46));
+    }
+
+    #[test]
+    fn test_slippage_cap_stops_market_sweep() {
+        let mut order_book = OrderBook::new();
+        for price in [dec!(100), dec!(100.5), dec!(101), dec!(101.5), dec!(102)] {
+            order_book
+                .add_order(create_test_order(Side::Sell, price, dec!(1)))
+                .unwrap();
+        }
+
+        // 100 bps through the 100 ask allows fills up to 101.
+        let mut buy = create_market_order(Side::Buy, dec!(5));
+        buy.max_slippage_bps = Some(dec!(100));
+        let result = order_book.match_order(buy);
+
+        let prices: Vec<Decimal> = result.trades.iter().map(|trade| trade.price).collect();
+        assert_eq!(prices, vec![dec!(100), dec!(100.5), dec!(101)]);
+        assert_eq!(result.filled_quantity, dec!(3));
+        assert_eq!(result.remaining_quantity, dec!(2));
+        assert_eq!(order_book.best_ask(), Some(dec!(101.5)));
+        assert!(order_book.bids.is_empty());
+
+        let mut sell = create_market_order(Side::Sell, dec!(1));
+        sell.max_slippage_bps = Some(dec!(100));
+        assert!(order_book.match_order(sell).trades.is_empty());
+    }
+
+    #[test]
+    fn test_market_sell_walks_bids_from_highest() {
+        let mut order_book = OrderBook::new();
+        order_book
+            .add_order(create_test_order(Side::Buy, dec!(99.0), dec!(5.0)))
+            .unwrap();
+        order_book
+            .add_order(create_test_order(Side::Buy, dec!(101.0), dec!(5.0)))
+            .unwrap();
+
+        let trades = order_book
+            .match_order(create_market_order(Side::Sell, dec!(7.0)))
+            .trades;
+
+        assert_eq!(trades.len(), 2);
+        assert_eq!(trades[0].price, dec!(101.0));
+        assert_eq!(trades[0].quantity, dec!(5.0));
+        assert_eq!(trades[1].price, dec!(99.0));
+        assert_eq!(trades[1].quantity, dec!(2.0));
+        assert_eq!(
+            order_book.bids.get(&dec!(99.0)).unwrap()[0].quantity,
+            dec!(3.0)
+        );
+        assert!(order_book.asks.is_empty());
+    }
+
+    #[test]
+    fn test_cancel_order() {
+        let mut order_book = OrderBook::new();
+        let first = create_test_order(Side::Buy, dec!(100.0), dec!(10.0));
+        let second = create_test_order(Side::Buy, dec!(100.0), dec!(4.0));
+        let ask = create_test_order(Side::Sell, dec!(102.0), dec!(1.0));
+        let (first_id, second_id, ask_id) = (first.id, second.id, ask.id);
+        order_book.add_order(first).unwrap();
+        order_book.add_order(second).unwrap();
+        order_book.add_order(ask).unwrap();
+
+        let cancelled = order_book.cancel_order(first_id).unwrap();
+        assert_eq!(cancelled.id, first_id);
+        assert_eq!(order_book.bids.get(&dec!(100.0)).unwrap().len(), 1);
+
+        order_book.cancel_order(second_id).unwrap();
+        assert!(order_book.bids.is_empty());
+
+        order_book.cancel_order(ask_id).unwrap();
+        assert!(order_book.asks.is_empty());
+    }
+
+    #[test]
+    fn test_cancel_unknown_order() {
+        let mut order_book = OrderBook::new();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(101.0), dec!(10.0)))
+            .unwrap();
+
+        assert!(order_book.cancel_order(Uuid::new_v4()).is_none());
+        assert_eq!(order_book.asks.len(), 1);
+    }
+
+    #[test]
+    fn test_matchable_levels_come_in_priority_order_up_to_the_limit() {
+        let mut order_book = OrderBook::new();
+        for price in [dec!(103.0), dec!(101.0), dec!(102.0)] {
+            order_book
+                .add_order(create_test_order(Side::Sell, price, dec!(1.0)))
+                .unwrap();
+        }
+        for price in [dec!(97.0), dec!(99.0), dec!(98.0)] {
+            order_book
+                .add_order(create_test_order(Side::Buy, price, dec!(1.0)))
+                .unwrap();
+        }
+        let prices = |taker_side, taker_price| {
+            order_book
+                .matchable_levels(taker_side, taker_price)
+                .map(|(price, _)| price)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            prices(Side::Buy, None),
+            vec![dec!(101.0), dec!(102.0), dec!(103.0)]
+        );
+        assert_eq!(
+            prices(Side::Buy, Some(dec!(102.0))),
+            vec![dec!(101.0), dec!(102.0)]
+        );
+        assert!(prices(Side::Buy, Some(dec!(100.0))).is_empty());
+        assert_eq!(
+            prices(Side::Sell, None),
+            vec![dec!(99.0), dec!(98.0), dec!(97.0)]
+        );
+        assert_eq!(
+            prices(Side::Sell, Some(dec!(98.0))),
+            vec![dec!(99.0), dec!(98.0)]
+        );
+        assert!(prices(Side::Sell, Some(dec!(100.0))).is_empty());
+    }
+
+    #[test]
+    fn test_depth_aggregates_levels() {
+        let mut order_book = OrderBook::new();
+        order_book
+            .add_order(create_test_order(Side::Buy, dec!(100.0), dec!(10.0)))
+            .unwrap();
+        order_book
+            .add_order(create_test_order(Side::Buy, dec!(100.0), dec!(2.5)))
+            .unwrap();
+        order_book
+            .add_order(create_test_order(Side::Buy, dec!(99.0), dec!(4.0)))
+            .unwrap();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(102.0), dec!(1.0)))
+            .unwrap();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(101.0), dec!(3.0)))
+            .unwrap();
+
+        let depth = order_book.depth(10);
+
+        assert_eq!(depth.bids.len(), 2);
+        assert_eq!(depth.bids[0].price, dec!(100.0));
+        assert_eq!(depth.bids[0].total_quantity, dec!(12.5));
+        assert_eq!(depth.bids[0].order_count, 2);
+        assert_eq!(depth.bids[1].price, dec!(99.0));
+        assert_eq!(depth.bids[1].total_quantity, dec!(4.0));
+        assert_eq!(depth.bids[1].order_count, 1);
+
+        assert_eq!(depth.asks[0].price, dec!(101.0));
+        assert_eq!(depth.asks[1].price, dec!(102.0));
+    }
+
+    #[test]
+    fn test_checksum_is_stable_and_tracks_quantity() {
+        let build = || {
+            let mut order_book = OrderBook::new();
+            for (side, price, quantity) in [
+                (Side::Buy, dec!(99.0), dec!(2.0)),
+                (Side::Buy, dec!(98.0), dec!(1.0)),
+                (Side::Sell, dec!(101.0), dec!(4.0)),
+            ] {
+                order_book
+                    .add_order(create_test_order(side, price, quantity))
+                    .unwrap();
+            }
+            order_book
+        };
+
+        let mut order_book = build();
+        let checksum = order_book.checksum(CHECKSUM_LEVELS);
+        assert_eq!(checksum, build().checksum(CHECKSUM_LEVELS));
+        assert_eq!(checksum, CRC32.checksum(b"101.0:4.0|99.0:2.0|98.0:1.0"));
+        assert_eq!(order_book.depth(1).checksum, checksum);
+        let empty: OrderBook = OrderBook::new();
+        assert_eq!(empty.checksum(CHECKSUM_LEVELS), 0);
+
+        order_book.match_order(create_test_order(Side::Sell, dec!(99.0), dec!(0.5)));
+        assert_ne!(order_book.checksum(CHECKSUM_LEVELS), checksum);
+        assert_eq!(
+            order_book.take_deltas("BTC-USD")[0].checksum,
+            order_book.checksum(CHECKSUM_LEVELS)
+        );
+    }
+
+    #[test]
+    fn test_depth_truncates_to_best_levels() {
+        let mut order_book = OrderBook::new();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(101.0), dec!(1.0)))
+            .unwrap();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(102.0), dec!(1.0)))
+            .unwrap();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(103.0), dec!(1.0)))
+            .unwrap();
+
+        let depth = order_book.depth(2);
+
+        assert!(depth.bids.is_empty());
+        assert_eq!(depth.asks.len(), 2);
+        assert_eq!(depth.asks[1].price, dec!(102.0));
+    }
+
+    #[test]
+    fn test_level2_sums_levels_past_the_cap() {
+        let mut order_book = OrderBook::new();
+        for (side, price, quantity) in [
+            (Side::Buy, dec!(99.0), dec!(1.0)),
+            (Side::Buy, dec!(98.0), dec!(2.0)),
+            (Side::Buy, dec!(98.0), dec!(0.5)),
+            (Side::Buy, dec!(97.0), dec!(3.0)),
+            (Side::Buy, dec!(96.0), dec!(4.0)),
+            (Side::Sell, dec!(101.0), dec!(1.5)),
+        ] {
+            order_book
+                .add_order(create_test_order(side, price, quantity))
+                .unwrap();
+        }
+
+        let level2 = order_book.level2(2);
+
+        assert_eq!(
+            level2.bids.levels,
+            vec![[dec!(99.0), dec!(1.0)], [dec!(98.0), dec!(2.5)]]
+        );
+        assert_eq!(level2.bids.rest, dec!(7.0));
+        assert_eq!(level2.asks.levels, vec![[dec!(101.0), dec!(1.5)]]);
+        assert_eq!(level2.asks.rest, Decimal::ZERO);
+
+        let json = serde_json::to_value(&level2).unwrap();
+        #[cfg(not(feature = "decimal-as-number"))]
+        {
+            assert_eq!(json["bids"]["levels"][1][0], "98.0");
+            assert_eq!(json["bids"]["rest"], "7.0");
+        }
+        #[cfg(feature = "decimal-as-number")]
+        {
+            assert_eq!(json["bids"]["levels"][1][0], serde_json::json!(98.0));
+            assert_eq!(json["bids"]["rest"], serde_json::json!(7.0));
+        }
+    }
+
+    #[test]
+    fn test_raw_levels_keep_time_order_and_match_aggregates() {
+        let mut order_book = OrderBook::new();
+        let mut ids = Vec::new();
+        for (price, quantity) in [
+            (dec!(100.0), dec!(2.0)),
+            (dec!(99.0), dec!(4.0)),
+            (dec!(100.0), dec!(0.5)),
+            (dec!(100.0), dec!(1.5)),
+        ] {
+            let order = create_test_order(Side::Buy, price, quantity);
+            ids.push(order.id);
+            order_book.add_order(order).unwrap();
+        }
+
+        let raw = order_book.raw_levels(Side::Buy);
+        assert_eq!(raw.len(), 2);
+        assert_eq!(raw[0].price, dec!(100.0));
+        let queue: Vec<Uuid> = raw[0].orders.iter().map(|o| o.order_id).collect();
+        assert_eq!(queue, vec![ids[0], ids[2], ids[3]]);
+        assert_eq!(raw[1].orders[0].order_id, ids[1]);
+
+        let depth = order_book.depth(usize::MAX);
+        for (level, aggregated) in raw.iter().zip(&depth.bids) {
+            let summed: Decimal = level.orders.iter().map(|o| o.quantity).sum();
+            assert_eq!(level.price, aggregated.price);
+            assert_eq!(summed, aggregated.total_quantity);
+            assert_eq!(level.orders.len(), aggregated.order_count);
+        }
+        assert_eq!(depth.bids[0].total_quantity, dec!(4.0));
+        assert!(order_book.raw_levels(Side::Sell).is_empty());
+    }
+
+    #[test]
+    fn test_add_order_rejects_crossing_orders() {
+        let mut order_book = OrderBook::new();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(100.0), dec!(10.0)))
+            .unwrap();
+        order_book
+            .add_order(create_test_order(Side::Buy, dec!(99.0), dec!(10.0)))
+            .unwrap();
+
+        let crossing_buy = create_test_order(Side::Buy, dec!(105.0), dec!(1.0));
+        assert_eq!(
+            order_book.add_order(crossing_buy),
+            Err(OrderBookError::WouldCross {
+                price: dec!(105.0),
+                opposite: dec!(100.0),
+            })
+        );
+
+        let locking_sell = create_test_order(Side::Sell, dec!(99.0), dec!(1.0));
+        assert!(order_book.add_order(locking_sell).is_err());
+
+        assert!(!order_book.is_crossed());
+        assert_eq!(order_book.bids.len(), 1);
+        assert_eq!(order_book.asks.len(), 1);
+    }
+
+    #[test]
+    fn test_is_crossed() {
+        let mut order_book = OrderBook::new();
+        assert!(!order_book.is_crossed());
+
+        order_book
+            .add_order(create_test_order(Side::Buy, dec!(100.0), dec!(1.0)))
+            .unwrap();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(101.0), dec!(1.0)))
+            .unwrap();
+        assert!(!order_book.is_crossed());
+
+        // Bypass add_order to simulate a corrupted book.
+        order_book
+            .bids
+            .entry(dec!(102.0))
+            .or_default()
+            .push_back(create_test_order(Side::Buy, dec!(102.0), dec!(1.0)));
+        assert!(order_book.is_crossed());
+    }
+
+    #[test]
+    fn test_price_level_keeps_time_priority() {
+        let mut order_book = OrderBook::new();
+        let mut newer = create_test_order(Side::Sell, dec!(100.0), dec!(5.0));
+        let mut older = create_test_order(Side::Sell, dec!(100.0), dec!(5.0));
+        older.timestamp = Utc::now() - chrono::Duration::seconds(10);
+        newer.timestamp = Utc::now();
+        let older_id = older.id;
+
+        // Insert the newer order first to simulate out-of-order arrival.
+        order_book.add_order(newer).unwrap();
+        order_book.add_order(older).unwrap();
+
+        let trades = order_book
+            .match_order(create_test_order(Side::Buy, dec!(100.0), dec!(5.0)))
+            .trades;
+
+        assert_eq!(trades.len(), 1);
+        assert_eq!(trades[0].maker_order_id, older_id);
+        assert_ne!(order_book.asks.get(&dec!(100.0)).unwrap()[0].id, older_id);
+    }
+
+    #[test]
+    fn test_fok_fills_completely_when_liquidity_suffices() {
+        let mut order_book = OrderBook::new();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(100.0), dec!(5.0)))
+            .unwrap();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(101.0), dec!(5.0)))
+            .unwrap();
+
+        let mut fok_buy = create_test_order(Side::Buy, dec!(101.0), dec!(8.0));
+        fok_buy.time_in_force = TimeInForce::Fok;
+        let trades = order_book.match_order(fok_buy).trades;
+
+        let filled: Decimal = trades.iter().map(|t| t.quantity).sum();
+        assert_eq!(filled, dec!(8.0));
+        assert!(order_book.bids.is_empty());
+        assert_eq!(
+            order_book.asks.get(&dec!(101.0)).unwrap()[0].quantity,
+            dec!(2.0)
+        );
+    }
+
+    #[test]
+    fn test_fok_killed_leaves_book_untouched() {
+        let mut order_book = OrderBook::new();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(100.0), dec!(5.0)))
+            .unwrap();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(102.0), dec!(5.0)))
+            .unwrap();
+        let before = order_book.depth(10);
+
+        // Only 5 units are available at or below 101.
+        let mut fok_buy = create_test_order(Side::Buy, dec!(101.0), dec!(8.0));
+        fok_buy.time_in_force = TimeInForce::Fok;
+        let trades = order_book.match_order(fok_buy).trades;
+
+        assert!(trades.is_empty());
+        assert_eq!(order_book.depth(10), before);
+    }
+
+    #[test]
+    fn test_ioc_discards_unfilled_remainder() {
+        let mut order_book = OrderBook::new();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(100.0), dec!(10.0)))
+            .unwrap();
+
+        let mut ioc_buy = create_test_order(Side::Buy, dec!(100.0), dec!(15.0));
+        ioc_buy.time_in_force = TimeInForce::Ioc;
+        let trades = order_book.match_order(ioc_buy).trades;
+
+        assert_eq!(trades.len(), 1);
+        assert_eq!(trades[0].quantity, dec!(10.0));
+        assert!(order_book.asks.is_empty());
+        assert!(order_book.bids.is_empty());
+    }
+
+    #[test]
+    fn test_order_result_filled() {
+        let mut order_book = OrderBook::new();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(100.0), dec!(10.0)))
+            .unwrap();
+
+        let buy_taker = create_test_order(Side::Buy, dec!(100.0), dec!(10.0));
+        let buy_id = buy_taker.id;
+        let result = order_book.match_order(buy_taker);
+
+        assert_eq!(result.order_id, buy_id);
+        assert_eq!(result.status, OrderStatus::Filled);
+        assert_eq!(result.filled_quantity, dec!(10.0));
+        assert_eq!(result.remaining_quantity, Decimal::ZERO);
+        assert_eq!(result.trades.len(), 1);
+    }
+
+    #[test]
+    fn test_order_result_partially_filled() {
+        let mut order_book = OrderBook::new();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(100.0), dec!(4.0)))
+            .unwrap();
+
+        let result = order_book.match_order(create_test_order(Side::Buy, dec!(100.0), dec!(10.0)));
+
+        assert_eq!(result.status, OrderStatus::PartiallyFilled);
+        assert_eq!(result.filled_quantity, dec!(4.0));
+        assert_eq!(result.remaining_quantity, dec!(6.0));
+    }
+
+    #[test]
+    fn test_order_result_resting() {
+        let mut order_book = OrderBook::new();
+
+        let result = order_book.match_order(create_test_order(Side::Buy, dec!(100.0), dec!(10.0)));
+
+        assert_eq!(result.status, OrderStatus::Resting);
+        assert_eq!(result.filled_quantity, Decimal::ZERO);
+        assert_eq!(result.remaining_quantity, dec!(10.0));
+        assert!(result.trades.is_empty());
+        assert_eq!(order_book.bids.len(), 1);
+    }
+
+    #[test]
+    fn test_order_result_rejected() {
+        let mut order_book = OrderBook::new();
+
+        let mut fok_buy = create_test_order(Side::Buy, dec!(100.0), dec!(10.0));
+        fok_buy.time_in_force = TimeInForce::Fok;
+        let result = order_book.match_order(fok_buy);
+
+        assert_eq!(result.status, OrderStatus::Rejected);
+        assert_eq!(result.remaining_quantity, dec!(10.0));
+        assert!(order_book.bids.is_empty());
+    }
+
+    #[test]
+    fn test_take_changes_reports_resting_order_updates() {
+        let mut order_book = OrderBook::new();
+        let filled_maker = create_test_order(Side::Sell, dec!(100.0), dec!(5.0));
+        let partial_maker = create_test_order(Side::Sell, dec!(101.0), dec!(5.0));
+        let (filled_id, partial_id) = (filled_maker.id, partial_maker.id);
+        order_book.add_order(filled_maker).unwrap();
+        order_book.add_order(partial_maker).unwrap();
+        assert_eq!(order_book.take_changes().len(), 2);
+
+        let buy_taker = create_test_order(Side::Buy, dec!(101.0), dec!(7.0));
+        order_book.match_order(buy_taker);
+        let resting_bid = create_test_order(Side::Buy, dec!(99.0), dec!(1.0));
+        let resting_id = resting_bid.id;
+        order_book.match_order(resting_bid);
+
+        let changes = order_book.take_changes();
+        assert_eq!(changes.len(), 3);
+        for change in &changes {
+            match change {
+                OrderChange::Delete(id) => assert_eq!(*id, filled_id),
+                OrderChange::Upsert(order) if order.id == partial_id => {
+                    assert_eq!(order.quantity, dec!(3.0));
+                }
+                OrderChange::Upsert(order) => assert_eq!(order.id, resting_id),
+            }
+        }
+        assert!(order_book.take_changes().is_empty());
+
+        order_book.cancel_order(resting_id).unwrap();
+        assert!(matches!(
+            order_book.take_changes().as_slice(),
+            [OrderChange::Delete(id)] if *id == resting_id
+        ));
+    }
+
+    #[test]
+    fn test_load_from_orders() {
+        let orders = vec![
+            create_test_order(Side::Buy, dec!(99.0), dec!(2.0)),
+            create_test_order(Side::Buy, dec!(99.0), dec!(3.0)),
+            create_test_order(Side::Sell, dec!(101.0), dec!(4.0)),
+        ];
+
+        let mut order_book = OrderBook::load_from_orders(orders);
+
+        assert_eq!(order_book.bids.get(&dec!(99.0)).unwrap().len(), 2);
+        assert_eq!(order_book.asks.get(&dec!(101.0)).unwrap().len(), 1);
+        assert!(order_book.take_changes().is_empty());
+    }
+
+    #[test]
+    fn test_top_of_book_changes_only_with_the_best_levels() {
+        let mut order_book = OrderBook::new();
+        order_book
+            .add_order(create_test_order(Side::Buy, dec!(99.0), dec!(1.0)))
+            .unwrap();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(101.0), dec!(1.0)))
+            .unwrap();
+        let first = order_book.top_of_book_changed();
+        assert!(first.changed);
+        assert_eq!(first.previous.bid, None);
+        assert_eq!(first.current.bid.unwrap().price, dec!(99.0));
+
+        order_book
+            .add_order(create_test_order(Side::Buy, dec!(98.0), dec!(5.0)))
+            .unwrap();
+        let deep = order_book.top_of_book_changed();
+        assert!(!deep.changed);
+        assert_eq!(deep.previous, deep.current);
+
+        order_book
+            .add_order(create_test_order(Side::Buy, dec!(100.0), dec!(2.0)))
+            .unwrap();
+        let better = order_book.top_of_book_changed();
+        assert!(better.changed);
+        assert_eq!(better.previous.bid.unwrap().price, dec!(99.0));
+        assert_eq!(better.current.bid.unwrap().price, dec!(100.0));
+        assert_eq!(better.current.ask, better.previous.ask);
+    }
+
+    #[test]
+    fn test_take_deltas_reports_changed_levels() {
+        let mut order_book = OrderBook::new();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(100.0), dec!(5.0)))
+            .unwrap();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(100.0), dec!(2.0)))
+            .unwrap();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(101.0), dec!(4.0)))
+            .unwrap();
+        let bid = create_test_order(Side::Buy, dec!(98.0), dec!(1.0));
+        let bid_id = bid.id;
+        order_book.add_order(bid).unwrap();
+
+        let deltas = order_book.take_deltas("BTC-USD");
+        assert_eq!(deltas.len(), 3);
+        assert_eq!(deltas[0].side, Side::Buy);
+        assert_eq!(deltas[1].price, dec!(100.0));
+        assert_eq!(deltas[1].new_total_quantity, dec!(7.0));
+
+        // Sweeps the 100 level and eats into 101.
+        order_book.match_order(create_test_order(Side::Buy, dec!(101.0), dec!(8.0)));
+        order_book.cancel_order(bid_id).unwrap();
+
+        let deltas = order_book.take_deltas("BTC-USD");
+        let checksum = order_book.checksum(CHECKSUM_LEVELS);
+        assert_eq!(
+            deltas,
+            vec![
+                BookDelta {
+                    symbol: "BTC-USD".to_string(),
+                    side: Side::Buy,
+                    price: dec!(98.0),
+                    new_total_quantity: Decimal::ZERO,
+                    checksum,
+                },
+                BookDelta {
+                    symbol: "BTC-USD".to_string(),
+                    side: Side::Sell,
+                    price: dec!(100.0),
+                    new_total_quantity: Decimal::ZERO,
+                    checksum,
+                },
+                BookDelta {
+                    symbol: "BTC-USD".to_string(),
+                    side: Side::Sell,
+                    price: dec!(101.0),
+                    new_total_quantity: dec!(3.0),
+                    checksum,
+                },
+            ]
+        );
+        assert!(order_book.take_deltas("BTC-USD").is_empty());
+    }
+
+    #[test]
+    fn test_self_trade_prevention_cancels_resting_order() {
+        let mut order_book = OrderBook::new();
+        let account_id = Uuid::new_v4();
+        let mut own_ask = create_test_order(Side::Sell, dec!(100.0), dec!(5.0));
+        own_ask.account_id = account_id;
+        let own_ask_id = own_ask.id;
+        order_book.add_order(own_ask).unwrap();
+
+        let mut own_bid = create_test_order(Side::Buy, dec!(100.0), dec!(5.0));
+        own_bid.account_id = account_id;
+        let result = order_book.match_order(own_bid);
+
+        assert!(result.trades.is_empty());
+        assert!(order_book.asks.is_empty());
+        assert!(order_book.find_order(own_ask_id).is_none());
+        // The incoming order rests once its own ask is out of the way.
+        assert_eq!(result.status, OrderStatus::Resting);
+        assert_eq!(order_book.bids.get(&dec!(100.0)).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_self_trade_prevention_disabled() {
+        let mut order_book = OrderBook::new();
+        order_book.self_trade_prevention = SelfTradePrevention::None;
+        let account_id = Uuid::new_v4();
+        let mut own_ask = create_test_order(Side::Sell, dec!(100.0), dec!(5.0));
+        own_ask.account_id = account_id;
+        order_book.add_order(own_ask).unwrap();
+
+        let mut own_bid = create_test_order(Side::Buy, dec!(100.0), dec!(5.0));
+        own_bid.account_id = account_id;
+        let trades = order_book.match_order(own_bid).trades;
+
+        assert_eq!(trades.len(), 1);
+        assert!(order_book.asks.is_empty());
+    }
+
+    #[test]
+    fn test_vwap_across_levels() {
+        let mut order_book = OrderBook::new();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(100.0), dec!(5.0)))
+            .unwrap();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(101.0), dec!(3.0)))
+            .unwrap();
+
+        let result = order_book.match_order(create_test_order(Side::Buy, dec!(101.0), dec!(8.0)));
+
+        assert_eq!(Trade::vwap(&result.trades), Some(dec!(100.375)));
+        assert_eq!(result.average_price, Some(dec!(100.375)));
+    }
+
+    #[test]
+    fn test_vwap_without_trades() {
+        assert_eq!(Trade::<Decimal>::vwap(&[]), None);
+
+        let mut order_book = OrderBook::new();
+        let result = order_book.match_order(create_test_order(Side::Buy, dec!(100.0), dec!(1.0)));
+        assert_eq!(result.average_price, None);
+    }
+
+    #[test]
+    fn test_priceless_maker_is_skipped_without_panicking() {
+        let mut order_book = OrderBook::new();
+        let mut corrupt = create_test_order(Side::Sell, dec!(100.0), dec!(5.0));
+        corrupt.price = None;
+        let corrupt_id = corrupt.id;
+        // Bypass add_order, which refuses priceless orders.
+        order_book
+            .asks
+            .entry(dec!(100.0))
+            .or_default()
+            .push_back(corrupt);
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(100.0), dec!(5.0)))
+            .unwrap();
+
+        let trades = order_book
+            .match_order(create_test_order(Side::Buy, dec!(100.0), dec!(5.0)))
+            .trades;
+
+        assert_eq!(trades.len(), 1);
+        assert_ne!(trades[0].maker_order_id, corrupt_id);
+        assert_eq!(trades[0].price, dec!(100.0));
+        assert!(order_book.asks.is_empty());
+    }
+
+    #[test]
+    fn test_trade_fees() {
+        let mut order_book = OrderBook::new();
+        order_book.fee_schedule = FeeSchedule {
+            maker_bps: dec!(2),
+            taker_bps: dec!(10),
+        };
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(100), dec!(10)))
+            .unwrap();
+
+        let trades = order_book
+            .match_order(create_test_order(Side::Buy, dec!(100), dec!(10)))
+            .trades;
+
+        assert_eq!(trades[0].taker_fee, dec!(1.0));
+        assert_eq!(trades[0].maker_fee, dec!(0.2));
+    }
+
+    #[test]
+    fn test_fees_and_average_price_are_rounded() {
+        let mut order_book = OrderBook::new();
+        order_book.fee_schedule = FeeSchedule {
+            maker_bps: dec!(3),
+            taker_bps: dec!(3),
+        };
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(100.00), dec!(1.0)))
+            .unwrap();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(100.01), dec!(2.0)))
+            .unwrap();
+
+        let result = order_book.match_order(create_test_order(Side::Buy, dec!(100.01), dec!(3.0)));
+
+        // 300.020 / 3 = 100.00666...
+        assert_eq!(result.average_price, Some(dec!(100.00666667)));
+        // 0.0003 * 33.333333 = 0.0099999999
+        assert_eq!(
+            FeeSchedule {
+                maker_bps: dec!(3),
+                taker_bps: dec!(3)
+            }
+            .taker_fee(dec!(33.333333), dec!(1)),
+            dec!(0.01000000)
+        );
+        assert_eq!(result.trades[1].taker_fee, dec!(0.060006));
+    }
+
+    #[test]
+    fn test_dust_maker_is_cancelled_instead_of_traded() {
+        let mut order_book = OrderBook::new();
+        order_book.min_trade_notional = dec!(1.0);
+        let dust = create_test_order(Side::Sell, dec!(100.0), dec!(0.005));
+        let dust_id = dust.id;
+        order_book.add_order(dust).unwrap();
+        let maker = create_test_order(Side::Sell, dec!(100.0), dec!(2.0));
+        let maker_id = maker.id;
+        order_book.add_order(maker).unwrap();
+        order_book.take_changes();
+
+        let result = order_book.match_order(create_test_order(Side::Buy, dec!(100.0), dec!(1.0)));
+
+        assert_eq!(result.trades.len(), 1);
+        assert_eq!(result.trades[0].maker_order_id, maker_id);
+        assert_eq!(result.trades[0].quantity, dec!(1.0));
+        assert!(order_book.find_order(dust_id).is_none());
+        assert_eq!(order_book.asks[&dec!(100.0)].len(), 1);
+        assert!(
+            order_book
+                .take_changes()
+                .iter()
+                .any(|change| matches!(change, OrderChange::Delete(id) if *id == dust_id))
+        );
+    }
+
+    #[test]
+    fn test_dust_remainder_of_maker_does_not_linger() {
+        let mut order_book = OrderBook::new();
+        let maker = create_test_order(Side::Sell, dec!(100.0), dec!(1.0000000001));
+        let (maker_id, maker_account) = (maker.id, maker.account_id);
+        order_book.add_order(maker).unwrap();
+        order_book.take_changes();
+
+        let result = order_book.match_order(create_test_order(Side::Buy, dec!(100.0), dec!(1.0)));
+
+        assert_eq!(result.status, OrderStatus::Filled);
+        assert!(order_book.find_order(maker_id).is_none());
+        assert!(order_book.asks.is_empty());
+        assert_eq!(order_book.open_order_count(maker_account), 0);
+        assert!(
+            order_book
+                .take_changes()
+                .iter()
+                .any(|change| matches!(change, OrderChange::Delete(id) if *id == maker_id))
+        );
+    }
+
+    #[test]
+    fn test_dust_remainder_of_taker_does_not_rest() {
+        let mut order_book = OrderBook::new();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(100.0), dec!(1.0)))
+            .unwrap();
+
+        let result = order_book.match_order(create_test_order(
+            Side::Buy,
+            dec!(100.0),
+            dec!(1.0000000001),
+        ));
+
+        assert_eq!(result.trades.len(), 1);
+        assert_eq!(result.status, OrderStatus::Filled);
+        assert_eq!(result.remaining_quantity, dec!(0));
+        assert!(order_book.bids.is_empty());
+        assert!(order_book.asks.is_empty());
+    }
+
+    // Debug builds stop at the assertion; release builds must still refuse
+    // the fill.
+    #[test]
+    #[cfg_attr(debug_assertions, should_panic(expected = "trade-through"))]
+    fn test_guard_stops_trade_through_of_misfiled_maker() {
+        let mut order_book = OrderBook::new();
+        // Corrupt the book: an ask priced 105 filed under the 100 level.
+        let misfiled = create_test_order(Side::Sell, dec!(105.0), dec!(1.0));
+        order_book
+            .asks
+            .entry(dec!(100.0))
+            .or_default()
+            .push_back(misfiled);
+
+        let result = order_book.match_order(create_test_order(Side::Buy, dec!(101.0), dec!(1.0)));
+
+        assert!(result.trades.is_empty());
+        assert_eq!(order_book.asks[&dec!(100.0)].len(), 1);
+    }
+
+    #[test]
+    fn test_midpoint_pricing_splits_price_improvement() {
+        let fill_price = |pricing_mode, side, taker_price| {
+            let mut order_book = OrderBook::new();
+            order_book.pricing_mode = pricing_mode;
+            order_book
+                .add_order(create_test_order(Side::Sell, dec!(100.0), dec!(1.0)))
+                .unwrap();
+            order_book
+                .add_order(create_test_order(Side::Buy, dec!(90.0), dec!(1.0)))
+                .unwrap();
+            let result = order_book.match_order(create_test_order(side, taker_price, dec!(1.0)));
+            assert_eq!(result.trades.len(), 1);
+            result.trades[0].price
+        };
+
+        assert_eq!(
+            fill_price(PricingMode::MakerPrice, Side::Buy, dec!(101.0)),
+            dec!(100.0)
+        );
+        assert_eq!(
+            fill_price(PricingMode::Midpoint, Side::Buy, dec!(101.0)),
+            dec!(100.5)
+        );
+        assert_eq!(
+            fill_price(PricingMode::MakerPrice, Side::Sell, dec!(89.0)),
+            dec!(90.0)
+        );
+        assert_eq!(
+            fill_price(PricingMode::Midpoint, Side::Sell, dec!(89.0)),
+            dec!(89.5)
+        );
+    }
+
+    #[test]
+    fn test_midpoint_of_maker_price_and_limit() {
+        let midpoint = PricingMode::Midpoint;
+        assert_eq!(midpoint.trade_price(dec!(100), Some(dec!(100))), dec!(100));
+        assert_eq!(
+            midpoint.trade_price(dec!(100.01), Some(dec!(100.02))),
+            dec!(100.015)
+        );
+        assert_eq!(midpoint.trade_price(dec!(100), None), dec!(100));
+        assert_eq!(midpoint.trade_price(10_i64, Some(13)), 11);
+        assert_eq!(
+            PricingMode::MakerPrice.trade_price(dec!(100), Some(dec!(110))),
+            dec!(100)
+        );
+    }
+
+    #[test]
+    fn test_level_tiebreaker_orders_identical_timestamps() {
+        let queue = |level_tiebreaker| {
+            let mut order_book = OrderBook::new();
+            order_book.level_tiebreaker = level_tiebreaker;
+            let timestamp = Utc::now();
+            let mut ids = Vec::new();
+            for quantity in [dec!(1.0), dec!(3.0), dec!(2.0)] {
+                let mut order = create_test_order(Side::Sell, dec!(100.0), quantity);
+                order.timestamp = timestamp;
+                ids.push(order.id);
+                order_book.add_order(order).unwrap();
+            }
+            let queued: Vec<Uuid> = order_book.asks[&dec!(100.0)].iter().map(|o| o.id).collect();
+            (ids, queued)
+        };
+
+        let (ids, queued) = queue(LevelTiebreaker::InsertionOrder);
+        assert_eq!(queued, ids);
+
+        let (ids, queued) = queue(LevelTiebreaker::LargerFirst);
+        assert_eq!(queued, vec![ids[1], ids[2], ids[0]]);
+    }
+
+    #[test]
+    fn test_clear_empties_book_and_resets_counters() {
+        let mut order_book = OrderBook::new();
+        let account_id = Uuid::new_v4();
+        let mut bid = create_test_order(Side::Buy, dec!(99.0), dec!(1.0));
+        bid.account_id = account_id;
+        let mut stop = create_test_order(Side::Sell, dec!(95.0), dec!(1.0));
+        stop.order_type = OrderType::StopLimit {
+            stop_price: dec!(96.0),
+        };
+        stop.account_id = account_id;
+        order_book.add_order(bid).unwrap();
+        order_book.add_order(stop).unwrap();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(101.0), dec!(2.0)))
+            .unwrap();
+        order_book.match_order(create_test_order(Side::Buy, dec!(101.0), dec!(1.0)));
+        order_book.take_changes();
+        assert_eq!(order_book.open_order_count(account_id), 2);
+        assert_eq!(order_book.stats.last_price(), Some(dec!(101.0)));
+
+        order_book.clear();
+
+        let depth = order_book.depth(usize::MAX);
+        assert!(depth.bids.is_empty());
+        assert!(depth.asks.is_empty());
+        assert!(order_book.stops.is_empty());
+        assert_eq!(order_book.open_order_count(account_id), 0);
+        assert_eq!(order_book.stats.last_price(), None);
+        assert_eq!(order_book.take_changes().len(), 3);
+    }
+
+    #[test]
+    fn test_auction_sets_off_stops_and_keeps_accounts_apart() {
+        let mut order_book = OrderBook::new();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(102), dec!(1)))
+            .unwrap();
+        let stop = create_stop_order(Side::Buy, dec!(100), dec!(103), dec!(1));
+        let stop_id = stop.id;
+        order_book.add_order(stop).unwrap();
+
+        let own_bid = create_test_order(Side::Buy, dec!(101), dec!(1));
+        let own_ask = Order {
+            account_id: own_bid.account_id,
+            ..create_test_order(Side::Sell, dec!(100), dec!(1))
+        };
+        let own_ask_id = own_ask.id;
+        order_book.add_auction_order(own_bid).unwrap();
+        order_book.add_auction_order(own_ask).unwrap();
+        assert!(order_book.bids.is_empty());
+        order_book
+            .add_auction_order(create_test_order(Side::Buy, dec!(101), dec!(1)))
+            .unwrap();
+
+        let (clearing_price, trades) = order_book.run_auction().unwrap();
+
+        assert_eq!(clearing_price, dec!(100));
+        assert_eq!(trades.len(), 2);
+        assert_eq!(trades[0].maker_order_id, own_ask_id);
+        assert_eq!(trades[1].taker_order_id, stop_id);
+        assert_eq!(trades[1].price, dec!(102));
+        assert!(order_book.stops.is_empty());
+        assert!(order_book.asks.is_empty());
+    }
+
+    #[test]
+    fn test_auction_clears_at_volume_maximizing_price() {
+        let mut order_book = OrderBook::new();
+        for (side, price, quantity) in [
+            (Side::Buy, dec!(102), dec!(3)),
+            (Side::Buy, dec!(101), dec!(3)),
+            (Side::Buy, dec!(100), dec!(1)),
+            (Side::Sell, dec!(99), dec!(2)),
+            (Side::Sell, dec!(100), dec!(3)),
+            (Side::Sell, dec!(101), dec!(4)),
+            (Side::Sell, dec!(103), dec!(1)),
+        ] {
+            order_book
+                .add_auction_order(create_test_order(side, price, quantity))
+                .unwrap();
+        }
+        assert!(order_book.is_crossed());
+
+        // 99 matches 2, 100 matches 5, 101 matches 6 and 102 matches 3.
+        let (clearing_price, trades) = order_book.run_auction().unwrap();
+
+        assert_eq!(clearing_price, dec!(101));
+        assert!(trades.iter().all(|trade| trade.price == dec!(101)));
+        let quantities: Vec<Decimal> = trades.iter().map(|trade| trade.quantity).collect();
+        assert_eq!(quantities, vec![dec!(2), dec!(1), dec!(2), dec!(1)]);
+
+        assert!(!order_book.is_crossed());
+        assert_eq!(order_book.best_bid(), Some(dec!(100)));
+        assert_eq!(order_book.best_ask(), Some(dec!(101)));
+        assert_eq!(
+            order_book.total_volume_at_or_better(Side::Sell, dec!(101)),
+            dec!(3)
+        );
+        assert!(order_book.run_auction().is_none());
+    }
+
+    #[test]
+    fn test_max_fill_per_maker_rotates_to_next_maker() {
+        let mut order_book = OrderBook::new();
+        order_book.max_fill_per_maker = Some(dec!(2.0));
+        let first = create_test_order(Side::Sell, dec!(100.0), dec!(5.0));
+        let second = create_test_order(Side::Sell, dec!(100.0), dec!(5.0));
+        let (first_id, second_id) = (first.id, second.id);
+        order_book.add_order(first).unwrap();
+        order_book.add_order(second).unwrap();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(101.0), dec!(5.0)))
+            .unwrap();
+
+        let result = order_book.match_order(create_test_order(Side::Buy, dec!(101.0), dec!(5.0)));
+
+        let fills: Vec<(Uuid, Decimal, Decimal)> = result
+            .trades
+            .iter()
+            .map(|trade| (trade.maker_order_id, trade.price, trade.quantity))
+            .collect();
+        assert_eq!(fills[0], (first_id, dec!(100.0), dec!(2.0)));
+        assert_eq!(fills[1], (second_id, dec!(100.0), dec!(2.0)));
+        assert_eq!(fills[2].1, dec!(101.0));
+        assert_eq!(fills[2].2, dec!(1.0));
+
+        // Capped makers keep their place in the queue.
+        let level = &order_book.asks[&dec!(100.0)];
+        assert_eq!(level[0].id, first_id);
+        assert_eq!(level[0].quantity, dec!(3.0));
+        assert_eq!(level[1].id, second_id);
+        assert_eq!(level[1].quantity, dec!(3.0));
+    }
+
+    #[test]
+    fn test_fill_many_makers_at_one_level() {
+        let mut order_book = OrderBook::new();
+        let mut maker_ids = Vec::new();
+        for _ in 0..50 {
+            let maker = create_test_order(Side::Sell, dec!(100.0), dec!(1.0));
+            maker_ids.push(maker.id);
+            order_book.add_order(maker).unwrap();
+        }
+
+        let trades = order_book
+            .match_order(create_test_order(Side::Buy, dec!(100.0), dec!(37.5)))
+            .trades;
+
+        assert_eq!(trades.len(), 38);
+        for (trade, maker_id) in trades.iter().zip(&maker_ids) {
+            assert_eq!(trade.maker_order_id, *maker_id);
+        }
+        assert_eq!(trades[37].quantity, dec!(0.5));
+
+        let level = order_book.asks.get(&dec!(100.0)).unwrap();
+        assert_eq!(level.len(), 13);
+        assert_eq!(level[0].id, maker_ids[37]);
+        assert_eq!(level[0].quantity, dec!(0.5));
+        assert_eq!(level[1].id, maker_ids[38]);
+    }
+
+    #[test]
+    fn test_top_of_book_helpers_on_empty_book() {
+        let order_book = DefaultOrderBook::new();
+        assert_eq!(order_book.best_bid(), None);
+        assert_eq!(order_book.best_ask(), None);
+        assert_eq!(order_book.spread(), None);
+        assert_eq!(order_book.mid_price(), None);
+    }
+
+    #[test]
+    fn test_top_of_book_helpers_one_sided() {
+        let mut order_book = OrderBook::new();
+        order_book
+            .add_order(create_test_order(Side::Buy, dec!(99.0), dec!(1.0)))
+            .unwrap();
+        order_book
+            .add_order(create_test_order(Side::Buy, dec!(100.0), dec!(1.0)))
+            .unwrap();
+
+        assert_eq!(order_book.best_bid(), Some(dec!(100.0)));
+        assert_eq!(order_book.best_ask(), None);
+        assert_eq!(order_book.spread(), None);
+        assert_eq!(order_book.mid_price(), None);
+    }
+
+    #[test]
+    fn test_top_of_book_helpers() {
+        let mut order_book = OrderBook::new();
+        order_book
+            .add_order(create_test_order(Side::Buy, dec!(100.0), dec!(1.0)))
+            .unwrap();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(101.0), dec!(1.0)))
+            .unwrap();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(103.0), dec!(1.0)))
+            .unwrap();
+
+        assert_eq!(order_book.best_bid(), Some(dec!(100.0)));
+        assert_eq!(order_book.best_ask(), Some(dec!(101.0)));
+        assert_eq!(order_book.spread(), Some(dec!(1.0)));
+        assert_eq!(order_book.mid_price(), Some(dec!(100.5)));
+    }
+
+    fn create_stop_order(
+        side: Side,
+        stop_price: Decimal,
+        price: Decimal,
+        quantity: Decimal,
+    ) -> Order {
+        let mut order = create_test_order(side, price, quantity);
+        order.order_type = OrderType::StopLimit { stop_price };
+        order
+    }
+
+    #[test]
+    fn test_stop_order_activates_and_fills() {
+        let mut order_book = OrderBook::new();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(100.0), dec!(1.0)))
+            .unwrap();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(102.0), dec!(5.0)))
+            .unwrap();
+
+        // Buy stop that fires once the market trades at 100 or higher.
+        let stop = create_stop_order(Side::Buy, dec!(100.0), dec!(103.0), dec!(2.0));
+        let stop_id = stop.id;
+        let parked = order_book.match_order(stop);
+        assert_eq!(parked.status, OrderStatus::Resting);
+        assert_eq!(order_book.stops.len(), 1);
+        assert!(order_book.bids.is_empty());
+
+        let result = order_book.match_order(create_test_order(Side::Buy, dec!(100.0), dec!(1.0)));
+
+        assert_eq!(result.trades.len(), 1);
+        assert_eq!(result.triggered.len(), 1);
+        let stop_result = &result.triggered[0];
+        assert_eq!(stop_result.order_id, stop_id);
+        assert_eq!(stop_result.status, OrderStatus::Filled);
+        assert_eq!(stop_result.trades[0].price, dec!(102.0));
+        assert_eq!(result.all_trades().len(), 2);
+
+        assert!(order_book.stops.is_empty());
+        assert_eq!(
+            order_book.asks.get(&dec!(102.0)).unwrap()[0].quantity,
+            dec!(3.0)
+        );
+    }
+
+    #[test]
+    fn test_stop_order_waits_until_price_reached() {
+        let mut order_book = OrderBook::new();
+        order_book
+            .add_order(create_test_order(Side::Buy, dec!(100.0), dec!(1.0)))
+            .unwrap();
+
+        // Sell stop at 95 must not fire on a trade at 100.
+        order_book.match_order(create_stop_order(
+            Side::Sell,
+            dec!(95.0),
+            dec!(94.0),
+            dec!(1.0),
+        ));
+        let result = order_book.match_order(create_test_order(Side::Sell, dec!(100.0), dec!(1.0)));
+
+        assert_eq!(result.trades.len(), 1);
+        assert!(result.triggered.is_empty());
+        assert_eq!(order_book.stops.len(), 1);
+        assert_eq!(order_book.trigger_stops(dec!(95.0)).len(), 1);
+        assert!(order_book.stops.is_empty());
+    }
+
+    #[test]
+    fn test_cancel_stop_order() {
+        let mut order_book = OrderBook::new();
+        let stop = create_stop_order(Side::Buy, dec!(100.0), dec!(101.0), dec!(1.0));
+        let stop_id = stop.id;
+        order_book.match_order(stop);
+
+        assert_eq!(order_book.cancel_order(stop_id).unwrap().id, stop_id);
+        assert!(order_book.stops.is_empty());
+    }
+
+    #[test]
+    fn test_post_only_order_that_crosses_is_rejected() {
+        let mut order_book = OrderBook::new();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(100.0), dec!(1.0)))
+            .unwrap();
+
+        let mut post_only = create_test_order(Side::Buy, dec!(100.0), dec!(1.0));
+        post_only.post_only = true;
+        let result = order_book.match_order(post_only);
+
+        assert_eq!(result.status, OrderStatus::Rejected);
+        assert!(result.trades.is_empty());
+        assert!(order_book.bids.is_empty());
+        assert_eq!(
+            order_book.asks.get(&dec!(100.0)).unwrap()[0].quantity,
+            dec!(1.0)
+        );
+    }
+
+    #[test]
+    fn test_reduce_only_order_takes_and_cancels_remainder() {
+        let mut order_book = OrderBook::new();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(100.0), dec!(1.0)))
+            .unwrap();
+
+        let mut reduce_only = create_test_order(Side::Buy, dec!(100.0), dec!(3.0));
+        reduce_only.reduce_only = true;
+        let result = order_book.match_order(reduce_only);
+
+        assert_eq!(result.status, OrderStatus::PartiallyFilled);
+        assert_eq!(result.filled_quantity, dec!(1.0));
+        assert!(order_book.bids.is_empty());
+        assert!(order_book.asks.is_empty());
+    }
+
+    #[test]
+    fn test_reduce_only_order_that_would_rest_is_cancelled() {
+        let mut order_book = OrderBook::new();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(101.0), dec!(1.0)))
+            .unwrap();
+
+        let mut reduce_only = create_test_order(Side::Buy, dec!(100.0), dec!(1.0));
+        reduce_only.reduce_only = true;
+        let id = reduce_only.id;
+        let result = order_book.match_order(reduce_only);
+
+        assert_eq!(result.status, OrderStatus::Rejected);
+        assert!(result.trades.is_empty());
+        assert!(order_book.bids.is_empty());
+        assert!(order_book.find_order(id).is_none());
+        assert_eq!(order_book.best_ask(), Some(dec!(101.0)));
+    }
+
+    #[test]
+    fn test_post_only_order_that_does_not_cross_rests() {
+        let mut order_book = OrderBook::new();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(100.0), dec!(1.0)))
+            .unwrap();
+
+        let mut post_only = create_test_order(Side::Buy, dec!(99.0), dec!(1.0));
+        post_only.post_only = true;
+        let result = order_book.match_order(post_only);
+
+        assert_eq!(result.status, OrderStatus::Resting);
+        assert_eq!(order_book.best_bid(), Some(dec!(99.0)));
+    }
+
+    #[test]
+    fn test_total_volume_at_or_better() {
+        let mut order_book = OrderBook::new();
+        for (price, quantity) in [
+            (dec!(101.0), dec!(1.0)),
+            (dec!(102.0), dec!(2.0)),
+            (dec!(104.0), dec!(4.0)),
+        ] {
+            order_book
+                .add_order(create_test_order(Side::Sell, price, quantity))
+                .unwrap();
+        }
+        for (price, quantity) in [
+            (dec!(99.0), dec!(1.0)),
+            (dec!(98.0), dec!(2.0)),
+            (dec!(96.0), dec!(4.0)),
+        ] {
+            order_book
+                .add_order(create_test_order(Side::Buy, price, quantity))
+                .unwrap();
+        }
+
+        // A buy taker at P reaches asks priced at or below P.
+        assert_eq!(
+            order_book.total_volume_at_or_better(Side::Sell, dec!(100.0)),
+            Decimal::ZERO
+        );
+        assert_eq!(
+            order_book.total_volume_at_or_better(Side::Sell, dec!(101.0)),
+            dec!(1.0)
+        );
+        assert_eq!(
+            order_book.total_volume_at_or_better(Side::Sell, dec!(103.0)),
+            dec!(3.0)
+        );
+        assert_eq!(
+            order_book.total_volume_at_or_better(Side::Sell, dec!(200.0)),
+            dec!(7.0)
+        );
+
+        // A sell taker at P reaches bids priced at or above P.
+        assert_eq!(
+            order_book.total_volume_at_or_better(Side::Buy, dec!(100.0)),
+            Decimal::ZERO
+        );
+        assert_eq!(
+            order_book.total_volume_at_or_better(Side::Buy, dec!(98.0)),
+            dec!(3.0)
+        );
+        assert_eq!(
+            order_book.total_volume_at_or_better(Side::Buy, dec!(1.0)),
+            dec!(7.0)
+        );
+    }
+
+    #[test]
+    fn test_iceberg_shows_slice_and_replenishes() {
+        let mut order_book = OrderBook::new();
+        let mut iceberg = create_test_order(Side::Sell, dec!(100.0), dec!(100.0));
+        iceberg.display_quantity = Some(dec!(10.0));
+        let iceberg_id = iceberg.id;
+        order_book.add_order(iceberg).unwrap();
+        let other = create_test_order(Side::Sell, dec!(100.0), dec!(5.0));
+        let other_id = other.id;
+        order_book.add_order(other).unwrap();
+
+        assert_eq!(order_book.depth(1).asks[0].total_quantity, dec!(15.0));
+
+        // The first taker only reaches the shown slice, then the iceberg
+        // replenishes behind the other maker.
+        let result = order_book.match_order(create_market_order(Side::Buy, dec!(12.0)));
+        let fills: Vec<(Uuid, Decimal)> = result
+            .trades
+            .iter()
+            .map(|t| (t.maker_order_id, t.quantity))
+            .collect();
+        assert_eq!(fills, vec![(iceberg_id, dec!(10.0)), (other_id, dec!(2.0))]);
+        let level = order_book.asks.get(&dec!(100.0)).unwrap();
+        assert_eq!(level[0].id, other_id);
+        assert_eq!(level[1].id, iceberg_id);
+        assert_eq!(level[1].quantity, dec!(90.0));
+        assert_eq!(order_book.depth(1).asks[0].total_quantity, dec!(13.0));
+
+        // It keeps filling slice by slice until the reserve runs out.
+        order_book.cancel_order(other_id).unwrap();
+        let result = order_book.match_order(create_market_order(Side::Buy, dec!(100.0)));
+        assert_eq!(result.trades.len(), 9);
+        assert!(result.trades.iter().all(|t| t.quantity == dec!(10.0)));
+        assert_eq!(result.filled_quantity, dec!(90.0));
+        assert!(order_book.asks.is_empty());
+    }
+
+    #[test]
+    fn test_integer_amounts_match() {
+        // Prices in cents, quantities in whole lots.
+        let int_order = |side: Side, price: i64, quantity: i64| {
+            Order::test_limit(side, price, quantity, Utc::now())
+        };
+
+        let mut order_book: OrderBook<i64> = OrderBook::new();
+        order_book
+            .add_order(int_order(Side::Sell, 10_050, 3))
+            .unwrap();
+        order_book
+            .add_order(int_order(Side::Sell, 10_100, 5))
+            .unwrap();
+        order_book
+            .add_order(int_order(Side::Buy, 9_950, 2))
+            .unwrap();
+        assert_eq!(order_book.spread(), Some(100));
+        assert_eq!(order_book.mid_price(), Some(10_000));
+
+        let result = order_book.match_order(int_order(Side::Buy, 10_100, 4));
+
+        assert_eq!(result.status, OrderStatus::Filled);
+        let fills: Vec<(i64, i64)> = result
+            .trades
+            .iter()
+            .map(|t| (t.price, t.quantity))
+            .collect();
+        assert_eq!(fills, vec![(10_050, 3), (10_100, 1)]);
+        assert_eq!(order_book.asks.get(&10_100).unwrap()[0].quantity, 4);
+        assert_eq!(order_book.depth(1).bids[0].total_quantity, 2);
+    }
+
+    #[test]
+    fn test_default_order_book_uses_decimal() {
+        let mut order_book = DefaultOrderBook::new();
+        order_book
+            .add_order(create_test_order(Side::Buy, dec!(100.5), dec!(1.0)))
+            .unwrap();
+        assert_eq!(order_book.best_bid(), Some(dec!(100.5)));
+    }
+
+    #[test]
+    fn test_matching_policy_parses_variant_names() {
+        assert_eq!("Fifo".parse(), Ok(MatchingPolicy::Fifo));
+        assert_eq!("ProRata".parse(), Ok(MatchingPolicy::ProRata));
+        assert!("pro-rata".parse::<MatchingPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_pricing_mode_parses_variant_names() {
+        assert_eq!("MakerPrice".parse(), Ok(PricingMode::MakerPrice));
+        assert_eq!("Midpoint".parse(), Ok(PricingMode::Midpoint));
+        assert!("midpoint".parse::<PricingMode>().is_err());
+    }
+
+    #[test]
+    fn test_level_tiebreaker_parses_variant_names() {
+        assert_eq!(
+            "InsertionOrder".parse(),
+            Ok(LevelTiebreaker::InsertionOrder)
+        );
+        assert_eq!("LargerFirst".parse(), Ok(LevelTiebreaker::LargerFirst));
+        assert!("larger".parse::<LevelTiebreaker>().is_err());
+    }
+
+    #[test]
+    fn test_pro_rata_splits_partial_fill_by_size() {
+        let mut order_book = OrderBook::with_policy(MatchingPolicy::ProRata);
+        let makers: Vec<Order> = [dec!(10), dec!(20), dec!(30)]
+            .into_iter()
+            .map(|quantity| create_test_order(Side::Sell, dec!(100.0), quantity))
+            .collect();
+        let maker_ids: Vec<Uuid> = makers.iter().map(|o| o.id).collect();
+        for maker in makers {
+            order_book.add_order(maker).unwrap();
+        }
+
+        let result = order_book.match_order(create_market_order(Side::Buy, dec!(30)));
+
+        let fills: Vec<(Uuid, Decimal)> = result
+            .trades
+            .iter()
+            .map(|t| (t.maker_order_id, t.quantity))
+            .collect();
+        assert_eq!(
+            fills,
+            vec![
+                (maker_ids[0], dec!(5)),
+                (maker_ids[1], dec!(10)),
+                (maker_ids[2], dec!(15)),
+            ]
+        );
+        let remaining: Vec<Decimal> = order_book.asks[&dec!(100.0)]
+            .iter()
+            .map(|o| o.quantity)
+            .collect();
+        assert_eq!(remaining, vec![dec!(5), dec!(10), dec!(15)]);
+    }
+
+    #[test]
+    fn test_pro_rata_rounding_leftover_goes_to_oldest() {
+        // 2 shared by three equal makers at whole-unit precision: each share
+        // of 0.66.. rounds down to 0, and the leftover fills in queue order.
+        assert_eq!(
+            allocate_pro_rata(dec!(2), &[dec!(1), dec!(1), dec!(1)]),
+            vec![dec!(1), dec!(1), dec!(0)]
+        );
+        assert_eq!(
+            allocate_pro_rata(dec!(1.0), &[dec!(1), dec!(1), dec!(1)]),
+            vec![dec!(0.4), dec!(0.3), dec!(0.3)]
+        );
+        assert_eq!(allocate_pro_rata(7_i64, &[3, 6]), vec![3, 4]);
+    }
+
+    #[test]
+    fn test_pro_rata_taker_clearing_level_fills_everything() {
+        let mut order_book = OrderBook::with_policy(MatchingPolicy::ProRata);
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(100.0), dec!(1)))
+            .unwrap();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(100.0), dec!(2)))
+            .unwrap();
+
+        let result = order_book.match_order(create_market_order(Side::Buy, dec!(3)));
+
+        assert_eq!(result.status, OrderStatus::Filled);
+        assert!(order_book.asks.is_empty());
+    }
+
+    #[test]
+    fn test_amend_shrinking_keeps_priority() {
+        let mut order_book = OrderBook::new();
+        let first = create_test_order(Side::Buy, dec!(100.0), dec!(5.0));
+        let first_id = first.id;
+        order_book.add_order(first).unwrap();
+        order_book
+            .add_order(create_test_order(Side::Buy, dec!(100.0), dec!(1.0)))
+            .unwrap();
+
+        let amended = order_book
+            .amend_order(first_id, dec!(100.0), dec!(2.0))
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(amended.quantity, dec!(2.0));
+        let level = &order_book.bids[&dec!(100.0)];
+        assert_eq!(level[0].id, first_id);
+        assert_eq!(level[0].quantity, dec!(2.0));
+    }
+
+    #[test]
+    fn test_amend_growing_or_repricing_loses_priority() {
+        let mut order_book = OrderBook::new();
+        let first = create_test_order(Side::Buy, dec!(100.0), dec!(1.0));
+        let first_id = first.id;
+        order_book.add_order(first).unwrap();
+        let second = create_test_order(Side::Buy, dec!(100.0), dec!(1.0));
+        let second_id = second.id;
+        order_book.add_order(second).unwrap();
+        order_book
+            .add_order(create_test_order(Side::Buy, dec!(99.0), dec!(1.0)))
+            .unwrap();
+
+        order_book
+            .amend_order(first_id, dec!(100.0), dec!(3.0))
+            .unwrap()
+            .unwrap();
+        let level = &order_book.bids[&dec!(100.0)];
+        assert_eq!(level[0].id, second_id);
+        assert_eq!(level[1].id, first_id);
+
+        order_book
+            .amend_order(second_id, dec!(99.0), dec!(1.0))
+            .unwrap()
+            .unwrap();
+        assert_eq!(order_book.bids[&dec!(99.0)].back().unwrap().id, second_id);
+        assert_eq!(order_book.bids[&dec!(100.0)].len(), 1);
+    }
+
+    #[test]
+    fn test_amend_rejects_crossing_and_unknown_orders() {
+        let mut order_book = OrderBook::new();
+        let bid = create_test_order(Side::Buy, dec!(99.0), dec!(1.0));
+        let bid_id = bid.id;
+        order_book.add_order(bid).unwrap();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(101.0), dec!(1.0)))
+            .unwrap();
+
+        assert_eq!(
+            order_book
+                .amend_order(bid_id, dec!(101.0), dec!(1.0))
+                .unwrap_err(),
+            OrderBookError::WouldCross {
+                price: dec!(101.0),
+                opposite: dec!(101.0),
+            }
+        );
+        assert_eq!(order_book.best_bid(), Some(dec!(99.0)));
+        assert!(
+            order_book
+                .amend_order(Uuid::new_v4(), dec!(99.0), dec!(1.0))
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_amend_into_a_full_side_keeps_the_original() {
+        let mut order_book = OrderBook::new();
+        order_book.max_price_levels_per_side = 2;
+        order_book
+            .add_order(create_test_order(Side::Buy, dec!(99.0), dec!(1.0)))
+            .unwrap();
+        let bid = create_test_order(Side::Buy, dec!(98.0), dec!(1.0));
+        let bid_id = bid.id;
+        let account_id = bid.account_id;
+        order_book.add_order(bid).unwrap();
+        order_book
+            .add_order(create_test_order(Side::Buy, dec!(98.0), dec!(2.0)))
+            .unwrap();
+
+        assert_eq!(
+            order_book
+                .amend_order(bid_id, dec!(97.0), dec!(1.0))
+                .unwrap_err(),
+            OrderBookError::BookFull {
+                price: dec!(97.0),
+                worst: Some(dec!(98.0)),
+            }
+        );
+        let level = &order_book.bids[&dec!(98.0)];
+        assert_eq!(level.len(), 2);
+        assert_eq!(level[0].id, bid_id);
+        assert_eq!(level[0].quantity, dec!(1.0));
+        assert_eq!(order_book.open_order_count(account_id), 1);
+        assert!(order_book.validate_invariants().is_ok());
+    }
+
+    #[test]
+    fn test_trades_from_one_taker_share_a_timestamp() {
+        let mut order_book = OrderBook::new();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(100.0), dec!(1.0)))
+            .unwrap();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(101.0), dec!(1.0)))
+            .unwrap();
+
+        let result = order_book.match_order(create_market_order(Side::Buy, dec!(2.0)));
+
+        assert_eq!(result.trades.len(), 2);
+        assert_eq!(result.trades[0].timestamp, result.trades[1].timestamp);
+    }
+
+    fn book_with_two_bids() -> (OrderBook, Uuid, Uuid) {
+        let mut order_book = OrderBook::new();
+        let first = create_test_order(Side::Buy, dec!(100.0), dec!(5.0));
+        let second = create_test_order(Side::Buy, dec!(100.0), dec!(1.0));
+        let ids = (first.id, second.id);
+        order_book.add_order(first).unwrap();
+        order_book.add_order(second).unwrap();
+        (order_book, ids.0, ids.1)
+    }
+
+    #[test]
+    fn test_reduce_below_remaining_keeps_priority() {
+        let (mut order_book, first_id, _) = book_with_two_bids();
+
+        let reduced = order_book.reduce_order(first_id, dec!(2.0)).unwrap();
+
+        assert_eq!(reduced.quantity, dec!(3.0));
+        let level = &order_book.bids[&dec!(100.0)];
+        assert_eq!(level[0].id, first_id);
+        assert_eq!(level[0].quantity, dec!(3.0));
+    }
+
+    #[test]
+    fn test_reduce_to_exactly_zero_removes_order_and_level() {
+        let (mut order_book, first_id, second_id) = book_with_two_bids();
+
+        assert_eq!(
+            order_book
+                .reduce_order(first_id, dec!(5.0))
+                .unwrap()
+                .quantity,
+            Decimal::ZERO
+        );
+        assert_eq!(order_book.bids[&dec!(100.0)].len(), 1);
+
+        order_book.reduce_order(second_id, dec!(1.0)).unwrap();
+        assert!(order_book.bids.is_empty());
+    }
+
+    #[test]
+    fn test_reduce_beyond_remaining_cancels() {
+        let (mut order_book, _, second_id) = book_with_two_bids();
+
+        let cancelled = order_book.reduce_order(second_id, dec!(10.0)).unwrap();
+
+        assert_eq!(cancelled.quantity, Decimal::ZERO);
+        assert!(order_book.find_order(second_id).is_none());
+        assert_eq!(
+            order_book.reduce_order(second_id, dec!(1.0)).unwrap_err(),
+            ReduceError::UnknownOrder(second_id)
+        );
+        assert_eq!(
+            order_book
+                .reduce_order(Uuid::new_v4(), Decimal::ZERO)
+                .unwrap_err(),
+            ReduceError::NonPositiveAmount
+        );
+    }
+
+    #[test]
+    fn test_max_price_levels_rejects_worse_and_displaces_worst() {
+        let mut order_book = OrderBook::new();
+        order_book.max_price_levels_per_side = 3;
+        for price in [dec!(100.0), dec!(99.0), dec!(98.0)] {
+            order_book
+                .add_order(create_test_order(Side::Buy, price, dec!(1.0)))
+                .unwrap();
+        }
+        let worst = order_book.bids[&dec!(98.0)][0].id;
+        order_book.take_changes();
+
+        // Joining an existing level is always fine.
+        order_book
+            .add_order(create_test_order(Side::Buy, dec!(99.0), dec!(1.0)))
+            .unwrap();
+
+        assert_eq!(
+            order_book
+                .add_order(create_test_order(Side::Buy, dec!(97.0), dec!(1.0)))
+                .unwrap_err(),
+            OrderBookError::BookFull {
+                price: dec!(97.0),
+                worst: Some(dec!(98.0)),
+            }
+        );
+
+        order_book
+            .add_order(create_test_order(Side::Buy, dec!(99.5), dec!(1.0)))
+            .unwrap();
+        let prices: Vec<Decimal> = order_book.bids.keys().copied().collect();
+        assert_eq!(prices, vec![dec!(99.0), dec!(99.5), dec!(100.0)]);
+        assert!(
+            order_book
+                .take_changes()
+                .iter()
+                .any(|change| matches!(change, OrderChange::Delete(id) if *id == worst))
+        );
+    }
+
+    #[test]
+    fn test_max_price_levels_on_ask_side() {
+        let mut order_book = OrderBook::new();
+        order_book.max_price_levels_per_side = 2;
+        for price in [dec!(101.0), dec!(102.0)] {
+            order_book
+                .add_order(create_test_order(Side::Sell, price, dec!(1.0)))
+                .unwrap();
+        }
+
+        assert!(
+            order_book
+                .check_level_capacity(Side::Sell, dec!(103.0))
+                .is_err()
+        );
+        assert!(
+            order_book
+                .check_level_capacity(Side::Sell, dec!(101.5))
+                .is_ok()
+        );
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(101.5), dec!(1.0)))
+            .unwrap();
+        assert_eq!(order_book.asks.keys().next_back(), Some(&dec!(101.5)));
+    }
+
+    #[test]
+    fn test_imbalance_bid_heavy_ask_heavy_and_balanced() {
+        let mut order_book = OrderBook::new();
+        assert_eq!(order_book.imbalance(5), None);
+
+        order_book
+            .add_order(create_test_order(Side::Buy, dec!(99.0), dec!(3.0)))
+            .unwrap();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(101.0), dec!(1.0)))
+            .unwrap();
+        assert_eq!(order_book.imbalance(5), Some(dec!(0.5)));
+
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(102.0), dec!(5.0)))
+            .unwrap();
+        // (3 - 6) / 9
+        assert_eq!(order_book.imbalance(5), Some(dec!(-3.0) / dec!(9.0)));
+        // Only the first level of each side counts.
+        assert_eq!(order_book.imbalance(1), Some(dec!(0.5)));
+
+        order_book
+            .add_order(create_test_order(Side::Buy, dec!(98.0), dec!(3.0)))
+            .unwrap();
+        assert_eq!(order_book.imbalance(5), Some(Decimal::ZERO));
+    }
+
+    #[test]
+    fn test_snapshot_restore_roundtrip() {
+        let mut order_book = OrderBook::with_policy(MatchingPolicy::ProRata);
+        order_book.max_price_levels_per_side = 10;
+        order_book.fee_schedule = FeeSchedule {
+            maker_bps: dec!(1),
+            taker_bps: dec!(5),
+        };
+        for (side, price, quantity) in [
+            (Side::Buy, dec!(99.0), dec!(1.0)),
+            (Side::Buy, dec!(99.0), dec!(2.0)),
+            (Side::Buy, dec!(98.5), dec!(4.0)),
+            (Side::Sell, dec!(101.0), dec!(3.0)),
+            (Side::Sell, dec!(102.0), dec!(1.5)),
+        ] {
+            order_book
+                .add_order(create_test_order(side, price, quantity))
+                .unwrap();
+        }
+        let mut iceberg = create_test_order(Side::Sell, dec!(101.0), dec!(10.0));
+        iceberg.display_quantity = Some(dec!(2.0));
+        order_book.add_order(iceberg).unwrap();
+        let mut stop = create_test_order(Side::Buy, dec!(105.0), dec!(1.0));
+        stop.order_type = OrderType::StopLimit {
+            stop_price: dec!(104.0),
+        };
+        order_book.add_order(stop).unwrap();
+        // Leave a partially filled maker behind.
+        order_book.match_order(create_market_order(Side::Sell, dec!(0.5)));
+
+        let json = serde_json::to_string(&order_book.snapshot()).unwrap();
+        assert!(json.contains(r#""price":"99.0""#));
+        let mut restored = OrderBook::restore(serde_json::from_str(&json).unwrap());
+
+        assert_eq!(restored.depth(usize::MAX), order_book.depth(usize::MAX));
+        let ids = |book: &OrderBook| book.orders().map(|order| order.id).collect::<Vec<_>>();
+        assert_eq!(ids(&restored), ids(&order_book));
+        assert_eq!(restored.matching_policy, MatchingPolicy::ProRata);
+        assert_eq!(restored.max_price_levels_per_side, 10);
+        assert_eq!(restored.fee_schedule, order_book.fee_schedule);
+        assert!(restored.take_changes().is_empty());
+    }
+
+    #[test]
+    fn test_open_order_count_follows_adds_fills_and_cancels() {
+        let account_id = Uuid::new_v4();
+        let order_for = |side, price, quantity| Order {
+            account_id,
+            ..create_test_order(side, price, quantity)
+        };
+        let mut order_book = OrderBook::new();
+        let first = order_for(Side::Sell, dec!(100.0), dec!(1.0));
+        let second = order_for(Side::Sell, dec!(101.0), dec!(2.0));
+        let third = order_for(Side::Sell, dec!(102.0), dec!(1.0));
+        let third_id = third.id;
+        let mut stop = order_for(Side::Buy, dec!(110.0), dec!(1.0));
+        stop.order_type = OrderType::StopLimit {
+            stop_price: dec!(105.0),
+        };
+        let stop_id = stop.id;
+        for order in [first, second, third, stop] {
+            order_book.add_order(order).unwrap();
+        }
+        assert_eq!(order_book.open_order_count(account_id), 4);
+
+        // Fills the first order and half of the second.
+        order_book.match_order(create_market_order(Side::Buy, dec!(2.0)));
+        assert_eq!(order_book.open_order_count(account_id), 3);
+
+        order_book.cancel_order(third_id).unwrap();
+        order_book.cancel_order(stop_id).unwrap();
+        assert_eq!(order_book.open_order_count(account_id), 1);
+
+        order_book.match_order(create_market_order(Side::Buy, dec!(1.0)));
+        assert_eq!(order_book.open_order_count(account_id), 0);
+    }
+
+    #[test]
+    fn test_open_order_count_after_pro_rata_fill() {
+        let account_id = Uuid::new_v4();
+        let mut order_book = OrderBook::with_policy(MatchingPolicy::ProRata);
+        for quantity in [dec!(1.0), dec!(3.0)] {
+            order_book
+                .add_order(Order {
+                    account_id,
+                    ..create_test_order(Side::Buy, dec!(100.0), quantity)
+                })
+                .unwrap();
+        }
+
+        order_book.match_order(create_market_order(Side::Sell, dec!(2.0)));
+        assert_eq!(order_book.open_order_count(account_id), 2);
+        order_book.match_order(create_market_order(Side::Sell, dec!(2.0)));
+        assert_eq!(order_book.open_order_count(account_id), 0);
+    }
+
+    #[test]
+    fn test_cancel_all_for_account_across_levels() {
+        let account_id = Uuid::new_v4();
+        let mine = |side, price| Order {
+            account_id,
+            ..create_test_order(side, price, dec!(1.0))
+        };
+        let mut order_book = OrderBook::new();
+        let other_bid = create_test_order(Side::Buy, dec!(99.0), dec!(1.0));
+        let other_id = other_bid.id;
+        let mut stop = mine(Side::Buy, dec!(110.0));
+        stop.order_type = OrderType::StopLimit {
+            stop_price: dec!(105.0),
+        };
+        for order in [
+            mine(Side::Buy, dec!(99.0)),
+            other_bid,
+            mine(Side::Buy, dec!(98.0)),
+            mine(Side::Sell, dec!(101.0)),
+            mine(Side::Sell, dec!(102.0)),
+            stop,
+        ] {
+            order_book.add_order(order).unwrap();
+        }
+        order_book.take_changes();
+
+        let cancelled = order_book.cancel_all_for_account(account_id);
+        let prices: Vec<Option<Decimal>> = cancelled.iter().map(|o| o.price).collect();
+        assert_eq!(
+            prices,
+            vec![
+                Some(dec!(99.0)),
+                Some(dec!(98.0)),
+                Some(dec!(101.0)),
+                Some(dec!(102.0)),
+                Some(dec!(110.0)),
+            ]
+        );
+
+        assert_eq!(order_book.bids.len(), 1);
+        assert_eq!(order_book.bids[&dec!(99.0)][0].id, other_id);
+        assert!(order_book.asks.is_empty());
+        assert!(order_book.stops.is_empty());
+        assert_eq!(order_book.open_order_count(account_id), 0);
+        assert_eq!(order_book.take_changes().len(), 5);
+        assert!(order_book.cancel_all_for_account(account_id).is_empty());
+    }
+
+    #[test]
+    fn test_gtd_order_matches_before_expiry_and_is_swept_after() {
+        let now = Utc::now();
+        let gtd_sell = |quantity| Order {
+            time_in_force: TimeInForce::Gtd {
+                expires_at: now + chrono::Duration::minutes(5),
+            },
+            ..create_test_order(Side::Sell, dec!(100.0), quantity)
+        };
+        let mut order_book = OrderBook::new();
+        let maker = gtd_sell(dec!(3.0));
+        let maker_id = maker.id;
+        order_book.add_order(maker).unwrap();
+
+        let before = order_book.match_order_at(
+            create_market_order(Side::Buy, dec!(1.0)),
+            now + chrono::Duration::minutes(4),
+        );
+        assert_eq!(before.trades[0].maker_order_id, maker_id);
+        assert!(
+            order_book
+                .expire_orders(now + chrono::Duration::minutes(4))
+                .is_empty()
+        );
+
+        // Past the expiry the order is gone before the market order looks.
+        let after = order_book.match_order_at(
+            create_market_order(Side::Buy, dec!(1.0)),
+            now + chrono::Duration::minutes(5),
+        );
+        assert!(after.trades.is_empty());
+        assert!(order_book.asks.is_empty());
+        assert!(order_book.find_order(maker_id).is_none());
+    }
+
+    #[test]
+    fn test_expire_orders_returns_expired_only() {
+        let now = Utc::now();
+        let mut order_book = OrderBook::new();
+        let mut expiring = create_test_order(Side::Buy, dec!(99.0), dec!(1.0));
+        expiring.time_in_force = TimeInForce::Gtd {
+            expires_at: now + chrono::Duration::seconds(10),
+        };
+        let expiring_id = expiring.id;
+        let later = Order {
+            time_in_force: TimeInForce::Gtd {
+                expires_at: now + chrono::Duration::seconds(60),
+            },
+            ..create_test_order(Side::Buy, dec!(99.0), dec!(1.0))
+        };
+        order_book.add_order(expiring).unwrap();
+        order_book.add_order(later).unwrap();
+        order_book
+            .add_order(create_test_order(Side::Buy, dec!(98.0), dec!(1.0)))
+            .unwrap();
+        order_book.take_changes();
+
+        let expired = order_book.expire_orders(now + chrono::Duration::seconds(30));
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].id, expiring_id);
+        assert_eq!(order_book.orders().count(), 2);
+        assert!(matches!(
+            order_book.take_changes().as_slice(),
+            [OrderChange::Delete(id)] if *id == expiring_id
+        ));
+
+        assert_eq!(
+            order_book
+                .expire_orders(now + chrono::Duration::seconds(60))
+                .len(),
+            1
+        );
+        assert!(
+            order_book
+                .expire_orders(now + chrono::Duration::days(1))
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_orders_past_max_age_are_swept() {
+        let now = Utc::now();
+        let mut order_book = OrderBook::new();
+        let stale = Order {
+            timestamp: now - chrono::Duration::minutes(10),
+            ..create_test_order(Side::Buy, dec!(99.0), dec!(1.0))
+        };
+        let stale_id = stale.id;
+        let fresh = Order {
+            timestamp: now - chrono::Duration::minutes(1),
+            ..create_test_order(Side::Buy, dec!(98.0), dec!(1.0))
+        };
+        let fresh_id = fresh.id;
+        order_book.add_order(stale).unwrap();
+        order_book.add_order(fresh).unwrap();
+        assert!(order_book.expire_orders(now).is_empty());
+
+        // Orders already resting are held to a newly set age.
+        order_book.set_max_order_age(Some(Duration::from_secs(5 * 60)));
+        let expired = order_book.expire_orders(now);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].id, stale_id);
+        assert!(order_book.find_order(fresh_id).is_some());
+
+        let expired = order_book.expire_orders(now + chrono::Duration::minutes(4));
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].id, fresh_id);
+    }
+
+    #[test]
+    fn test_expired_gtd_taker_is_rejected() {
+        let now = Utc::now();
+        let mut order_book = OrderBook::new();
+        order_book
+            .add_order(create_test_order(Side::Sell, dec!(100.0), dec!(1.0)))
+            .unwrap();
+        let mut taker = create_test_order(Side::Buy, dec!(100.0), dec!(1.0));
+        taker.time_in_force = TimeInForce::Gtd { expires_at: now };
+
+        let result = order_book.match_order_at(taker, now);
+        assert_eq!(result.status, OrderStatus::Rejected);
+        assert_eq!(order_book.asks.len(), 1);
+    }
+}
@@ -0,0 +1,237 @@
+use crate::AppState;
+use crate::matching_engine::{OrderBook, Trade};
+use axum::{extract::State, http::header, response::IntoResponse};
+use rust_decimal::Decimal;
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal_macros::dec;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Volume is counted in millionths so it fits an atomic integer.
+const MICROS_PER_UNIT: Decimal = dec!(1000000);
+
+/// Upper bounds of the latency buckets, in microseconds. The last bucket
+/// takes everything slower.
+const LATENCY_BUCKETS_MICROS: [u64; 14] = [
+    1,
+    2,
+    5,
+    10,
+    20,
+    50,
+    100,
+    200,
+    500,
+    1_000,
+    2_000,
+    5_000,
+    10_000,
+    u64::MAX,
+];
+
+/// Quantiles `render` reports for each latency histogram.
+const LATENCY_QUANTILES: [f64; 2] = [0.5, 0.99];
+
+/// Counts of samples per fixed bucket, recorded lock-free so timing a hot
+/// path costs a few atomic adds. Quantiles are only as precise as the
+/// buckets: each is reported as the upper bound of the bucket it falls in.
+#[derive(Debug, Default)]
+pub struct LatencyHistogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS_MICROS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl LatencyHistogram {
+    pub fn record(&self, elapsed: Duration) {
+        let micros = u64::try_from(elapsed.as_micros()).unwrap_or(u64::MAX);
+        let bucket = LATENCY_BUCKETS_MICROS
+            .iter()
+            .position(|&bound| micros <= bound)
+            .unwrap_or(LATENCY_BUCKETS_MICROS.len() - 1);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// The upper bound in microseconds of the bucket holding the `q`
+    /// quantile, or `None` before any sample.
+    pub fn quantile_micros(&self, q: f64) -> Option<u64> {
+        let counts: Vec<u64> = self
+            .buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .collect();
+        let total: u64 = counts.iter().sum();
+        if total == 0 {
+            return None;
+        }
+        // The rank of the sample the quantile falls on, counting from 1.
+        let rank = ((q * total as f64).ceil() as u64).clamp(1, total);
+        let mut seen = 0;
+        for (count, bound) in counts.into_iter().zip(LATENCY_BUCKETS_MICROS) {
+            seen += count;
+            if seen >= rank {
+                return Some(bound);
+            }
+        }
+        None
+    }
+}
+
+/// Process-wide counters, bumped lock-free from the request handlers.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    orders_received: AtomicU64,
+    trades_executed: AtomicU64,
+    volume_traded_micros: AtomicU64,
+    /// How long matching one order takes, stops it sets off included.
+    pub match_latency: LatencyHistogram,
+}
+
+impl Metrics {
+    pub fn record_order(&self) {
+        self.orders_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_trade(&self, trade: &Trade) {
+        let micros = (trade.quantity * MICROS_PER_UNIT)
+            .trunc()
+            .to_u64()
+            .unwrap_or(0);
+        self.trades_executed.fetch_add(1, Ordering::Relaxed);
+        self.volume_traded_micros
+            .fetch_add(micros, Ordering::Relaxed);
+    }
+
+    /// Formats the counters, plus resting depth per book side, in the
+    /// Prometheus text exposition format.
+    pub fn render(&self, order_books: &HashMap<String, OrderBook>) -> String {
+        let volume =
+            Decimal::from(self.volume_traded_micros.load(Ordering::Relaxed)) / MICROS_PER_UNIT;
+        let mut out = String::new();
+
+        write_metric(
+            &mut out,
+            "mini_dex_orders_received_total",
+            "counter",
+            "Orders accepted for matching.",
+        );
+        let _ = writeln!(
+            out,
+            "mini_dex_orders_received_total {}",
+            self.orders_received.load(Ordering::Relaxed)
+        );
+        write_metric(
+            &mut out,
+            "mini_dex_trades_executed_total",
+            "counter",
+            "Trades executed.",
+        );
+        let _ = writeln!(
+            out,
+            "mini_dex_trades_executed_total {}",
+            self.trades_executed.load(Ordering::Relaxed)
+        );
+        write_metric(
+            &mut out,
+            "mini_dex_volume_traded_total",
+            "counter",
+            "Base quantity traded.",
+        );
+        let _ = writeln!(out, "mini_dex_volume_traded_total {}", volume.normalize());
+        write_metric(
+            &mut out,
+            "mini_dex_book_depth",
+            "gauge",
+            "Resting quantity per book side.",
+        );
+
+        let mut symbols: Vec<&String> = order_books.keys().collect();
+        symbols.sort();
+        for symbol in symbols {
+            let order_book = &order_books[symbol];
+            for (side, levels) in [("buy", &order_book.bids), ("sell", &order_book.asks)] {
+                let depth: Decimal = levels.values().flatten().map(|o| o.quantity).sum();
+                let _ = writeln!(
+                    out,
+                    "mini_dex_book_depth{{symbol=\"{}\",side=\"{}\"}} {}",
+                    symbol,
+                    side,
+                    depth.normalize()
+                );
+            }
+        }
+
+        write_metric(
+            &mut out,
+            "mini_dex_match_latency_micros",
+            "summary",
+            "Time to match one order, in microseconds, to bucket precision.",
+        );
+        for q in LATENCY_QUANTILES {
+            if let Some(micros) = self.match_latency.quantile_micros(q) {
+                let _ = writeln!(
+                    out,
+                    "mini_dex_match_latency_micros{{quantile=\"{}\"}} {}",
+                    q, micros
+                );
+            }
+        }
+        let _ = writeln!(
+            out,
+            "mini_dex_match_latency_micros_sum {}",
+            self.match_latency.sum_micros.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "mini_dex_match_latency_micros_count {}",
+            self.match_latency.count()
+        );
+        out
+    }
+}
+
+fn write_metric(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+pub(crate) async fn metrics_handler(State(state): State<AppState>) -> impl IntoResponse {
+    let body = state.metrics.render(&state.order_books.read().unwrap());
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latency_histogram_aggregates_samples() {
+        let histogram = LatencyHistogram::default();
+        assert_eq!(histogram.quantile_micros(0.5), None);
+
+        // 98 fast samples, one at 150µs and one far past the last bound.
+        for _ in 0..98 {
+            histogram.record(Duration::from_micros(3));
+        }
+        histogram.record(Duration::from_micros(150));
+        histogram.record(Duration::from_secs(1));
+
+        assert_eq!(histogram.count(), 100);
+        assert_eq!(histogram.quantile_micros(0.5), Some(5));
+        assert_eq!(histogram.quantile_micros(0.98), Some(5));
+        assert_eq!(histogram.quantile_micros(0.99), Some(200));
+        assert_eq!(histogram.quantile_micros(1.0), Some(u64::MAX));
+        assert_eq!(
+            histogram.sum_micros.load(Ordering::Relaxed),
+            98 * 3 + 150 + 1_000_000
+        );
+    }
+}
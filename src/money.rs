@@ -0,0 +1,39 @@
+use rust_decimal::{Decimal, RoundingStrategy};
+
+/// Decimal places computed prices such as averages and mid prices are
+/// reported at.
+pub const PRICE_SCALE: u32 = 8;
+/// Decimal places fees are charged at.
+pub const FEE_SCALE: u32 = 8;
+/// Quantities at or below this, `1e-8`, are too small to trade and count as
+/// nothing left.
+pub const QUANTITY_EPSILON: Decimal = Decimal::from_parts(1, 0, 0, false, 8);
+
+/// Rounds `d` to at most `scale` decimal places, sending exact halves to
+/// the even neighbour so that rounding errors do not drift one way over
+/// many trades. Values already that precise are left as they are.
+pub fn round_to_scale(d: Decimal, scale: u32) -> Decimal {
+    d.round_dp_with_strategy(scale, RoundingStrategy::MidpointNearestEven)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_halves_round_to_even() {
+        assert_eq!(round_to_scale(dec!(2.5), 0), dec!(2));
+        assert_eq!(round_to_scale(dec!(3.5), 0), dec!(4));
+        assert_eq!(round_to_scale(dec!(-2.5), 0), dec!(-2));
+        assert_eq!(round_to_scale(dec!(0.125), 2), dec!(0.12));
+        assert_eq!(round_to_scale(dec!(0.135), 2), dec!(0.14));
+    }
+
+    #[test]
+    fn test_non_halves_round_to_nearest_and_short_values_are_kept() {
+        assert_eq!(round_to_scale(dec!(2.51), 0), dec!(3));
+        assert_eq!(round_to_scale(dec!(2.49), 0), dec!(2));
+        assert_eq!(round_to_scale(dec!(100.375), PRICE_SCALE), dec!(100.375));
+    }
+}
@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// Orders per second each account may send; 0 turns the limit off.
+pub const DEFAULT_MAX_ORDERS_PER_SEC: f64 = 0.0;
+/// How many tracked accounts make `try_acquire_at` drop the idle ones.
+const PRUNE_AT: usize = 10_000;
+
+/// Tokens one account has left, as of `updated`.
+#[derive(Debug, Clone, Copy)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Per-account token buckets. Each account may send `burst` orders at
+/// once, and its bucket refills at `rate` orders per second after that.
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: HashMap<Uuid, TokenBucket>,
+}
+
+impl RateLimiter {
+    /// A burst below one order would turn every order away, so it is
+    /// raised to one.
+    pub fn new(rate: f64, burst: f64) -> Self {
+        RateLimiter {
+            rate,
+            burst: burst.max(1.0),
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Takes a token for one order from `account_id`, if it has one.
+    pub fn try_acquire(&mut self, account_id: Uuid) -> bool {
+        self.try_acquire_at(account_id, Instant::now()).is_ok()
+    }
+
+    /// `try_acquire` as of `now`. An account out of tokens gets back how
+    /// long until its next one.
+    pub fn try_acquire_at(&mut self, account_id: Uuid, now: Instant) -> Result<(), Duration> {
+        if self.buckets.len() >= PRUNE_AT {
+            self.prune(now);
+        }
+        let (rate, burst) = (self.rate, self.burst);
+        let bucket = self.buckets.entry(account_id).or_insert(TokenBucket {
+            tokens: burst,
+            updated: now,
+        });
+        refill(bucket, rate, burst, now);
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+        }
+    }
+
+    /// Forgets accounts whose bucket has filled up again, since a fresh
+    /// bucket treats them the same.
+    fn prune(&mut self, now: Instant) {
+        let (rate, burst) = (self.rate, self.burst);
+        self.buckets.retain(|_, bucket| {
+            refill(bucket, rate, burst, now);
+            bucket.tokens < burst
+        });
+    }
+}
+
+fn refill(bucket: &mut TokenBucket, rate: f64, burst: f64, now: Instant) {
+    let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+    bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+    bucket.updated = now;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_burst_passes_and_the_next_order_waits() {
+        let mut limiter = RateLimiter::new(2.0, 3.0);
+        let account_id = Uuid::new_v4();
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert_eq!(limiter.try_acquire_at(account_id, start), Ok(()));
+        }
+        assert_eq!(
+            limiter.try_acquire_at(account_id, start),
+            Err(Duration::from_millis(500))
+        );
+        // Other accounts have buckets of their own.
+        assert!(limiter.try_acquire_at(Uuid::new_v4(), start).is_ok());
+
+        let later = start + Duration::from_millis(500);
+        assert_eq!(limiter.try_acquire_at(account_id, later), Ok(()));
+        assert!(limiter.try_acquire_at(account_id, later).is_err());
+    }
+
+    #[test]
+    fn test_idle_accounts_are_pruned() {
+        let mut limiter = RateLimiter::new(1.0, 1.0);
+        let start = Instant::now();
+        for _ in 0..PRUNE_AT {
+            limiter.try_acquire_at(Uuid::new_v4(), start).unwrap();
+        }
+
+        limiter
+            .try_acquire_at(Uuid::new_v4(), start + Duration::from_secs(1))
+            .unwrap();
+
+        assert_eq!(limiter.buckets.len(), 1);
+    }
+}
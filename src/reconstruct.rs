@@ -0,0 +1,356 @@
+use crate::matching_engine::{OrderBook, OrderBookError, OrderResult, ReduceError, Trade};
+use crate::{Order, Side};
+use chrono::{DateTime, Utc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Something that changed a book, logged before it is applied so the book
+/// can be rebuilt as it was at any earlier moment. Triggered stops and
+/// evictions are left out, since replaying the events that caused them
+/// redoes them. Expiry sweeps are logged, since pegs move when they run.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum BookEvent {
+    /// An order passed the pre-trade checks and went to the matching
+    /// engine, as it was before matching.
+    Submitted {
+        order: Order,
+    },
+    Cancelled {
+        order_id: Uuid,
+    },
+    /// Every order of one account was pulled from the book.
+    CancelledAll {
+        account_id: Uuid,
+    },
+    /// Every order resting at one price was pulled from the book.
+    CancelledLevel {
+        side: Side,
+        price: Decimal,
+    },
+    Amended {
+        order_id: Uuid,
+        price: Decimal,
+        quantity: Decimal,
+    },
+    Reduced {
+        order_id: Uuid,
+        by: Decimal,
+    },
+    /// An order rested unmatched to wait for the auction that ends its
+    /// speed-bump batch.
+    Batched {
+        order: Order,
+    },
+    /// A call auction uncrossed the book.
+    Auction,
+    /// The expiry sweep took off the orders due by then.
+    Expired,
+    /// An operator emptied the book.
+    Cleared,
+}
+
+/// What applying one `BookEvent` did, one variant per kind of event.
+#[derive(Debug)]
+pub enum EventOutcome {
+    Submitted(OrderResult),
+    Cancelled(Option<Order>),
+    CancelledAll(Vec<Order>),
+    CancelledLevel(Vec<Order>),
+    Amended(Result<Option<Order>, OrderBookError>),
+    Reduced(Result<Order, ReduceError>),
+    Batched(Result<(), OrderBookError>),
+    Auction(Option<(Decimal, Vec<Trade>)>),
+    Expired(Vec<Order>),
+    Cleared,
+}
+
+impl OrderBook {
+    /// Expires what is due by `at`, applies `event` as of `at`, then moves
+    /// any pegged orders after the book it left. The server changes books
+    /// only through here, so the same events applied to an empty book with
+    /// the same settings always rebuild the same book.
+    pub fn apply_event(&mut self, event: BookEvent, at: DateTime<Utc>) -> EventOutcome {
+        let expired = self.expire_orders(at);
+        let outcome = match event {
+            BookEvent::Submitted { order } => {
+                EventOutcome::Submitted(self.match_order_at(order, at))
+            }
+            BookEvent::Cancelled { order_id } => {
+                EventOutcome::Cancelled(self.cancel_order(order_id))
+            }
+            BookEvent::CancelledAll { account_id } => {
+                EventOutcome::CancelledAll(self.cancel_all_for_account(account_id))
+            }
+            BookEvent::CancelledLevel { side, price } => {
+                EventOutcome::CancelledLevel(self.cancel_level(side, price))
+            }
+            BookEvent::Amended {
+                order_id,
+                price,
+                quantity,
+            } => EventOutcome::Amended(self.amend_order_at(order_id, price, quantity, at)),
+            BookEvent::Reduced { order_id, by } => {
+                EventOutcome::Reduced(self.reduce_order_at(order_id, by, at))
+            }
+            BookEvent::Batched { order } => EventOutcome::Batched(self.add_auction_order(order)),
+            BookEvent::Auction => EventOutcome::Auction(self.run_auction_at(at)),
+            BookEvent::Expired => EventOutcome::Expired(expired),
+            BookEvent::Cleared => {
+                self.clear();
+                EventOutcome::Cleared
+            }
+        };
+        self.reprice_pegged(at);
+        outcome
+    }
+}
+
+/// A `BookEvent` with its place in the exchange-wide event sequence and
+/// the time it hit the book.
+#[derive(Debug, Clone)]
+pub struct RecordedEvent {
+    pub seq: u64,
+    pub symbol: String,
+    pub timestamp: DateTime<Utc>,
+    pub event: BookEvent,
+}
+
+/// Replays the `events` stamped at or before `at` onto `order_book`, in
+/// sequence order, and returns the book as it stood at `at`. The book
+/// should start empty with the settings the events were recorded under;
+/// other settings rebuild a different book.
+pub fn reconstruct_book_at(
+    mut order_book: OrderBook,
+    events: &[RecordedEvent],
+    at: DateTime<Utc>,
+) -> OrderBook {
+    let mut replayed: Vec<&RecordedEvent> = events
+        .iter()
+        .filter(|recorded| recorded.timestamp <= at)
+        .collect();
+    replayed.sort_by_key(|recorded| recorded.seq);
+
+    for recorded in replayed {
+        order_book.apply_event(recorded.event.clone(), recorded.timestamp);
+    }
+    order_book.apply_event(BookEvent::Expired, at);
+
+    // Nothing here needs persisting or broadcasting.
+    order_book.take_changes();
+    order_book.take_deltas("");
+    order_book
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matching_engine::OrderChange;
+    use crate::{OrderType, PegReference, Side, TimeInForce};
+    use chrono::Duration;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_pegged_bid_follows_a_higher_best_bid() {
+        let start = Utc::now() - Duration::hours(1);
+        let at = |seconds| start + Duration::seconds(seconds);
+        let mut order_book = OrderBook::new();
+        order_book.apply_event(
+            BookEvent::Submitted {
+                order: Order::test_limit(Side::Buy, dec!(100.0), dec!(1.0), at(0)),
+            },
+            at(0),
+        );
+        let mut pegged = Order::test_limit(Side::Buy, dec!(0), dec!(2.0), at(1));
+        pegged.order_type = OrderType::Pegged {
+            reference: PegReference::BestBid,
+            offset: dec!(-0.1),
+        };
+        pegged.price = None;
+        let pegged_id = pegged.id;
+        order_book.apply_event(BookEvent::Submitted { order: pegged }, at(1));
+        assert_eq!(order_book.bids[&dec!(99.9)][0].id, pegged_id);
+        order_book.take_changes();
+
+        order_book.apply_event(
+            BookEvent::Submitted {
+                order: Order::test_limit(Side::Buy, dec!(100.5), dec!(1.0), at(2)),
+            },
+            at(2),
+        );
+
+        assert!(!order_book.bids.contains_key(&dec!(99.9)));
+        let repriced = &order_book.bids[&dec!(100.4)][0];
+        assert_eq!(repriced.id, pegged_id);
+        assert_eq!(repriced.timestamp, at(2));
+        assert!(
+            order_book.take_changes().iter().any(
+                |change| matches!(change, OrderChange::Upsert(order) if order.id == pegged_id)
+            )
+        );
+        assert!(order_book.validate_invariants().is_ok());
+    }
+
+    #[test]
+    fn test_orders_expire_before_any_event() {
+        let start = Utc::now() - Duration::hours(1);
+        let at = |seconds| start + Duration::seconds(seconds);
+        let mut gtd = Order::test_limit(Side::Buy, dec!(100.0), dec!(1.0), at(0));
+        gtd.time_in_force = TimeInForce::Gtd { expires_at: at(5) };
+        let ask = Order::test_limit(Side::Sell, dec!(101.0), dec!(1.0), at(1));
+        let ask_id = ask.id;
+        let events = [
+            BookEvent::Submitted { order: gtd },
+            BookEvent::Submitted { order: ask },
+            BookEvent::Amended {
+                order_id: ask_id,
+                price: dec!(100.0),
+                quantity: dec!(1.0),
+            },
+        ];
+        let recorded: Vec<RecordedEvent> = events
+            .into_iter()
+            .zip([0, 1, 10])
+            .enumerate()
+            .map(|(seq, (event, seconds))| RecordedEvent {
+                seq: seq as u64,
+                symbol: "BTC-USD".to_string(),
+                timestamp: at(seconds),
+                event,
+            })
+            .collect();
+
+        let mut order_book = OrderBook::new();
+        for event in &recorded[..2] {
+            order_book.apply_event(event.event.clone(), event.timestamp);
+        }
+        // The bid the amend would cross expired before it arrived.
+        let EventOutcome::Amended(amended) =
+            order_book.apply_event(recorded[2].event.clone(), recorded[2].timestamp)
+        else {
+            panic!("expected an amend outcome");
+        };
+        assert_eq!(amended.unwrap().unwrap().price, Some(dec!(100.0)));
+        assert!(order_book.bids.is_empty());
+
+        let rebuilt = reconstruct_book_at(OrderBook::new(), &recorded, at(10));
+        assert_eq!(rebuilt.asks[&dec!(100.0)][0].id, ask_id);
+        assert!(rebuilt.bids.is_empty());
+    }
+
+    #[test]
+    fn test_reconstruct_at_intermediate_time_differs_from_final_book() {
+        let start = Utc::now() - Duration::hours(1);
+        let at = |seconds| start + Duration::seconds(seconds);
+        let ask = Order::test_limit(Side::Sell, dec!(101.0), dec!(3.0), at(0));
+        let bid = Order::test_limit(Side::Buy, dec!(99.0), dec!(2.0), at(1));
+        let bid_id = bid.id;
+        let events: Vec<RecordedEvent> = [
+            (at(0), BookEvent::Submitted { order: ask }),
+            (at(1), BookEvent::Submitted { order: bid }),
+            (
+                at(2),
+                BookEvent::Submitted {
+                    order: Order::test_limit(Side::Buy, dec!(101.0), dec!(1.0), at(2)),
+                },
+            ),
+            (at(3), BookEvent::Cancelled { order_id: bid_id }),
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(i, (timestamp, event))| RecordedEvent {
+            seq: i as u64 + 1,
+            symbol: "BTC-USD".to_string(),
+            timestamp,
+            event,
+        })
+        .collect();
+
+        let before_trade = reconstruct_book_at(OrderBook::new(), &events, at(1));
+        let after_trade = reconstruct_book_at(OrderBook::new(), &events, at(2));
+        let final_book = reconstruct_book_at(OrderBook::new(), &events, at(10));
+
+        assert_eq!(before_trade.asks[&dec!(101.0)][0].quantity, dec!(3.0));
+        assert_eq!(after_trade.asks[&dec!(101.0)][0].quantity, dec!(2.0));
+        assert_eq!(after_trade.best_bid(), Some(dec!(99.0)));
+        assert_eq!(final_book.best_bid(), None);
+        assert_ne!(after_trade.depth(usize::MAX), final_book.depth(usize::MAX));
+    }
+
+    #[test]
+    fn test_replaying_applied_events_rebuilds_identical_book() {
+        let start = Utc::now() - Duration::hours(1);
+        let at = |seconds| start + Duration::seconds(seconds);
+        let ask = Order::test_limit(Side::Sell, dec!(101.0), dec!(3.0), at(0));
+        let ask_id = ask.id;
+        let mut bid = Order::test_limit(Side::Buy, dec!(99.0), dec!(2.0), at(1));
+        let account_id = bid.account_id;
+        let bid_id = bid.id;
+        let mut second_bid = Order::test_limit(Side::Buy, dec!(98.0), dec!(1.0), at(2));
+        second_bid.account_id = account_id;
+        bid.display_quantity = Some(dec!(1.0));
+        let events = vec![
+            (at(0), BookEvent::Submitted { order: ask }),
+            (at(1), BookEvent::Submitted { order: bid }),
+            (at(2), BookEvent::Submitted { order: second_bid }),
+            (
+                at(3),
+                BookEvent::Submitted {
+                    order: Order::test_limit(Side::Sell, dec!(99.0), dec!(1.5), at(3)),
+                },
+            ),
+            (
+                at(4),
+                BookEvent::Amended {
+                    order_id: ask_id,
+                    price: dec!(100.5),
+                    quantity: dec!(4.0),
+                },
+            ),
+            (
+                at(5),
+                BookEvent::Reduced {
+                    order_id: ask_id,
+                    by: dec!(1.0),
+                },
+            ),
+            (
+                at(6),
+                BookEvent::Batched {
+                    order: Order::test_limit(Side::Buy, dec!(100.5), dec!(1.0), at(6)),
+                },
+            ),
+            (at(7), BookEvent::Auction),
+            (at(8), BookEvent::Cancelled { order_id: bid_id }),
+            (at(9), BookEvent::CancelledAll { account_id }),
+            (
+                at(10),
+                BookEvent::Submitted {
+                    order: Order::test_limit(Side::Buy, dec!(97.0), dec!(2.0), at(10)),
+                },
+            ),
+        ];
+
+        let mut live = OrderBook::new();
+        let mut recorded = Vec::new();
+        for (seq, (timestamp, event)) in events.into_iter().enumerate() {
+            recorded.push(RecordedEvent {
+                seq: seq as u64 + 1,
+                symbol: "BTC-USD".to_string(),
+                timestamp,
+                event: event.clone(),
+            });
+            live.apply_event(event, timestamp);
+        }
+        let rebuilt = reconstruct_book_at(OrderBook::new(), &recorded, at(10));
+
+        let state = |order_book: &OrderBook| serde_json::to_value(order_book.snapshot()).unwrap();
+        assert_eq!(state(&rebuilt), state(&live));
+        assert_eq!(rebuilt.stats.last_price(), live.stats.last_price());
+        assert_eq!(rebuilt.best_ask(), Some(dec!(100.5)));
+        assert_eq!(rebuilt.asks[&dec!(100.5)][0].quantity, dec!(2.0));
+        assert_eq!(rebuilt.best_bid(), Some(dec!(97.0)));
+        assert_eq!(rebuilt.validate_invariants(), Ok(()));
+    }
+}
@@ -0,0 +1,251 @@
+use crate::matching_engine::OrderBook;
+use crate::{Order, OrderType, Side};
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RiskError {
+    /// The order is worth more than a single order may be.
+    NotionalTooLarge { notional: Decimal, limit: Decimal },
+    /// A limit order priced so far through the opposite side that it is
+    /// most likely a mistake, e.g. a stale client price.
+    OutsidePriceBand {
+        price: Decimal,
+        reference: Decimal,
+        band_pct: Decimal,
+    },
+}
+
+impl fmt::Display for RiskError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RiskError::NotionalTooLarge { notional, limit } => write!(
+                f,
+                "order notional {} exceeds the limit of {}",
+                notional, limit
+            ),
+            RiskError::OutsidePriceBand {
+                price,
+                reference,
+                band_pct,
+            } => write!(
+                f,
+                "order price {} is more than {}% through the best opposite price {}",
+                price, band_pct, reference
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RiskError {}
+
+/// Pre-trade checks run before an order reaches the matching engine.
+/// Priced orders are valued at `price * quantity`; market orders at what
+/// they would fill for against `order_book` right now, capped by any quote
+/// budget. Negative prices are valued by their magnitude.
+pub fn check_order(order: &Order, order_book: &OrderBook, limit: Decimal) -> Result<(), RiskError> {
+    let notional = match (&order.order_type, order.price) {
+        (OrderType::Market, _) | (_, None) => {
+            let estimate = order_book.estimate_market_notional(order.side, order.quantity);
+            order
+                .quote_quantity
+                .map_or(estimate, |budget| estimate.min(budget))
+        }
+        (_, Some(price)) => price * order.quantity,
+    }
+    .abs();
+
+    if notional > limit {
+        return Err(RiskError::NotionalTooLarge { notional, limit });
+    }
+    Ok(())
+}
+
+/// Rejects limit orders priced more than `band_pct` percent through the
+/// opposite top of book: buys above the best ask, sells below the best bid.
+/// Orders against an empty side have nothing to compare to and pass.
+pub fn check_price_band(
+    order: &Order,
+    order_book: &OrderBook,
+    band_pct: Decimal,
+) -> Result<(), RiskError> {
+    let (OrderType::Limit | OrderType::Pegged { .. }, Some(price)) =
+        (&order.order_type, order.price)
+    else {
+        return Ok(());
+    };
+
+    // Measured from the magnitude so the band keeps its width below zero.
+    let band = band_pct / dec!(100);
+    let (reference, outside) = match order.side {
+        Side::Buy => match order_book.best_ask() {
+            Some(ask) => (ask, price > ask + ask.abs() * band),
+            None => return Ok(()),
+        },
+        Side::Sell => match order_book.best_bid() {
+            Some(bid) => (bid, price < bid - bid.abs() * band),
+            None => return Ok(()),
+        },
+    };
+
+    if outside {
+        return Err(RiskError::OutsidePriceBand {
+            price,
+            reference,
+            band_pct,
+        });
+    }
+    Ok(())
+}
+
+/// Whether a trade at `price` moved more than `limit_pct` percent away from
+/// the trade before it, a limit move that should halt trading. A previous
+/// price of zero gives nothing to take a percentage of, so never counts.
+pub fn is_limit_move(previous_price: Decimal, price: Decimal, limit_pct: Decimal) -> bool {
+    if previous_price.is_zero() {
+        return false;
+    }
+    (price - previous_price).abs() / previous_price.abs() * dec!(100) > limit_pct
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Side;
+    use chrono::Utc;
+    use rust_decimal_macros::dec;
+
+    fn create_order(
+        order_type: OrderType,
+        side: Side,
+        price: Option<Decimal>,
+        quantity: Decimal,
+    ) -> Order {
+        Order {
+            order_type,
+            price,
+            ..Order::test_limit(side, Decimal::ZERO, quantity, Utc::now())
+        }
+    }
+
+    #[test]
+    fn test_notional_exactly_at_limit_passes() {
+        let order = create_order(OrderType::Limit, Side::Buy, Some(dec!(100.00)), dec!(10));
+        assert!(check_order(&order, &OrderBook::new(), dec!(1000.00)).is_ok());
+    }
+
+    #[test]
+    fn test_notional_one_cent_over_limit_fails() {
+        let order = create_order(OrderType::Limit, Side::Buy, Some(dec!(100.001)), dec!(10));
+        assert_eq!(
+            check_order(&order, &OrderBook::new(), dec!(1000.00)),
+            Err(RiskError::NotionalTooLarge {
+                notional: dec!(1000.01),
+                limit: dec!(1000.00),
+            })
+        );
+    }
+
+    #[test]
+    fn test_market_order_uses_estimated_fill_notional() {
+        let mut order_book = OrderBook::new();
+        order_book
+            .add_order(create_order(
+                OrderType::Limit,
+                Side::Sell,
+                Some(dec!(100)),
+                dec!(1),
+            ))
+            .unwrap();
+        order_book
+            .add_order(create_order(
+                OrderType::Limit,
+                Side::Sell,
+                Some(dec!(110)),
+                dec!(1),
+            ))
+            .unwrap();
+
+        // Sweeps 1 @ 100 and 1 @ 110; the third unit has nothing to fill.
+        let order = create_order(OrderType::Market, Side::Buy, None, dec!(3));
+        assert!(check_order(&order, &order_book, dec!(210)).is_ok());
+        assert!(check_order(&order, &order_book, dec!(209.99)).is_err());
+    }
+
+    fn book_with_top(bid: Decimal, ask: Decimal) -> OrderBook {
+        let mut order_book = OrderBook::new();
+        order_book
+            .add_order(create_order(
+                OrderType::Limit,
+                Side::Buy,
+                Some(bid),
+                dec!(1),
+            ))
+            .unwrap();
+        order_book
+            .add_order(create_order(
+                OrderType::Limit,
+                Side::Sell,
+                Some(ask),
+                dec!(1),
+            ))
+            .unwrap();
+        order_book
+    }
+
+    #[test]
+    fn test_buy_at_band_edge_passes_and_beyond_fails() {
+        let order_book = book_with_top(dec!(90), dec!(100));
+
+        let at_edge = create_order(OrderType::Limit, Side::Buy, Some(dec!(110)), dec!(1));
+        assert!(check_price_band(&at_edge, &order_book, dec!(10)).is_ok());
+
+        let beyond = create_order(OrderType::Limit, Side::Buy, Some(dec!(110.01)), dec!(1));
+        assert_eq!(
+            check_price_band(&beyond, &order_book, dec!(10)),
+            Err(RiskError::OutsidePriceBand {
+                price: dec!(110.01),
+                reference: dec!(100),
+                band_pct: dec!(10),
+            })
+        );
+    }
+
+    #[test]
+    fn test_sell_at_band_edge_passes_and_beyond_fails() {
+        let order_book = book_with_top(dec!(100), dec!(110));
+
+        let at_edge = create_order(OrderType::Limit, Side::Sell, Some(dec!(90)), dec!(1));
+        assert!(check_price_band(&at_edge, &order_book, dec!(10)).is_ok());
+
+        let beyond = create_order(OrderType::Limit, Side::Sell, Some(dec!(89.99)), dec!(1));
+        assert!(check_price_band(&beyond, &order_book, dec!(10)).is_err());
+    }
+
+    #[test]
+    fn test_price_band_keeps_its_width_below_zero() {
+        let order_book = book_with_top(dec!(-20), dec!(-10));
+
+        let at_edge = create_order(OrderType::Limit, Side::Buy, Some(dec!(-9)), dec!(1));
+        assert!(check_price_band(&at_edge, &order_book, dec!(10)).is_ok());
+        let beyond = create_order(OrderType::Limit, Side::Buy, Some(dec!(-8.99)), dec!(1));
+        assert!(check_price_band(&beyond, &order_book, dec!(10)).is_err());
+
+        let at_edge = create_order(OrderType::Limit, Side::Sell, Some(dec!(-22)), dec!(1));
+        assert!(check_price_band(&at_edge, &order_book, dec!(10)).is_ok());
+        assert!(check_order(&at_edge, &order_book, dec!(21)).is_err());
+        assert!(is_limit_move(dec!(-10), dec!(-12.5), dec!(20)));
+        assert!(!is_limit_move(Decimal::ZERO, dec!(-12.5), dec!(20)));
+    }
+
+    #[test]
+    fn test_price_band_skips_empty_side_and_market_orders() {
+        let order = create_order(OrderType::Limit, Side::Buy, Some(dec!(1000)), dec!(1));
+        assert!(check_price_band(&order, &OrderBook::new(), dec!(10)).is_ok());
+
+        let order_book = book_with_top(dec!(90), dec!(100));
+        let market = create_order(OrderType::Market, Side::Buy, None, dec!(1));
+        assert!(check_price_band(&market, &order_book, dec!(10)).is_ok());
+    }
+}
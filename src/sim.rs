@@ -0,0 +1,170 @@
+use crate::Order;
+use crate::matching_engine::{OrderBook, Trade, TradeIds};
+use chrono::{DateTime, Duration, Utc};
+
+/// Source of the current time for a replay.
+pub trait Clock {
+    fn now(&mut self) -> DateTime<Utc>;
+}
+
+/// The wall clock, for replays that do not need to be repeatable.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&mut self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// Starts at a fixed instant and moves forward by `step` on every reading,
+/// so the same replay always produces the same timestamps.
+pub struct SteppingClock {
+    next: DateTime<Utc>,
+    step: Duration,
+}
+
+impl SteppingClock {
+    pub fn new(start: DateTime<Utc>, step: Duration) -> Self {
+        SteppingClock { next: start, step }
+    }
+}
+
+impl Clock for SteppingClock {
+    fn now(&mut self) -> DateTime<Utc> {
+        let now = self.next;
+        self.next += self.step;
+        now
+    }
+}
+
+/// Feeds `orders` through a fresh book one at a time, as if each arrived at
+/// the clock's next reading, and returns the resulting book with every
+/// trade in execution order, including those of triggered stops. Trade
+/// ids are sequential, so they repeat from one replay to the next too.
+pub fn replay(orders: Vec<Order>, clock: &mut impl Clock) -> (OrderBook, Vec<Trade>) {
+    let mut order_book = OrderBook::new();
+    order_book.trade_ids = TradeIds::sequential();
+    let mut trades = Vec::new();
+
+    for mut order in orders {
+        let now = clock.now();
+        order.timestamp = now;
+        let result = order_book.match_order_at(order, now);
+        trades.extend(result.all_trades().into_iter().cloned());
+    }
+
+    (order_book, trades)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{OrderType, Side};
+    use rust_decimal::Decimal;
+    use rust_decimal_macros::dec;
+    use uuid::Uuid;
+
+    fn order(
+        order_type: OrderType,
+        side: Side,
+        price: Option<Decimal>,
+        quantity: Decimal,
+    ) -> Order {
+        Order {
+            order_type,
+            price,
+            ..Order::test_limit(side, Decimal::ZERO, quantity, DateTime::<Utc>::MIN_UTC)
+        }
+    }
+
+    fn limit(side: Side, price: Decimal, quantity: Decimal) -> Order {
+        order(OrderType::Limit, side, Some(price), quantity)
+    }
+
+    fn scenario() -> Vec<Order> {
+        vec![
+            limit(Side::Sell, dec!(101), dec!(2)),
+            limit(Side::Sell, dec!(102), dec!(3)),
+            limit(Side::Buy, dec!(99), dec!(1)),
+            limit(Side::Buy, dec!(98), dec!(4)),
+            // Lifts the 101 ask and part of 102.
+            limit(Side::Buy, dec!(102), dec!(3)),
+            limit(Side::Sell, dec!(103), dec!(1)),
+            // Hits the 99 bid and part of 98.
+            order(OrderType::Market, Side::Sell, None, dec!(2)),
+            limit(Side::Buy, dec!(100), dec!(1)),
+            // Crosses the fresh 100 bid, rests the remainder.
+            limit(Side::Sell, dec!(100), dec!(2)),
+            order(OrderType::Market, Side::Buy, None, dec!(1)),
+        ]
+    }
+
+    #[test]
+    fn test_replay_ten_order_scenario() {
+        let start = DateTime::parse_from_rfc3339("2026-01-01T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let mut clock = SteppingClock::new(start, Duration::seconds(1));
+
+        let (order_book, trades) = replay(scenario(), &mut clock);
+
+        let fills: Vec<(Decimal, Decimal)> = trades.iter().map(|t| (t.price, t.quantity)).collect();
+        assert_eq!(
+            fills,
+            vec![
+                (dec!(101), dec!(2)),
+                (dec!(102), dec!(1)),
+                (dec!(99), dec!(1)),
+                (dec!(98), dec!(1)),
+                (dec!(100), dec!(1)),
+                (dec!(100), dec!(1)),
+            ]
+        );
+        assert_eq!(trades[0].timestamp, start + Duration::seconds(4));
+        assert_eq!(trades[5].timestamp, start + Duration::seconds(9));
+
+        let depth = order_book.depth(usize::MAX);
+        let bids: Vec<(Decimal, Decimal)> = depth
+            .bids
+            .iter()
+            .map(|l| (l.price, l.total_quantity))
+            .collect();
+        let asks: Vec<(Decimal, Decimal)> = depth
+            .asks
+            .iter()
+            .map(|l| (l.price, l.total_quantity))
+            .collect();
+        assert_eq!(bids, vec![(dec!(98), dec!(3))]);
+        assert_eq!(asks, vec![(dec!(102), dec!(2)), (dec!(103), dec!(1))]);
+    }
+
+    #[test]
+    fn test_replay_is_repeatable() {
+        let start = Utc::now();
+        let run = || {
+            let mut clock = SteppingClock::new(start, Duration::milliseconds(1));
+            let (_, trades) = replay(scenario(), &mut clock);
+            trades
+                .iter()
+                .map(|t| (t.price, t.quantity, t.timestamp))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(run(), run());
+    }
+
+    #[test]
+    fn test_replayed_trade_ids_repeat() {
+        let start = Utc::now();
+        let ids = || {
+            let mut clock = SteppingClock::new(start, Duration::milliseconds(1));
+            let (_, trades) = replay(scenario(), &mut clock);
+            trades.iter().map(|t| t.id).collect::<Vec<_>>()
+        };
+
+        let first = ids();
+        assert_eq!(first, ids());
+        assert_eq!(first[0], Uuid::from_u128(1));
+        assert_eq!(first.last(), Some(&Uuid::from_u128(first.len() as u128)));
+    }
+}
@@ -0,0 +1,172 @@
+use crate::config::EngineConfig;
+use crate::error::{self, ApiError};
+use crate::matching_engine::{
+    MatchingPolicy, OrderBook, OrderChange, OrderResult, PricingMode, Trade,
+};
+use crate::reconstruct::{BookEvent, EventOutcome};
+use crate::{AppState, AppStateInner, Order, OrderType};
+use chrono::Utc;
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use tokio::sync::oneshot;
+
+pub const DEFAULT_SPEED_BUMP_MS: u64 = 0;
+
+/// An order held for the next batch, with the channel its result goes to.
+pub(crate) type PendingOrder = (Order, oneshot::Sender<Result<OrderResult, ApiError>>);
+
+/// Whether the speed bump holds `order` back for a batch. Only plain
+/// limit orders that may rest do; everything else matches on arrival
+/// against the book the last batch left, since an auction cannot honour
+/// post-only, reduce-only, immediate or market instructions.
+pub fn holds(order: &Order) -> bool {
+    order.order_type == OrderType::Limit
+        && order.time_in_force.can_rest()
+        && !order.post_only
+        && !order.reduce_only
+}
+
+/// The first engine setting batches cannot honour, by its variable name,
+/// or `None` if the speed bump may run with `engine`. Batches clear in one
+/// FIFO auction at a single price, so no other fill policy applies.
+pub fn unsupported_setting(engine: &EngineConfig) -> Option<&'static str> {
+    if engine.matching_policy != MatchingPolicy::Fifo {
+        Some("MATCHING_POLICY")
+    } else if engine.pricing_mode != PricingMode::MakerPrice {
+        Some("PRICING_MODE")
+    } else if engine.max_fill_per_maker.is_some() {
+        Some("MAX_FILL_PER_MAKER")
+    } else if !engine.min_trade_notional.is_zero() {
+        Some("MIN_TRADE_NOTIONAL")
+    } else {
+        None
+    }
+}
+
+/// Holds `order` until its symbol's batch window closes and returns its
+/// result from the batch. The first order into an empty batch opens the
+/// window, so no order waits longer than the speed bump.
+pub(crate) async fn submit(state: &AppState, order: Order) -> Result<OrderResult, ApiError> {
+    let symbol = order.symbol.clone();
+    let (result_tx, result_rx) = oneshot::channel();
+    let opens_window = {
+        let mut batches = error::lock(&state.speed_bump_batches)?;
+        let batch = batches.entry(symbol.clone()).or_default();
+        batch.push((order, result_tx));
+        batch.len() == 1
+    };
+    if opens_window {
+        let state = state.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(state.speed_bump).await;
+            flush(&state, &symbol).await;
+        });
+    }
+
+    result_rx
+        .await
+        .unwrap_or_else(|_| Err(ApiError::Internal("order batch was dropped".to_string())))
+}
+
+/// Matches the orders waiting on `symbol` as one batch, then stores the
+/// trades and hands every order its result.
+async fn flush(state: &AppStateInner, symbol: &str) {
+    let pending = match error::lock(&state.speed_bump_batches) {
+        Ok(mut batches) => batches.remove(symbol).unwrap_or_default(),
+        Err(_) => return,
+    };
+    let (orders, result_txs): (Vec<Order>, Vec<_>) = pending.into_iter().unzip();
+
+    let matched = error::write(&state.order_books)
+        .map(|mut order_books| match_batch(state, &mut order_books, symbol, orders));
+    let (results, trades, changes) = match matched {
+        Ok(matched) => matched,
+        Err(e) => {
+            for result_tx in result_txs {
+                let _ = result_tx.send(Err(e.clone()));
+            }
+            return;
+        }
+    };
+
+    crate::publish_trades(state, &trades.iter().collect::<Vec<_>>(), &changes).await;
+    println!(
+        "Batch of {} orders on {} produced {} trades.",
+        results.len(),
+        symbol,
+        trades.len()
+    );
+    for (result_tx, result) in result_txs.into_iter().zip(results) {
+        let _ = result_tx.send(result);
+    }
+}
+
+/// Rests every order of the batch that passes the pre-trade checks, then
+/// uncrosses the book in one auction so they all trade at one price.
+/// Returns each order's result in batch order, the trades once each, and
+/// the storage changes. Runs under the books lock.
+fn match_batch(
+    state: &AppStateInner,
+    order_books: &mut HashMap<String, OrderBook>,
+    symbol: &str,
+    orders: Vec<Order>,
+) -> (
+    Vec<Result<OrderResult, ApiError>>,
+    Vec<Trade>,
+    Vec<OrderChange>,
+) {
+    let order_book = crate::book_for(order_books, symbol, &state.engine);
+    let previous_price = order_book.stats.last_price();
+    let now = Utc::now();
+
+    let mut accepted = Vec::with_capacity(orders.len());
+    for order in &orders {
+        let added = crate::check_not_halted(state)
+            .and_then(|()| crate::check_order(state, order_book, order))
+            .and_then(|()| {
+                let event = BookEvent::Batched {
+                    order: order.clone(),
+                };
+                match crate::apply_event(state, symbol, order_book, now, event) {
+                    EventOutcome::Batched(added) => {
+                        added.map_err(|e| ApiError::Validation(e.to_string()))
+                    }
+                    _ => unreachable!("a batched order always yields a batch outcome"),
+                }
+            });
+        accepted.push(added);
+    }
+
+    let mut trades = Vec::new();
+    if accepted.iter().any(Result::is_ok)
+        && let EventOutcome::Auction(Some((_, auction_trades))) =
+            crate::apply_event(state, symbol, order_book, now, BookEvent::Auction)
+    {
+        trades = auction_trades;
+    }
+    crate::broadcast_book_changes(state, symbol, order_book);
+    for trade in &mut trades {
+        trade.seq = state.last_trade_seq.fetch_add(1, Ordering::SeqCst) + 1;
+        crate::broadcast_trade(state, trade);
+    }
+    crate::halt_on_limit_move(state, symbol, previous_price, &trades);
+
+    let results = orders
+        .iter()
+        .zip(accepted)
+        .map(|(order, added)| {
+            added.map(|()| {
+                let own_trades = trades
+                    .iter()
+                    .filter(|trade| {
+                        trade.maker_order_id == order.id || trade.taker_order_id == order.id
+                    })
+                    .cloned()
+                    .collect();
+                let rested = order_book.find_order(order.id).is_some();
+                OrderResult::new(order.id, order.quantity, own_trades, rested)
+            })
+        })
+        .collect();
+    (results, trades, order_book.take_changes())
+}
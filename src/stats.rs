@@ -0,0 +1,155 @@
+use crate::amount::Amount;
+use crate::matching_engine::Trade;
+use chrono::{DateTime, Duration, Utc};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::collections::VecDeque;
+
+/// Trades older than this drop out of the OHLCV figures.
+pub const STATS_WINDOW_HOURS: i64 = 24;
+
+/// Rolling price and volume figures for one symbol.
+#[derive(Debug, Clone)]
+pub struct MarketStats<N = Decimal> {
+    window: Duration,
+    /// Price and quantity of every trade inside the window, oldest first.
+    trades: VecDeque<(DateTime<Utc>, N, N)>,
+    last_price: Option<N>,
+}
+
+/// What `GET /stats/:symbol` reports. Everything but `last_price` only
+/// covers the rolling window, so a quiet market reports no OHLC.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct StatsSnapshot<N = Decimal> {
+    pub last_price: Option<N>,
+    pub open: Option<N>,
+    pub high: Option<N>,
+    pub low: Option<N>,
+    pub volume: N,
+}
+
+impl<N: Amount> Default for MarketStats<N> {
+    fn default() -> Self {
+        Self::new(Duration::hours(STATS_WINDOW_HOURS))
+    }
+}
+
+impl<N: Amount> MarketStats<N> {
+    pub fn new(window: Duration) -> Self {
+        MarketStats {
+            window,
+            trades: VecDeque::new(),
+            last_price: None,
+        }
+    }
+
+    pub fn record(&mut self, trade: &Trade<N>) {
+        self.last_price = Some(trade.price);
+        self.trades
+            .push_back((trade.timestamp, trade.price, trade.quantity));
+    }
+
+    /// Forgets every trade, keeping the window.
+    pub fn clear(&mut self) {
+        self.trades.clear();
+        self.last_price = None;
+    }
+
+    /// Price of the most recent trade, however long ago it was.
+    pub fn last_price(&self) -> Option<N> {
+        self.last_price
+    }
+
+    /// Drops trades that fell out of the window as of `now`.
+    pub fn expire(&mut self, now: DateTime<Utc>) {
+        let cutoff = now - self.window;
+        while self
+            .trades
+            .front()
+            .is_some_and(|&(timestamp, _, _)| timestamp <= cutoff)
+        {
+            self.trades.pop_front();
+        }
+    }
+
+    /// Figures over the window ending at `now`.
+    pub fn snapshot(&self, now: DateTime<Utc>) -> StatsSnapshot<N> {
+        let cutoff = now - self.window;
+        let mut in_window = self
+            .trades
+            .iter()
+            .filter(|&&(timestamp, _, _)| timestamp > cutoff)
+            .peekable();
+
+        let open = in_window.peek().map(|&&(_, price, _)| price);
+        let mut snapshot = StatsSnapshot {
+            last_price: self.last_price,
+            open,
+            high: open,
+            low: open,
+            volume: N::ZERO,
+        };
+        for &(_, price, quantity) in in_window {
+            snapshot.high = snapshot.high.max(Some(price));
+            snapshot.low = snapshot.low.min(Some(price));
+            snapshot.volume += quantity;
+        }
+        snapshot
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    fn trade_at(timestamp: DateTime<Utc>, price: Decimal, quantity: Decimal) -> Trade {
+        Trade::test_at("BTC-USD", price, quantity, timestamp)
+    }
+
+    #[test]
+    fn test_ohlcv_over_several_trades() {
+        let now = Utc::now();
+        let mut stats = MarketStats::default();
+        stats.record(&trade_at(now - Duration::hours(3), dec!(100.0), dec!(1.0)));
+        stats.record(&trade_at(now - Duration::hours(2), dec!(105.0), dec!(0.5)));
+        stats.record(&trade_at(now - Duration::hours(1), dec!(95.0), dec!(2.0)));
+        stats.record(&trade_at(now, dec!(101.0), dec!(1.5)));
+
+        assert_eq!(
+            stats.snapshot(now),
+            StatsSnapshot {
+                last_price: Some(dec!(101.0)),
+                open: Some(dec!(100.0)),
+                high: Some(dec!(105.0)),
+                low: Some(dec!(95.0)),
+                volume: dec!(5.0),
+            }
+        );
+    }
+
+    #[test]
+    fn test_trades_older_than_window_expire() {
+        let now = Utc::now();
+        let mut stats = MarketStats::default();
+        stats.record(&trade_at(now - Duration::hours(30), dec!(200.0), dec!(4.0)));
+        stats.record(&trade_at(now - Duration::hours(24), dec!(50.0), dec!(3.0)));
+        stats.record(&trade_at(now - Duration::hours(1), dec!(100.0), dec!(1.0)));
+
+        let snapshot = stats.snapshot(now);
+        assert_eq!(snapshot.open, Some(dec!(100.0)));
+        assert_eq!(snapshot.high, Some(dec!(100.0)));
+        assert_eq!(snapshot.low, Some(dec!(100.0)));
+        assert_eq!(snapshot.volume, dec!(1.0));
+
+        // Once everything ages out only the last price remains.
+        stats.expire(now + Duration::hours(24));
+        assert_eq!(
+            stats.snapshot(now + Duration::hours(24)),
+            StatsSnapshot {
+                last_price: Some(dec!(100.0)),
+                ..StatsSnapshot::default()
+            }
+        );
+    }
+}
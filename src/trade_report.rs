@@ -0,0 +1,160 @@
+use crate::AppState;
+use crate::matching_engine::Trade;
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+
+/// A trade held back from publication until `due`.
+#[derive(Debug)]
+pub struct DelayedTrade {
+    pub due: Instant,
+    pub trade: Trade,
+}
+
+impl PartialEq for DelayedTrade {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for DelayedTrade {}
+
+impl PartialOrd for DelayedTrade {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for DelayedTrade {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.due, self.trade.seq).cmp(&(other.due, other.trade.seq))
+    }
+}
+
+/// Delayed trades, released in the order they fall due. Symbols may be
+/// delayed by different amounts, so that need not be the order they
+/// traded in; trades due together come out in sequence order.
+#[derive(Debug, Default)]
+pub struct DelayQueue {
+    pending: BinaryHeap<Reverse<DelayedTrade>>,
+}
+
+impl DelayQueue {
+    pub fn push(&mut self, delayed: DelayedTrade) {
+        self.pending.push(Reverse(delayed));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// When the next trade falls due, if any is waiting.
+    pub fn next_due(&self) -> Option<Instant> {
+        self.pending.peek().map(|Reverse(delayed)| delayed.due)
+    }
+
+    /// Takes every trade due at or before `now`, soonest first.
+    pub fn pop_due(&mut self, now: Instant) -> Vec<Trade> {
+        let mut released = Vec::new();
+        while self.next_due().is_some_and(|due| due <= now) {
+            if let Some(Reverse(delayed)) = self.pending.pop() {
+                released.push(delayed.trade);
+            }
+        }
+        released
+    }
+
+    /// Takes every trade still waiting, soonest first.
+    pub fn drain(&mut self) -> Vec<Trade> {
+        let mut released = Vec::with_capacity(self.pending.len());
+        while let Some(Reverse(delayed)) = self.pending.pop() {
+            released.push(delayed.trade);
+        }
+        released
+    }
+}
+
+/// Publishes the trades sent on `delayed` as each falls due, until every
+/// sender is gone and nothing is left waiting. Matching never waits on it.
+/// Once `flush` fires, everything still waiting is published at once, so
+/// a shutdown drops nothing.
+pub(crate) async fn run(
+    state: AppState,
+    mut delayed: mpsc::UnboundedReceiver<DelayedTrade>,
+    mut flush: oneshot::Receiver<()>,
+) {
+    let mut queue = DelayQueue::default();
+    let mut open = true;
+    while open || !queue.is_empty() {
+        let next_due = queue.next_due();
+        tokio::select! {
+            received = delayed.recv(), if open => match received {
+                Some(delayed_trade) => queue.push(delayed_trade),
+                None => open = false,
+            },
+            _ = tokio::time::sleep_until(next_due.unwrap_or_else(Instant::now)), if next_due.is_some() => {
+                publish(&state, queue.pop_due(Instant::now()));
+            }
+            Ok(()) = &mut flush => {
+                while let Ok(delayed_trade) = delayed.try_recv() {
+                    queue.push(delayed_trade);
+                }
+                publish(&state, queue.drain());
+                return;
+            }
+        }
+    }
+}
+
+/// Broadcasts and reports `released`; they were stored as they executed.
+fn publish(state: &AppState, released: Vec<Trade>) {
+    for trade in &released {
+        let _ = state.trade_tx.send(trade.clone());
+    }
+    crate::report_trades(state, &released.iter().collect::<Vec<_>>());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use rust_decimal_macros::dec;
+    use std::time::Duration;
+
+    fn trade(seq: u64) -> Trade {
+        Trade {
+            seq,
+            ..Trade::test_at("BTC-USD", dec!(100), dec!(1), Utc::now())
+        }
+    }
+
+    #[test]
+    fn test_trades_come_out_as_they_fall_due() {
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+        let mut queue = DelayQueue::default();
+        for (seq, due) in [(1, 300), (2, 100), (3, 100), (4, 200)] {
+            queue.push(DelayedTrade {
+                due: at(due),
+                trade: trade(seq),
+            });
+        }
+        let seqs = |trades: Vec<Trade>| trades.iter().map(|t| t.seq).collect::<Vec<_>>();
+
+        assert_eq!(queue.next_due(), Some(at(100)));
+        assert!(queue.pop_due(at(99)).is_empty());
+        assert_eq!(seqs(queue.pop_due(at(200))), vec![2, 3, 4]);
+        assert_eq!(seqs(queue.pop_due(at(1000))), vec![1]);
+        assert!(queue.is_empty());
+
+        for (seq, due) in [(5, 300), (6, 100)] {
+            queue.push(DelayedTrade {
+                due: at(due),
+                trade: trade(seq),
+            });
+        }
+        assert_eq!(seqs(queue.drain()), vec![6, 5]);
+        assert!(queue.is_empty());
+    }
+}
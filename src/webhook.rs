@@ -0,0 +1,271 @@
+use crate::matching_engine::Trade;
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// How many trades wait for the webhook before new ones are dropped rather
+/// than holding up matching.
+pub const WEBHOOK_CHANNEL_CAPACITY: usize = 1024;
+pub const DEFAULT_WEBHOOK_MAX_ATTEMPTS: u32 = 5;
+pub const DEFAULT_WEBHOOK_BACKOFF_MS: u64 = 100;
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Why a delivery did not go through.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeliveryError {
+    /// The webhook answered with a non-success status.
+    Status(u16),
+    /// No answer came back: the connection failed or timed out.
+    Transport(String),
+}
+
+impl DeliveryError {
+    /// Whether trying again might succeed. Client errors other than rate
+    /// limiting mean the webhook refuses this payload and always will.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            DeliveryError::Status(status) => *status == 429 || *status >= 500,
+            DeliveryError::Transport(_) => true,
+        }
+    }
+}
+
+impl fmt::Display for DeliveryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeliveryError::Status(status) => write!(f, "webhook answered {}", status),
+            DeliveryError::Transport(message) => f.write_str(message),
+        }
+    }
+}
+
+/// Somewhere trades are delivered to. The HTTP webhook in production; tests
+/// substitute their own.
+pub trait TradeSink {
+    fn deliver(&self, trade: &Trade) -> impl Future<Output = Result<(), DeliveryError>> + Send;
+}
+
+/// POSTs each trade as JSON to `url`.
+pub struct HttpSink {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl HttpSink {
+    pub fn new(url: String) -> Self {
+        HttpSink {
+            client: reqwest::Client::new(),
+            url,
+        }
+    }
+}
+
+impl TradeSink for HttpSink {
+    async fn deliver(&self, trade: &Trade) -> Result<(), DeliveryError> {
+        let response = self
+            .client
+            .post(&self.url)
+            .json(trade)
+            .timeout(Duration::from_secs(10))
+            .send()
+            .await
+            .map_err(|e| DeliveryError::Transport(e.to_string()))?;
+        let status = response.status();
+        if status.is_success() {
+            Ok(())
+        } else {
+            Err(DeliveryError::Status(status.as_u16()))
+        }
+    }
+}
+
+/// How often, and how patiently, a failed delivery is retried.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Tries per trade, including the first.
+    pub max_attempts: u32,
+    /// Wait after the first failure; each later wait doubles, up to
+    /// `MAX_BACKOFF`.
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: DEFAULT_WEBHOOK_MAX_ATTEMPTS,
+            backoff: Duration::from_millis(DEFAULT_WEBHOOK_BACKOFF_MS),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// How long to wait before the next try after `attempts` tries ended in
+    /// `error`, or `None` to give up on the trade.
+    pub fn next_delay(&self, attempts: u32, error: &DeliveryError) -> Option<Duration> {
+        if !error.is_retryable() || attempts >= self.max_attempts {
+            return None;
+        }
+        let doublings = attempts.saturating_sub(1).min(31);
+        Some(self.backoff.saturating_mul(1 << doublings).min(MAX_BACKOFF))
+    }
+}
+
+/// Delivers `trade`, retrying as `policy` allows. Returns whether it got
+/// through in the end.
+pub async fn deliver_with_retry(sink: &impl TradeSink, trade: &Trade, policy: RetryPolicy) -> bool {
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        let error = match sink.deliver(trade).await {
+            Ok(()) => return true,
+            Err(error) => error,
+        };
+        match policy.next_delay(attempts, &error) {
+            Some(delay) => {
+                eprintln!(
+                    "Webhook delivery of trade {} failed ({}), retrying in {:?}.",
+                    trade.seq, error, delay
+                );
+                tokio::time::sleep(delay).await;
+            }
+            None => {
+                eprintln!(
+                    "Giving up on webhook delivery of trade {} after {} attempts: {}",
+                    trade.seq, attempts, error
+                );
+                return false;
+            }
+        }
+    }
+}
+
+/// Delivers trades from `trades` one at a time, in the order they were
+/// queued, until every sender is gone. Runs apart from matching, which
+/// only ever waits on the channel.
+pub(crate) async fn run(
+    mut trades: mpsc::Receiver<Trade>,
+    sink: impl TradeSink,
+    policy: RetryPolicy,
+) {
+    while let Some(trade) = trades.recv().await {
+        deliver_with_retry(&sink, &trade, policy).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use rust_decimal_macros::dec;
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+
+    /// Answers with the scripted results in turn, then succeeds.
+    struct ScriptedSink {
+        results: Mutex<VecDeque<Result<(), DeliveryError>>>,
+        calls: Mutex<u32>,
+    }
+
+    impl ScriptedSink {
+        fn new(results: Vec<Result<(), DeliveryError>>) -> Self {
+            ScriptedSink {
+                results: Mutex::new(results.into()),
+                calls: Mutex::new(0),
+            }
+        }
+
+        fn calls(&self) -> u32 {
+            *self.calls.lock().unwrap()
+        }
+    }
+
+    impl TradeSink for ScriptedSink {
+        async fn deliver(&self, _trade: &Trade) -> Result<(), DeliveryError> {
+            *self.calls.lock().unwrap() += 1;
+            self.results.lock().unwrap().pop_front().unwrap_or(Ok(()))
+        }
+    }
+
+    fn trade() -> Trade {
+        Trade {
+            maker_fee: dec!(0.01),
+            taker_fee: dec!(0.02),
+            seq: 7,
+            ..Trade::test_at("BTC-USD", dec!(100.5), dec!(2), Utc::now())
+        }
+    }
+
+    const POLICY: RetryPolicy = RetryPolicy {
+        max_attempts: 4,
+        backoff: Duration::from_millis(1),
+    };
+
+    #[test]
+    fn test_trade_serializes_for_the_webhook() {
+        let trade = trade();
+        let json = serde_json::to_value(&trade).unwrap();
+
+        assert_eq!(json["symbol"], "BTC-USD");
+        #[cfg(not(feature = "decimal-as-number"))]
+        {
+            assert_eq!(json["price"], "100.5");
+            assert_eq!(json["quantity"], "2");
+        }
+        #[cfg(feature = "decimal-as-number")]
+        {
+            assert_eq!(json["price"], serde_json::json!(100.5));
+            assert_eq!(json["quantity"], serde_json::json!(2.0));
+        }
+        assert_eq!(json["taker_side"], "Buy");
+        assert_eq!(json["seq"], 7);
+        assert_eq!(json["maker_order_id"], trade.maker_order_id.to_string());
+    }
+
+    #[test]
+    fn test_backoff_doubles_and_stops_on_permanent_errors() {
+        let transient = DeliveryError::Status(503);
+        let policy = RetryPolicy {
+            max_attempts: 20,
+            backoff: Duration::from_millis(100),
+        };
+
+        assert_eq!(
+            policy.next_delay(1, &transient),
+            Some(Duration::from_millis(100))
+        );
+        assert_eq!(
+            policy.next_delay(3, &transient),
+            Some(Duration::from_millis(400))
+        );
+        assert_eq!(policy.next_delay(19, &transient), Some(MAX_BACKOFF));
+        assert_eq!(policy.next_delay(20, &transient), None);
+
+        assert!(DeliveryError::Transport("connection refused".to_string()).is_retryable());
+        assert!(DeliveryError::Status(429).is_retryable());
+        assert!(!DeliveryError::Status(400).is_retryable());
+        assert_eq!(policy.next_delay(1, &DeliveryError::Status(404)), None);
+    }
+
+    #[tokio::test]
+    async fn test_delivery_retries_until_it_succeeds() {
+        let sink = ScriptedSink::new(vec![
+            Err(DeliveryError::Status(500)),
+            Err(DeliveryError::Transport("timed out".to_string())),
+        ]);
+
+        assert!(deliver_with_retry(&sink, &trade(), POLICY).await);
+        assert_eq!(sink.calls(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_delivery_gives_up() {
+        let failing = ScriptedSink::new(vec![Err(DeliveryError::Status(502)); 10]);
+        assert!(!deliver_with_retry(&failing, &trade(), POLICY).await);
+        assert_eq!(failing.calls(), POLICY.max_attempts);
+
+        let refused = ScriptedSink::new(vec![Err(DeliveryError::Status(400))]);
+        assert!(!deliver_with_retry(&refused, &trade(), POLICY).await);
+        assert_eq!(refused.calls(), 1);
+    }
+}
@@ -0,0 +1,300 @@
+use crate::AppState;
+use crate::matching_engine::{BookDelta, DepthSnapshot, TopOfBook};
+use axum::{
+    extract::{
+        Query, State,
+        ws::{Message, WebSocket, WebSocketUpgrade},
+    },
+    response::Response,
+};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::time::{self, Instant};
+
+/// How many trades the broadcast channel buffers before slow clients start
+/// missing messages.
+pub const TRADE_CHANNEL_CAPACITY: usize = 1024;
+pub const BOOK_CHANNEL_CAPACITY: usize = 4096;
+pub const DEFAULT_PING_INTERVAL_SECS: u64 = 30;
+pub const DEFAULT_PONG_TIMEOUT_SECS: u64 = 10;
+
+/// How often streaming clients are pinged, and how long they get to answer
+/// before they are taken for dead and disconnected.
+#[derive(Debug, Clone, Copy)]
+pub struct Heartbeat {
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Heartbeat {
+            interval: Duration::from_secs(DEFAULT_PING_INTERVAL_SECS),
+            timeout: Duration::from_secs(DEFAULT_PONG_TIMEOUT_SECS),
+        }
+    }
+}
+
+/// Whether a client has answered our last ping. Any frame from the client
+/// counts as an answer, not just a pong.
+#[derive(Debug)]
+struct Liveness {
+    timeout: Duration,
+    /// When the oldest unanswered ping went out.
+    awaiting_since: Option<Instant>,
+}
+
+impl Liveness {
+    fn new(timeout: Duration) -> Self {
+        Liveness {
+            timeout,
+            awaiting_since: None,
+        }
+    }
+
+    fn ping_sent(&mut self, now: Instant) {
+        self.awaiting_since.get_or_insert(now);
+    }
+
+    fn frame_received(&mut self) {
+        self.awaiting_since = None;
+    }
+
+    /// When the client is due to be dropped, if a ping is outstanding.
+    fn deadline(&self) -> Option<Instant> {
+        Some(self.awaiting_since? + self.timeout)
+    }
+
+    fn timed_out(&self, now: Instant) -> bool {
+        self.deadline().is_some_and(|deadline| now >= deadline)
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum BookMessage {
+    /// Every resting level of one symbol, sent once per symbol on connect.
+    Snapshot {
+        symbol: String,
+        #[serde(flatten)]
+        depth: DepthSnapshot,
+    },
+    Delta(BookDelta),
+    TopOfBook(TopOfBookUpdate),
+}
+
+/// A book's best levels, sent once per symbol on connect and then only
+/// when one of them changes.
+#[derive(Debug, Clone, Serialize)]
+pub struct TopOfBookUpdate {
+    pub symbol: String,
+    #[serde(flatten)]
+    pub top: TopOfBook,
+}
+
+/// Which feed a `/ws/book` client gets: every level change, or just the
+/// top of book.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BookChannel {
+    #[default]
+    Deltas,
+    TopOfBook,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BookStreamQuery {
+    #[serde(default)]
+    pub channel: BookChannel,
+}
+
+pub(crate) async fn trades_ws(ws: WebSocketUpgrade, State(state): State<AppState>) -> Response {
+    let receiver = state.trade_tx.subscribe();
+    let heartbeat = state.ws_heartbeat;
+    ws.on_upgrade(move |socket| {
+        forward(socket, receiver, heartbeat, "Trade", std::convert::identity)
+    })
+}
+
+pub(crate) async fn book_ws(
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+    Query(query): Query<BookStreamQuery>,
+) -> Response {
+    ws.on_upgrade(move |socket| async move {
+        match query.channel {
+            BookChannel::Deltas => stream_book(socket, state).await,
+            BookChannel::TopOfBook => stream_top_of_book(socket, state).await,
+        }
+    })
+}
+
+async fn stream_top_of_book(mut socket: WebSocket, state: AppState) {
+    // Subscribed first for the same reason as `stream_book`.
+    let receiver = state.top_tx.subscribe();
+    let snapshots: Vec<BookMessage> = match state.order_books.read() {
+        Ok(order_books) => order_books
+            .iter()
+            .map(|(symbol, order_book)| {
+                BookMessage::TopOfBook(TopOfBookUpdate {
+                    symbol: symbol.clone(),
+                    top: order_book.top_of_book(),
+                })
+            })
+            .collect(),
+        Err(_) => {
+            eprintln!("Top of book stream unavailable, state lock poisoned.");
+            return;
+        }
+    };
+    for snapshot in &snapshots {
+        if !send_json(&mut socket, snapshot).await {
+            return;
+        }
+    }
+    forward(
+        socket,
+        receiver,
+        state.ws_heartbeat,
+        "Top of book",
+        BookMessage::TopOfBook,
+    )
+    .await;
+}
+
+async fn stream_book(mut socket: WebSocket, state: AppState) {
+    // Subscribe before taking the snapshot so no delta falls in between.
+    // Deltas carry absolute totals, so replaying one already reflected in
+    // the snapshot is harmless.
+    let receiver = state.book_tx.subscribe();
+    let snapshots: Vec<BookMessage> = match state.order_books.read() {
+        Ok(order_books) => order_books
+            .iter()
+            .map(|(symbol, order_book)| BookMessage::Snapshot {
+                symbol: symbol.clone(),
+                depth: order_book.depth(usize::MAX),
+            })
+            .collect(),
+        Err(_) => {
+            eprintln!("Book stream unavailable, state lock poisoned.");
+            return;
+        }
+    };
+
+    for snapshot in &snapshots {
+        if !send_json(&mut socket, snapshot).await {
+            return;
+        }
+    }
+
+    forward(
+        socket,
+        receiver,
+        state.ws_heartbeat,
+        "Book",
+        BookMessage::Delta,
+    )
+    .await;
+}
+
+/// Forwards every message from `receiver` to the client until either side
+/// goes away. Clients that fall behind skip the missed messages instead of
+/// holding up the sender. The client is pinged every `heartbeat.interval`
+/// and dropped, releasing its receiver, if it then stays silent for
+/// `heartbeat.timeout`.
+async fn forward<T, M>(
+    mut socket: WebSocket,
+    mut receiver: broadcast::Receiver<T>,
+    heartbeat: Heartbeat,
+    stream: &str,
+    to_message: fn(T) -> M,
+) where
+    T: Clone,
+    M: Serialize,
+{
+    let mut pings = time::interval_at(Instant::now() + heartbeat.interval, heartbeat.interval);
+    let mut liveness = Liveness::new(heartbeat.timeout);
+
+    loop {
+        let deadline = liveness.deadline();
+        tokio::select! {
+            received = receiver.recv() => match received {
+                Ok(item) => {
+                    if !send_json(&mut socket, &to_message(item)).await {
+                        break;
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    println!(
+                        "{} stream client lagged, skipped {} messages.",
+                        stream, skipped
+                    );
+                }
+                Err(RecvError::Closed) => break,
+            },
+            frame = socket.recv() => match frame {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => liveness.frame_received(),
+            },
+            _ = pings.tick() => {
+                if socket.send(Message::Ping(Vec::new())).await.is_err() {
+                    break;
+                }
+                liveness.ping_sent(Instant::now());
+            }
+            _ = time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                if liveness.timed_out(Instant::now()) {
+                    println!(
+                        "{} stream client did not answer a ping within {:?}, disconnecting.",
+                        stream, heartbeat.timeout
+                    );
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Sends `message` as a JSON text frame. Returns false once the client is
+/// gone.
+async fn send_json<M: Serialize>(socket: &mut WebSocket, message: &M) -> bool {
+    let text = match serde_json::to_string(message) {
+        Ok(text) => text,
+        Err(e) => {
+            eprintln!("Failed to serialize WebSocket message: {}", e);
+            return true;
+        }
+    };
+    socket.send(Message::Text(text)).await.is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_silent_client_times_out() {
+        let start = Instant::now();
+        let mut liveness = Liveness::new(Duration::from_secs(10));
+        assert!(!liveness.timed_out(start + Duration::from_secs(3600)));
+
+        liveness.ping_sent(start);
+        // A second ping does not push back the deadline of the first.
+        liveness.ping_sent(start + Duration::from_secs(5));
+        assert_eq!(liveness.deadline(), Some(start + Duration::from_secs(10)));
+        assert!(!liveness.timed_out(start + Duration::from_secs(9)));
+        assert!(liveness.timed_out(start + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn test_answering_client_stays_alive() {
+        let start = Instant::now();
+        let mut liveness = Liveness::new(Duration::from_secs(10));
+
+        liveness.ping_sent(start);
+        liveness.frame_received();
+        assert_eq!(liveness.deadline(), None);
+        assert!(!liveness.timed_out(start + Duration::from_secs(60)));
+    }
+}